@@ -0,0 +1,143 @@
+//! Blends a sparse watermark layer onto a 4000x3000 image, comparing `blend_layer` with
+//! the per-pixel `get_pixel` loop it replaced, and builds and blends rotated tiles as an
+//! RGBA layer and as the `ALPHA_MASK_LAYER` mask. Run with `cargo bench --bench blend`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dynamic_minio_watermark::layout::{
+    build_watermark_layer, build_watermark_mask, glyph_extent, LayoutOptions, WatermarkLayout,
+};
+use dynamic_minio_watermark::{blend_layer, blend_mask, FontChain, WatermarkStyle};
+use image::{Rgba, RgbaImage};
+use rusttype::Font;
+
+const WIDTH: u32 = 4000;
+const HEIGHT: u32 = 3000;
+
+fn base_image() -> RgbaImage {
+    RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgba([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, 255])
+    })
+}
+
+/// Mostly transparent, with glyph-sized blocks of partial coverage like a tiled layer.
+fn watermark_layer() -> RgbaImage {
+    RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        if x % 60 < 24 && y % 40 < 30 {
+            Rgba([255, 255, 255, ((x * 7 + y * 3) % 256) as u8])
+        } else {
+            Rgba([0, 0, 0, 0])
+        }
+    })
+}
+
+/// The blend before `blend_layer` walked the buffers as contiguous chunks.
+fn blend_per_pixel(base_image: &mut RgbaImage, layer: &RgbaImage) {
+    let (width, height) = base_image.dimensions();
+    for y in 0..height {
+        for x in 0..width {
+            let layer_pixel = layer.get_pixel(x, y);
+            let base_pixel = base_image.get_pixel_mut(x, y);
+
+            let layer_alpha = layer_pixel[3] as u32;
+            let base_alpha = base_pixel[3] as u32;
+
+            if base_alpha < 255 {
+                let base_weight = base_alpha * (255 - layer_alpha);
+                let out_alpha = layer_alpha * 255 + base_weight;
+                for i in 0..3 {
+                    let weighted = layer_pixel[i] as u32 * layer_alpha * 255
+                        + base_pixel[i] as u32 * base_weight
+                        + out_alpha / 2;
+                    if let Some(value) = weighted.checked_div(out_alpha) {
+                        base_pixel[i] = value as u8;
+                    }
+                }
+                base_pixel[3] = ((out_alpha + 127) / 255) as u8;
+                continue;
+            }
+
+            for i in 0..3 {
+                base_pixel[i] = ((layer_pixel[i] as u32 * layer_alpha
+                    + base_pixel[i] as u32 * (255 - layer_alpha)
+                    + 127)
+                    / 255) as u8;
+            }
+        }
+    }
+}
+
+fn blend(c: &mut Criterion) {
+    let base = base_image();
+    let layer = watermark_layer();
+    let mut group = c.benchmark_group("blend_4000x3000");
+    group.sample_size(10);
+    group.bench_function("blend_layer", |b| {
+        b.iter_batched_ref(
+            || base.clone(),
+            |image| blend_layer(image, black_box(&layer)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("per_pixel", |b| {
+        b.iter_batched_ref(
+            || base.clone(),
+            |image| blend_per_pixel(image, black_box(&layer)),
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Builds the tiles and blends them, as an RGBA layer and as a single-channel mask that
+/// takes a quarter of its memory. Shadows are off, as the mask requires a single color.
+fn tiles(c: &mut Criterion) {
+    let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+    let fonts = FontChain::new(font, Vec::new());
+    let chars: Vec<String> = "user@example.com".chars().map(String::from).collect();
+    let style = WatermarkStyle {
+        angle: 30.0,
+        ..WatermarkStyle::default()
+    };
+    let options = LayoutOptions {
+        shadow_enabled: false,
+        ..LayoutOptions::default()
+    };
+    let layout = WatermarkLayout::new(
+        WIDTH,
+        HEIGHT,
+        &[chars.len()],
+        glyph_extent(&chars, &fonts, &options),
+        &style,
+        &options,
+    );
+    let base = base_image();
+
+    let mut group = c.benchmark_group("tiles_4000x3000");
+    group.sample_size(10);
+    group.bench_function("rgba_layer", |b| {
+        b.iter_batched_ref(
+            || base.clone(),
+            |image| {
+                let (layer, _) =
+                    build_watermark_layer(WIDTH, HEIGHT, &chars, &fonts, &layout, &style, &options);
+                blend_layer(image, black_box(&layer));
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("alpha_mask", |b| {
+        b.iter_batched_ref(
+            || base.clone(),
+            |image| {
+                let (mask, _) =
+                    build_watermark_mask(WIDTH, HEIGHT, &chars, &fonts, &layout, &style, &options);
+                blend_mask(image, black_box(&mask), style.color);
+            },
+            criterion::BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, blend, tiles);
+criterion_main!(benches);
@@ -0,0 +1,248 @@
+use crate::{RenderedImage, StyleOverrides};
+use log::info;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedMutexGuard;
+
+/// Identifies a rendered result. `etag` is the source object's MinIO ETag, so objects
+/// replaced in place miss the cache. `watermarked` separates results rendered inside
+/// and outside `WATERMARK_SCHEDULE`, which differ for the same object and text, and
+/// `webp_allowed` separates `OUTPUT_FORMAT=smallest` results for clients that do and
+/// do not accept WebP. `style` holds the request's changes to the bucket's style.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub bucket: String,
+    pub object: String,
+    pub etag: String,
+    pub text: String,
+    pub watermarked: bool,
+    pub style: StyleOverrides,
+    pub webp_allowed: bool,
+}
+
+struct Entries {
+    /// Results with the time they were stored
+    lru: LruCache<CacheKey, (RenderedImage, Instant)>,
+    total_bytes: usize,
+}
+
+impl Entries {
+    /// The result for `key`, dropping it instead when it is older than `ttl`.
+    fn fresh(&mut self, key: &CacheKey, ttl: Option<Duration>) -> Option<RenderedImage> {
+        let (rendered, stored_at) = self.lru.get(key)?;
+        if ttl.is_none_or(|ttl| stored_at.elapsed() < ttl) {
+            return Some(rendered.clone());
+        }
+        if let Some((expired, _)) = self.lru.pop(key) {
+            self.total_bytes -= expired.cached_size();
+        }
+        None
+    }
+}
+
+/// In-memory LRU cache of encoded results, bounded by entry count and total bytes.
+/// Results can also expire after a TTL.
+pub struct RenderCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+    ttl: Option<Duration>,
+    in_flight: Mutex<HashMap<CacheKey, Arc<tokio::sync::Mutex<()>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Held while rendering a key, so concurrent requests for the same key wait for
+/// the result instead of rendering it again.
+pub struct FlightGuard<'a> {
+    cache: &'a RenderCache,
+    key: CacheKey,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self
+            .cache
+            .in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if in_flight
+            .get(&self.key)
+            .is_some_and(|lock| Arc::ptr_eq(lock, &self.lock))
+        {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+impl RenderCache {
+    /// Returns `None` when `max_entries` or `max_bytes` is 0, which disables caching.
+    /// Results are kept until evicted without a `ttl`.
+    pub fn new(max_entries: usize, max_bytes: usize, ttl: Option<Duration>) -> Option<Self> {
+        let capacity = NonZeroUsize::new(max_entries)?;
+        if max_bytes == 0 {
+            return None;
+        }
+
+        Some(Self {
+            entries: Mutex::new(Entries {
+                lru: LruCache::new(capacity),
+                total_bytes: 0,
+            }),
+            max_bytes,
+            ttl,
+            in_flight: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Looks up a cached result, counting the lookup as a hit or a miss.
+    pub fn get(&self, key: &CacheKey) -> Option<RenderedImage> {
+        let cached = self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .fresh(key, self.ttl);
+
+        let counter = if cached.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Cache {} for {}/{} (hits: {}, misses: {})",
+            if cached.is_some() { "hit" } else { "miss" },
+            key.bucket,
+            key.object,
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        );
+        cached
+    }
+
+    /// Looks up a result again after `claim`, without counting it as a lookup.
+    pub fn recheck(&self, key: &CacheKey) -> Option<RenderedImage> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .fresh(key, self.ttl)
+    }
+
+    /// Waits until no other request is rendering `key` and claims it. Callers should
+    /// check the cache again afterwards, as the previous holder may have filled it.
+    pub async fn claim(&self, key: &CacheKey) -> FlightGuard<'_> {
+        let lock = Arc::clone(
+            self.in_flight
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(key.clone())
+                .or_default(),
+        );
+        let guard = Arc::clone(&lock).lock_owned().await;
+
+        FlightGuard {
+            cache: self,
+            key: key.clone(),
+            lock,
+            _guard: guard,
+        }
+    }
+
+    /// Current number of cached entries and their total size in bytes.
+    pub fn usage(&self) -> (usize, usize) {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        (entries.lru.len(), entries.total_bytes)
+    }
+
+    /// Drops every cached result, for when the settings they were rendered with change.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.lru.clear();
+        entries.total_bytes = 0;
+    }
+
+    /// Stores a result, evicting least recently used entries to stay within the
+    /// byte limit. Results larger than the whole limit are not cached.
+    pub fn insert(&self, key: CacheKey, rendered: RenderedImage) {
+        let size = rendered.cached_size();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, (replaced, _))) = entries.lru.push(key, (rendered, Instant::now())) {
+            entries.total_bytes -= replaced.cached_size();
+        }
+        entries.total_bytes += size;
+
+        while entries.total_bytes > self.max_bytes {
+            match entries.lru.pop_lru() {
+                Some((_, (evicted, _))) => entries.total_bytes -= evicted.cached_size(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn key() -> CacheKey {
+        CacheKey {
+            bucket: "bucket".to_string(),
+            object: "key.jpg".to_string(),
+            etag: "\"etag-1\"".to_string(),
+            text: "ABCD1234".to_string(),
+            watermarked: true,
+            style: StyleOverrides::default(),
+            webp_allowed: true,
+        }
+    }
+
+    fn rendered() -> RenderedImage {
+        RenderedImage {
+            body: Bytes::from_static(b"image"),
+            content_type: "image/jpeg",
+            svg_overlay: None,
+            blurhash: None,
+            stats: Default::default(),
+        }
+    }
+
+    #[test]
+    fn results_are_served_until_evicted_without_ttl() {
+        let cache = RenderCache::new(10, 1024, None).unwrap();
+        cache.insert(key(), rendered());
+        assert!(cache.get(&key()).is_some());
+    }
+
+    #[test]
+    fn expired_results_are_dropped() {
+        let cache = RenderCache::new(10, 1024, Some(Duration::from_millis(10))).unwrap();
+        cache.insert(key(), rendered());
+        assert!(cache.get(&key()).is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get(&key()).is_none());
+        assert_eq!(cache.usage(), (0, 0));
+    }
+
+    #[test]
+    fn replaced_objects_miss_the_cache() {
+        let cache = RenderCache::new(10, 1024, None).unwrap();
+        cache.insert(key(), rendered());
+        let replaced = CacheKey {
+            etag: "\"etag-2\"".to_string(),
+            ..key()
+        };
+        assert!(cache.get(&replaced).is_none());
+    }
+}
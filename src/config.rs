@@ -1,128 +1,1210 @@
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use dynamic_minio_watermark::layout::LayoutOptions;
+use dynamic_minio_watermark::WatermarkStyle;
+use image::imageops::FilterType;
 use image::Rgba;
+use imageproc::geometric_transformations::Interpolation;
 use lazy_static::lazy_static;
 use log::warn;
+use regex::Regex;
 use std::env;
 use std::fmt::Debug;
 
+#[cfg(not(test))]
 lazy_static! {
     pub static ref CONFIG: Config = Config::from_env();
 }
 
+// Tests get fixed settings, whatever the environment they run in
+#[cfg(test)]
+lazy_static! {
+    pub static ref CONFIG: Config = Config::from_settings(&Settings::test());
+}
+
+/// Where `Config` reads its settings from, by environment variable name.
+pub enum Settings {
+    /// The process environment
+    Env,
+    /// The given values only, for tests that need a specific configuration
+    #[cfg(test)]
+    Values(std::collections::HashMap<String, String>),
+}
+
+impl Settings {
+    #[cfg(test)]
+    pub fn from_pairs(pairs: &[(&str, &str)]) -> Self {
+        Self::Values(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    /// The settings of `CONFIG` in tests: a local MinIO and a known admin token.
+    #[cfg(test)]
+    pub fn test() -> Self {
+        Self::from_pairs(&[
+            ("MINIO_ENDPOINT", "http://127.0.0.1:9000"),
+            ("MINIO_ACCESS_KEY", "minioadmin"),
+            ("MINIO_SECRET_KEY", "minioadmin"),
+            ("MINIO_SECURE", "false"),
+            ("ADMIN_TOKEN", TEST_ADMIN_TOKEN),
+        ])
+    }
+
+    /// These settings with `key` set to `value`.
+    #[cfg(test)]
+    pub fn with(mut self, key: &str, value: &str) -> Self {
+        if let Self::Values(values) = &mut self {
+            values.insert(key.to_string(), value.to_string());
+        }
+        self
+    }
+
+    fn var(&self, key: &str) -> Result<String, env::VarError> {
+        match self {
+            Settings::Env => env::var(key),
+            #[cfg(test)]
+            Settings::Values(values) => values.get(key).cloned().ok_or(env::VarError::NotPresent),
+        }
+    }
+
+    /// Parses a numeric or boolean setting, falling back to `default` when it is unset
+    /// or invalid.
+    fn numeric<T: std::str::FromStr + Debug>(&self, key: &str, default: T) -> T {
+        match self.var(key) {
+            Ok(val) => match val.parse::<T>() {
+                Ok(parsed) => parsed,
+                Err(_) => {
+                    warn!("Invalid value for {}, using default: {:?}", key, default);
+                    default
+                }
+            },
+            Err(_) => default,
+        }
+    }
+}
+
+/// `ADMIN_TOKEN` of the test configuration.
+#[cfg(test)]
+pub const TEST_ADMIN_TOKEN: &str = "test-admin-token";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkOutput {
+    /// Render the watermark into the image itself
+    Image,
+    /// Return the source image untouched plus an SVG overlay of the watermark
+    SvgOverlay,
+    /// Return only the watermark layer as a transparent PNG for later compositing
+    AlphaMask,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Re-encode in the format of the source image when it is JPEG, PNG or WebP
+    Auto,
+    Jpeg,
+    Png,
+    WebP,
+    /// Encode as JPEG and, when the client accepts it, WebP, and return the smaller
+    Smallest,
+}
+
+/// How the watermark text is placed on the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatermarkMode {
+    /// Repeat the text in a staggered grid over the whole image
+    Tile,
+    /// Draw the text once in the center
+    Center,
+}
+
+/// How `WATERMARK_ADAPTIVE` picks the watermark color from the image underneath.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdaptiveColor {
+    /// Always use the configured color
+    Off,
+    /// White on dark images, black on light ones, judged over the whole image
+    Image,
+    /// White or black for every region of an `ADAPTIVE_GRID_SIZE` grid
+    Grid,
+}
+
+/// What to do with images too small for a single watermark glyph at `FONT_HEIGHT_MIN`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TinyImageMode {
+    /// Return the image without the tiled watermark
+    Skip,
+    /// Draw the text, or logo, once in the center, scaled to fit
+    Fit,
+    /// Fail the request
+    Error,
+}
+
+/// Where the watermark text can come from, tried in `WATERMARK_TEXT_SOURCES` order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextSource {
+    /// The `WATERMARK_PARAM` query parameter
+    Param,
+    /// The `WATERMARK_TEXT_HEADER` request header
+    Header,
+    /// The `DEFAULT_WATERMARK_TEXT` template
+    Default,
+}
+
+/// When the WASM plugin runs relative to watermarking.
+#[cfg(feature = "plugins")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginStage {
+    /// Run on the decoded image before the watermark is drawn
+    Before,
+    /// Run on the watermarked image before it is resized and encoded
+    After,
+}
+
+/// Per-request access log line layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// No access log
+    Off,
+    /// Common Log Format followed by the duration in milliseconds
+    Common,
+    /// Combined Log Format, adding the referer and user agent to `Common`, followed by the
+    /// duration in milliseconds
+    Combined,
+}
+
+/// Body format of error responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// `{"status": "error", "message": ...}`
+    Json,
+    /// S3-style `<Error><Code>...</Code><Message>...</Message></Error>`
+    Xml,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain `env_logger` lines
+    Text,
+    /// One JSON object per line with timestamp, level, target, message and any
+    /// structured fields of the record
+    Json,
+}
+
+/// What to do when the font lock was poisoned by a thread panicking while holding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontPoisonPolicy {
+    /// Keep using the font already in the lock and clear the poison flag
+    Recover,
+    /// Reload the font from `FONT_PATH`, replace the lock contents and clear the poison flag
+    Reload,
+    /// Fail the request, leaving the lock poisoned
+    Fail,
+}
+
+/// Source color made transparent before watermarking, with a per-channel tolerance.
+pub struct ChromaKey {
+    pub color: [u8; 3],
+    pub tolerance: u8,
+}
+
+/// Daily time ranges, in a given timezone, during which watermarking is applied.
+pub struct WatermarkSchedule {
+    ranges: Vec<(NaiveTime, NaiveTime)>,
+    timezone: Tz,
+}
+
+impl WatermarkSchedule {
+    /// Parses comma-separated `HH:MM-HH:MM` ranges. A range whose end is before its
+    /// start wraps past midnight.
+    fn parse(spec: &str, timezone: Tz) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for range in spec.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (start, end) = range
+                .split_once('-')
+                .ok_or_else(|| format!("range '{}' is not in HH:MM-HH:MM form", range))?;
+            let parse_time = |value: &str| {
+                NaiveTime::parse_from_str(value.trim(), "%H:%M")
+                    .map_err(|e| format!("invalid time '{}': {}", value.trim(), e))
+            };
+            ranges.push((parse_time(start)?, parse_time(end)?));
+        }
+        if ranges.is_empty() {
+            return Err("no time ranges given".to_string());
+        }
+        Ok(Self { ranges, timezone })
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let local_time = now.with_timezone(&self.timezone).time();
+        self.ranges.iter().any(|&(start, end)| {
+            if start <= end {
+                local_time >= start && local_time < end
+            } else {
+                local_time >= start || local_time < end
+            }
+        })
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+}
+
 pub struct Config {
     // Server settings
     pub host: String,
     pub port: u16,
     pub workers: usize,
-    pub log_level: String,
+    pub slow_request_ms: u64,
+    pub admin_token: Option<String>,
+    /// HMAC key for `X-Usercode-Hash`, set only when `EXPOSE_USERCODE_HASH` is enabled
+    pub usercode_hash_secret: Option<String>,
+    /// HMAC key verifying `X-Watermark-Token` texts, which are ignored without one
+    pub watermark_signing_key: Option<String>,
+    pub expose_timing_headers: bool,
+    pub require_output_route: bool,
+    pub cors_allowed_origins: Vec<String>,
+    pub access_log_format: AccessLogFormat,
+    pub error_format: ErrorFormat,
+    pub cache_max_entries: usize,
+    pub cache_max_bytes: usize,
+    /// Seconds a cached result is served before it is rendered again, or 0 for no limit
+    pub cache_ttl_secs: u64,
+    pub max_concurrent_downloads: usize,
+    pub max_concurrent_renders: usize,
+    pub render_queue_limit: usize,
 
     // Font settings
     pub font_path: String,
+    pub font_mmap: bool,
+    /// `FONT_FALLBACK_PATHS`, tried in order for characters `FONT_PATH` has no glyph for
+    pub font_fallback_paths: Vec<String>,
+    pub watermark_image_path: Option<String>,
     pub font_height_ratio: f32,
-    pub font_height_min: f32,
-    pub font_width_ratio: f32,
+    /// `MEASURED_SPACING`, see `WatermarkStyle::measured_spacing`
+    pub measured_spacing: bool,
+    pub tiny_image_mode: TinyImageMode,
+    pub watermark_mode: WatermarkMode,
+    pub missing_glyph_replacement: Option<char>,
+    pub watermark_line_sep: Option<String>,
+    pub font_poison_policy: FontPoisonPolicy,
 
     // Color settings
     pub watermark_color: Rgba<u8>,
-    pub shadow_color: Rgba<u8>,
+    /// Lowest alpha a non-admin request may set through the `opacity` override
+    pub min_watermark_alpha: u8,
+    pub watermark_adaptive: AdaptiveColor,
+    pub adaptive_grid_size: u32,
+
+    // Layout settings, including FONT_HEIGHT_MIN, FONT_WIDTH_RATIO and the shadow color
+    pub layout: LayoutOptions,
+    pub watermark_angle: f32,
+    pub alpha_mask_layer: bool,
+
+    // Output settings
+    pub watermark_output: WatermarkOutput,
+    pub preview_placeholder: bool,
+    pub watermark_schedule: Option<WatermarkSchedule>,
+    pub watermark_param: String,
+    pub default_watermark_text: Option<String>,
+    /// Graphemes kept of a watermark text, or 0 for no limit
+    pub max_watermark_text_length: usize,
+    pub watermark_text_header: String,
+    pub watermark_text_sources: Vec<TextSource>,
+    pub skip_prefixes: Vec<String>,
+    pub fallback_image_path: Option<String>,
+    pub serve_fallback: bool,
+    pub tenant_config_path: Option<String>,
+    pub chroma_key: Option<ChromaKey>,
+
+    // Resize settings
+    pub target_width: u32,
+    pub resize_before_watermark: bool,
+    /// `SHARPEN_SIGMA`, the blur sigma of the unsharp mask applied after downscaling
+    pub sharpen_sigma: f32,
+    pub resize_filter: FilterType,
+    pub max_pixels: u64,
+    pub preserve_dimensions: bool,
+
+    // Banner settings
+    pub banner_text: Option<String>,
+    pub banner_color: Rgba<u8>,
+    pub banner_angle: f32,
+    pub layered_watermark: bool,
+    pub layered_label_color: Rgba<u8>,
+    pub layered_label_scale: f32,
 
-    // Layout settings
-    pub shadow_offset_ratio: f32,
-    pub char_spacing_x_ratio: f32,
-    pub char_spacing_y_ratio: f32,
-    pub global_offset_x_ratio: f32,
-    pub global_offset_y_ratio: f32,
+    // Border settings
+    pub border_width: u32,
+    pub border_color: Rgba<u8>,
 
     // Image quality settings
+    pub output_format: OutputFormat,
     pub jpeg_quality: u8,
+    #[cfg(feature = "svg")]
+    pub svg_render_width: u32,
+    pub decode_brute_force: bool,
+    pub tolerate_truncated: bool,
+    pub apply_exif_orientation: bool,
+    pub verify_output: bool,
+
+    // Plugin settings
+    #[cfg(feature = "plugins")]
+    pub plugin_path: Option<String>,
+    #[cfg(feature = "plugins")]
+    pub plugin_stage: PluginStage,
 
     // Minio settings
     pub minio_endpoint: String,
     pub minio_access_key: String,
     pub minio_secret_key: String,
     pub minio_secure: bool,
+    pub minio_ignore_cert: bool,
+    pub minio_ca_cert_file: Option<String>,
+    pub minio_timeout_secs: u64,
+    pub minio_max_retries: u32,
+    pub max_object_bytes: u64,
+    pub healthcheck_bucket: Option<String>,
+    /// `ALLOWED_BUCKETS`, or empty to serve every bucket
+    pub allowed_buckets: Vec<String>,
+
+    // URL resolution settings
+    pub url_template: Option<Regex>,
+    pub url_template_bucket: String,
+}
+
+/// Read separately from `Config` so the logger can be set up before the rest of the
+/// configuration is loaded, keeping its warnings visible.
+pub fn log_level() -> String {
+    env::var("LOG_LEVEL").unwrap_or_else(|_| "error".to_string())
+}
+
+/// Reads `LOG_FORMAT` on its own, like `log_level`, since the logger is set up before
+/// `CONFIG`. Invalid values are returned as the error for the caller to warn about.
+pub fn log_format() -> Result<LogFormat, String> {
+    match env::var("LOG_FORMAT")
+        .unwrap_or_else(|_| "text".to_string())
+        .to_lowercase()
+        .as_str()
+    {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        other => Err(other.to_string()),
+    }
+}
+
+/// Clamps a `JPEG_QUALITY` to the 1-100 the encoder accepts, warning when it was outside.
+fn clamp_jpeg_quality(requested: u32) -> u8 {
+    let quality = requested.clamp(1, 100) as u8;
+    if quality as u32 != requested {
+        warn!(
+            "JPEG_QUALITY {} is outside 1-100, using {}",
+            requested, quality
+        );
+    }
+    quality
+}
+
+/// Most download retries allowed by `MINIO_MAX_RETRIES`.
+const MAX_MINIO_RETRIES: u32 = 10;
+
+/// Clamps `MINIO_MAX_RETRIES` to `MAX_MINIO_RETRIES`, warning when it was above.
+fn clamp_max_retries(requested: u32) -> u32 {
+    let retries = requested.min(MAX_MINIO_RETRIES);
+    if retries != requested {
+        warn!(
+            "MINIO_MAX_RETRIES {} is above {}, using {}",
+            requested, MAX_MINIO_RETRIES, retries
+        );
+    }
+    retries
+}
+
+/// Parses a `#RGB`, `#RGBA`, `#RRGGBB` or `#RRGGBBAA` hex color, using `default_alpha`
+/// for the forms without an alpha channel. The leading `#` is optional.
+pub fn parse_hex_color(value: &str, default_alpha: u8) -> Option<Rgba<u8>> {
+    let hex = value.trim();
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    // from_str_radix alone would take a sign, as in `+f`
+    if !hex.chars().all(|digit| digit.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channels: Vec<u8> = match hex.len() {
+        3 | 4 => hex
+            .chars()
+            .map(|digit| digit.to_digit(16).map(|value| value as u8 * 0x11))
+            .collect::<Option<_>>()?,
+        6 | 8 => (0..hex.len())
+            .step_by(2)
+            .map(|start| u8::from_str_radix(&hex[start..start + 2], 16).ok())
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    Some(Rgba([
+        channels[0],
+        channels[1],
+        channels[2],
+        channels.get(3).copied().unwrap_or(default_alpha),
+    ]))
 }
 
 impl Config {
-    pub fn from_env() -> Self {
-        // Helper function to get numeric settings with default values
-        fn get_numeric<T: std::str::FromStr + Debug>(key: &str, default: T) -> T {
-            match env::var(key) {
-                Ok(val) => match val.parse::<T>() {
-                    Ok(parsed) => parsed,
-                    Err(_) => {
-                        warn!("Invalid value for {}, using default: {:?}", key, default);
-                        default
-                    }
-                },
-                Err(_) => default,
-            }
+    /// The style from `WATERMARK_COLOR`, `WATERMARK_ANGLE`, `FONT_HEIGHT_RATIO` and
+    /// `MEASURED_SPACING`.
+    pub fn watermark_style(&self) -> WatermarkStyle {
+        WatermarkStyle {
+            color: self.watermark_color,
+            angle: self.watermark_angle,
+            font_height_ratio: self.font_height_ratio,
+            measured_spacing: self.measured_spacing,
         }
+    }
+
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn from_env() -> Self {
+        Self::from_settings(&Settings::Env)
+    }
 
+    pub fn from_settings(settings: &Settings) -> Self {
         // Reading server settings
-        let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
-        let port = get_numeric("PORT", 3333);
-        let workers = get_numeric("WORKERS", 0);
-        let log_level = env::var("LOG_LEVEL").unwrap_or_else(|_| "error".to_string());
+        let host = settings
+            .var("HOST")
+            .unwrap_or_else(|_| "0.0.0.0".to_string());
+        let port = settings.numeric("PORT", 3333);
+        let workers = settings.numeric("WORKERS", 0);
+        let slow_request_ms = settings.numeric("SLOW_REQUEST_MS", 0);
+        let admin_token = settings
+            .var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+        let usercode_hash_secret = if settings.numeric("EXPOSE_USERCODE_HASH", false) {
+            let secret = settings
+                .var("USERCODE_HASH_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty());
+            if secret.is_none() {
+                warn!("EXPOSE_USERCODE_HASH is enabled but USERCODE_HASH_SECRET is not set, not sending X-Usercode-Hash");
+            }
+            secret
+        } else {
+            None
+        };
+        let watermark_signing_key = settings
+            .var("WATERMARK_SIGNING_KEY")
+            .ok()
+            .filter(|key| !key.is_empty());
+        let expose_timing_headers = settings.numeric("EXPOSE_TIMING_HEADERS", false);
+        let require_output_route = settings.numeric("REQUIRE_OUTPUT_ROUTE", false);
+        let cors_allowed_origins = settings
+            .var("CORS_ALLOWED_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        let access_log_format = match settings
+            .var("ACCESS_LOG_FORMAT")
+            .unwrap_or_else(|_| "off".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "off" => AccessLogFormat::Off,
+            "common" => AccessLogFormat::Common,
+            "combined" => AccessLogFormat::Combined,
+            other => {
+                warn!(
+                    "Invalid value for ACCESS_LOG_FORMAT '{}', using default: off",
+                    other
+                );
+                AccessLogFormat::Off
+            }
+        };
+        let error_format = match settings
+            .var("ERROR_FORMAT")
+            .unwrap_or_else(|_| "json".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "json" => ErrorFormat::Json,
+            "xml" => ErrorFormat::Xml,
+            other => {
+                warn!(
+                    "Invalid value for ERROR_FORMAT '{}', using default: json",
+                    other
+                );
+                ErrorFormat::Json
+            }
+        };
+        let cache_max_entries = settings.numeric("CACHE_MAX_ENTRIES", 0);
+        let cache_max_bytes = settings.numeric("CACHE_MAX_BYTES", 268_435_456);
+        let cache_ttl_secs = settings.numeric("CACHE_TTL_SECS", 0);
+        let max_concurrent_downloads = settings.numeric("MAX_CONCURRENT_DOWNLOADS", 0);
+        let max_concurrent_renders = settings.numeric("MAX_CONCURRENT_RENDERS", 0);
+        let render_queue_limit = settings.numeric("RENDER_QUEUE_LIMIT", 0);
 
         // Reading font settings
-        let font_path =
-            env::var("FONT_PATH").unwrap_or_else(|_| "assets/DejaVuSans.ttf".to_string());
-        let font_height_ratio = get_numeric("FONT_HEIGHT_RATIO", 0.10);
-        let font_height_min = get_numeric("FONT_HEIGHT_MIN", 40.0);
-        let font_width_ratio = get_numeric("FONT_WIDTH_RATIO", 0.6);
+        let font_path = settings
+            .var("FONT_PATH")
+            .unwrap_or_else(|_| "assets/DejaVuSans.ttf".to_string());
+        let font_mmap = settings.numeric("FONT_MMAP", false);
+        let font_fallback_paths = settings
+            .var("FONT_FALLBACK_PATHS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty())
+            .collect();
+        let watermark_image_path = settings
+            .var("WATERMARK_IMAGE_PATH")
+            .ok()
+            .filter(|path| !path.is_empty());
+        let font_height_ratio = settings.numeric("FONT_HEIGHT_RATIO", 0.10);
+        let font_height_min = settings.numeric("FONT_HEIGHT_MIN", 40.0);
+        let font_width_ratio = settings.numeric("FONT_WIDTH_RATIO", 0.6);
+        let tiny_image_mode = match settings
+            .var("TINY_IMAGE_MODE")
+            .unwrap_or_else(|_| "skip".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "skip" => TinyImageMode::Skip,
+            "fit" => TinyImageMode::Fit,
+            "error" => TinyImageMode::Error,
+            other => {
+                warn!(
+                    "Invalid value for TINY_IMAGE_MODE '{}', using default: skip",
+                    other
+                );
+                TinyImageMode::Skip
+            }
+        };
+        let watermark_mode = match settings
+            .var("WATERMARK_MODE")
+            .unwrap_or_else(|_| "tile".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "tile" => WatermarkMode::Tile,
+            "center" => WatermarkMode::Center,
+            other => {
+                warn!(
+                    "Invalid value for WATERMARK_MODE '{}', using default: tile",
+                    other
+                );
+                WatermarkMode::Tile
+            }
+        };
+        let missing_glyph_replacement = settings
+            .var("MISSING_GLYPH_REPLACEMENT")
+            .ok()
+            .and_then(|value| value.chars().next());
+        // Env files cannot hold a newline, so the escape `\n` stands for one; empty disables
+        let watermark_line_sep = match settings.var("WATERMARK_LINE_SEP") {
+            Ok(separator) if separator.is_empty() => None,
+            Ok(separator) => Some(separator.replace("\\n", "\n")),
+            Err(_) => Some("\n".to_string()),
+        };
+        let font_poison_policy = match settings
+            .var("FONT_POISON_POLICY")
+            .unwrap_or_else(|_| "recover".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "recover" => FontPoisonPolicy::Recover,
+            "reload" => FontPoisonPolicy::Reload,
+            "fail" => FontPoisonPolicy::Fail,
+            other => {
+                warn!(
+                    "Invalid value for FONT_POISON_POLICY '{}', using default: recover",
+                    other
+                );
+                FontPoisonPolicy::Recover
+            }
+        };
 
         // Reading color settings
-        let watermark_color = Rgba([
-            get_numeric("WATERMARK_COLOR_R", 255),
-            get_numeric("WATERMARK_COLOR_G", 255),
-            get_numeric("WATERMARK_COLOR_B", 255),
-            get_numeric("WATERMARK_COLOR_A", 46),
+        let channel_color = Rgba([
+            settings.numeric("WATERMARK_COLOR_R", 255),
+            settings.numeric("WATERMARK_COLOR_G", 255),
+            settings.numeric("WATERMARK_COLOR_B", 255),
+            settings.numeric("WATERMARK_COLOR_A", 46),
         ]);
+        let watermark_color = match settings.var("WATERMARK_COLOR") {
+            Ok(value) if !value.is_empty() => match parse_hex_color(&value, channel_color[3]) {
+                Some(color) => color,
+                None => {
+                    warn!(
+                        "Invalid value for WATERMARK_COLOR '{}', using WATERMARK_COLOR_R/G/B/A",
+                        value
+                    );
+                    channel_color
+                }
+            },
+            _ => channel_color,
+        };
+
+        let min_watermark_alpha = settings.numeric("MIN_WATERMARK_ALPHA", 16);
+        let watermark_adaptive = match settings
+            .var("WATERMARK_ADAPTIVE")
+            .unwrap_or_else(|_| "off".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "off" => AdaptiveColor::Off,
+            "image" => AdaptiveColor::Image,
+            "grid" => AdaptiveColor::Grid,
+            other => {
+                warn!(
+                    "Invalid value for WATERMARK_ADAPTIVE '{}', using default: off",
+                    other
+                );
+                AdaptiveColor::Off
+            }
+        };
+        let adaptive_grid_size = settings.numeric("ADAPTIVE_GRID_SIZE", 4u32).max(1);
 
         let shadow_color = Rgba([
-            get_numeric("SHADOW_COLOR_R", 0),
-            get_numeric("SHADOW_COLOR_G", 0),
-            get_numeric("SHADOW_COLOR_B", 0),
-            get_numeric("SHADOW_COLOR_A", 46),
+            settings.numeric("SHADOW_COLOR_R", 0),
+            settings.numeric("SHADOW_COLOR_G", 0),
+            settings.numeric("SHADOW_COLOR_B", 0),
+            settings.numeric("SHADOW_COLOR_A", 46),
         ]);
 
+        // A fully opaque mark hides the image underneath, which is rarely intended
+        for (key, alpha) in [
+            ("WATERMARK_COLOR_A", watermark_color[3]),
+            ("SHADOW_COLOR_A", shadow_color[3]),
+        ] {
+            if alpha == 255 {
+                warn!(
+                    "{} is 255, the watermark will be fully opaque; use a lower value for a subtle mark",
+                    key
+                );
+            }
+        }
+
         // Reading layout settings
-        let shadow_offset_ratio = get_numeric("SHADOW_OFFSET_RATIO", 0.065);
-        let char_spacing_x_ratio = get_numeric("CHAR_SPACING_X_RATIO", 1.1);
-        let char_spacing_y_ratio = get_numeric("CHAR_SPACING_Y_RATIO", 0.4);
-        let global_offset_x_ratio = get_numeric("GLOBAL_OFFSET_X_RATIO", -0.5);
-        let global_offset_y_ratio = get_numeric("GLOBAL_OFFSET_Y_RATIO", -1.2);
+        let shadow_enabled = settings.numeric("SHADOW_ENABLED", true);
+        let shadow_offset_ratio = settings.numeric("SHADOW_OFFSET_RATIO", 0.065);
+        let shadow_blur_sigma = settings.numeric("SHADOW_BLUR_SIGMA", 0.0f32).max(0.0);
+        let char_spacing_x_ratio = settings.numeric("CHAR_SPACING_X_RATIO", 1.1);
+        let char_spacing_y_ratio = settings.numeric("CHAR_SPACING_Y_RATIO", 0.4);
+        let global_offset_x_ratio = settings.numeric("GLOBAL_OFFSET_X_RATIO", -0.5);
+        let global_offset_y_ratio = settings.numeric("GLOBAL_OFFSET_Y_RATIO", -1.2);
+        let coverage_guarantee = settings.numeric("COVERAGE_GUARANTEE", 0.0);
+        let safe_area_margin = settings
+            .numeric("SAFE_AREA_MARGIN", 0.0f32)
+            .clamp(0.0, 0.45);
+        // MAX_GLYPHS counts glyph draws rather than tiles, one per tile plus one per shadow
+        let draws_per_tile = if shadow_enabled { 2 } else { 1 };
+        let auto_density_cap = match settings.var("AUTO_DENSITY_CAP").ok() {
+            Some(_) => settings.numeric("AUTO_DENSITY_CAP", 0),
+            None => match settings.numeric::<usize>("MAX_GLYPHS", 0) {
+                0 => 0,
+                max_glyphs => (max_glyphs / draws_per_tile).max(1),
+            },
+        };
+        let aspect_adaptive = settings.numeric("ASPECT_ADAPTIVE", false);
+        let rotation_interpolation = match settings
+            .var("ROTATION_INTERPOLATION")
+            .unwrap_or_else(|_| "bilinear".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "nearest" => Interpolation::Nearest,
+            "bilinear" => Interpolation::Bilinear,
+            "bicubic" => Interpolation::Bicubic,
+            other => {
+                warn!(
+                    "Invalid value for ROTATION_INTERPOLATION '{}', using default: bilinear",
+                    other
+                );
+                Interpolation::Bilinear
+            }
+        };
+        let watermark_angle = settings.numeric("WATERMARK_ANGLE", 0.0f32) % 360.0;
+        let measured_spacing = settings.numeric("MEASURED_SPACING", false);
+        let alpha_mask_layer = settings.numeric("ALPHA_MASK_LAYER", false);
+
+        // Reading output settings
+        let watermark_output = match settings
+            .var("WATERMARK_OUTPUT")
+            .unwrap_or_else(|_| "image".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "image" => WatermarkOutput::Image,
+            "svg-overlay" => WatermarkOutput::SvgOverlay,
+            "alpha-mask" => WatermarkOutput::AlphaMask,
+            other => {
+                warn!(
+                    "Invalid value for WATERMARK_OUTPUT '{}', using default: image",
+                    other
+                );
+                WatermarkOutput::Image
+            }
+        };
+        let preview_placeholder = settings.numeric("PREVIEW_PLACEHOLDER", false);
+
+        let watermark_schedule = match settings.var("WATERMARK_SCHEDULE") {
+            Ok(spec) if !spec.trim().is_empty() => {
+                let timezone_name = settings
+                    .var("WATERMARK_SCHEDULE_TZ")
+                    .unwrap_or_else(|_| "UTC".to_string());
+                let timezone = timezone_name.parse::<Tz>().unwrap_or_else(|_| {
+                    warn!(
+                        "Invalid value for WATERMARK_SCHEDULE_TZ '{}', using default: UTC",
+                        timezone_name
+                    );
+                    Tz::UTC
+                });
+                match WatermarkSchedule::parse(&spec, timezone) {
+                    Ok(schedule) => Some(schedule),
+                    Err(e) => {
+                        warn!(
+                            "Invalid WATERMARK_SCHEDULE '{}', watermarking at all times: {}",
+                            spec, e
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let watermark_param = settings
+            .var("WATERMARK_PARAM")
+            .ok()
+            .filter(|param| !param.is_empty())
+            .unwrap_or_else(|| "usercode".to_string());
+        // WATERMARK_DEFAULT is accepted as an alias
+        let default_watermark_text = settings
+            .var("DEFAULT_WATERMARK_TEXT")
+            .or_else(|_| settings.var("WATERMARK_DEFAULT"))
+            .ok()
+            .filter(|text| !text.trim().is_empty());
+        let max_watermark_text_length = settings.numeric("MAX_WATERMARK_TEXT_LENGTH", 0);
+        let watermark_text_header = settings
+            .var("WATERMARK_TEXT_HEADER")
+            .ok()
+            .filter(|header| !header.is_empty())
+            .unwrap_or_else(|| "X-Watermark-Text".to_string());
+        let mut watermark_text_sources = Vec::new();
+        for source in settings
+            .var("WATERMARK_TEXT_SOURCES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|source| !source.is_empty())
+        {
+            match source.to_lowercase().as_str() {
+                "param" => watermark_text_sources.push(TextSource::Param),
+                "header" => watermark_text_sources.push(TextSource::Header),
+                "default" => watermark_text_sources.push(TextSource::Default),
+                _ => warn!(
+                    "Ignoring unknown source '{}' in WATERMARK_TEXT_SOURCES",
+                    source
+                ),
+            }
+        }
+        if watermark_text_sources.is_empty() {
+            watermark_text_sources = vec![TextSource::Param, TextSource::Default];
+        }
+
+        let skip_prefixes = settings
+            .var("SKIP_PREFIXES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|prefix| prefix.trim().to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .collect();
+
+        let fallback_image_path = settings
+            .var("FALLBACK_IMAGE_PATH")
+            .ok()
+            .filter(|path| !path.is_empty());
+        let serve_fallback = settings.numeric("SERVE_FALLBACK", false);
+        if serve_fallback && fallback_image_path.is_none() {
+            warn!("SERVE_FALLBACK is enabled but FALLBACK_IMAGE_PATH is not set, ignoring it");
+        }
+        let tenant_config_path = settings
+            .var("TENANT_CONFIG_PATH")
+            .ok()
+            .filter(|path| !path.is_empty());
+
+        let chroma_key = settings
+            .numeric("CHROMA_KEY_ENABLED", false)
+            .then(|| ChromaKey {
+                color: [
+                    settings.numeric("CHROMA_KEY_COLOR_R", 0),
+                    settings.numeric("CHROMA_KEY_COLOR_G", 255),
+                    settings.numeric("CHROMA_KEY_COLOR_B", 0),
+                ],
+                tolerance: settings.numeric("CHROMA_KEY_TOLERANCE", 30),
+            });
+
+        // Reading resize settings
+        let target_width = settings.numeric("TARGET_WIDTH", 0);
+        let resize_before_watermark = settings.numeric("RESIZE_BEFORE_WATERMARK", false);
+        let sharpen_sigma =
+            if settings.var("SHARPEN_SIGMA").is_err() && settings.var("SHARPEN_AMOUNT").is_ok() {
+                warn!("SHARPEN_AMOUNT is deprecated, use SHARPEN_SIGMA, which it is read as");
+                settings.numeric("SHARPEN_AMOUNT", 0.0)
+            } else {
+                settings.numeric("SHARPEN_SIGMA", 0.0)
+            };
+        let max_pixels = settings.numeric("MAX_PIXELS", 0);
+        let preserve_dimensions = settings.numeric("PRESERVE_DIMENSIONS", false);
+        let resize_filter = match settings
+            .var("RESIZE_FILTER")
+            .unwrap_or_else(|_| "lanczos3".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "nearest" => FilterType::Nearest,
+            "triangle" => FilterType::Triangle,
+            "catmull-rom" => FilterType::CatmullRom,
+            "lanczos3" => FilterType::Lanczos3,
+            other => {
+                warn!(
+                    "Invalid value for RESIZE_FILTER '{}', using default: lanczos3",
+                    other
+                );
+                FilterType::Lanczos3
+            }
+        };
+
+        // Reading banner settings
+        let banner_text = settings
+            .var("BANNER_TEXT")
+            .ok()
+            .filter(|text| !text.trim().is_empty());
+        let banner_opacity: f32 = settings.numeric("BANNER_OPACITY", 0.35);
+        let banner_color = Rgba([
+            settings.numeric("BANNER_COLOR_R", 255),
+            settings.numeric("BANNER_COLOR_G", 0),
+            settings.numeric("BANNER_COLOR_B", 0),
+            (banner_opacity.clamp(0.0, 1.0) * 255.0).round() as u8,
+        ]);
+        let banner_angle = settings.numeric("BANNER_ANGLE", 30.0);
+
+        // Reading layered watermark settings
+        let layered_watermark = settings.numeric("LAYERED_WATERMARK", false);
+        let label_opacity: f32 = settings.numeric("LAYERED_LABEL_OPACITY", 0.6);
+        let label_alpha = (label_opacity.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let default_label_color = Rgba([255, 255, 255, label_alpha]);
+        let layered_label_color = match settings.var("LAYERED_LABEL_COLOR") {
+            Ok(value) if !value.is_empty() => {
+                parse_hex_color(&value, label_alpha).unwrap_or_else(|| {
+                    warn!(
+                        "Invalid value for LAYERED_LABEL_COLOR '{}', using default: #FFFFFF",
+                        value
+                    );
+                    default_label_color
+                })
+            }
+            _ => default_label_color,
+        };
+        let mut layered_label_scale = settings.numeric("LAYERED_LABEL_SCALE", 0.6);
+        if !(layered_label_scale > 0.0 && layered_label_scale <= 1.0) {
+            warn!(
+                "Invalid value for LAYERED_LABEL_SCALE '{}', using default: 0.6",
+                layered_label_scale
+            );
+            layered_label_scale = 0.6;
+        }
+
+        // Reading border settings
+        let border_width = settings.numeric("BORDER_WIDTH", 0);
+        let border_color = Rgba([
+            settings.numeric("BORDER_COLOR_R", 0),
+            settings.numeric("BORDER_COLOR_G", 0),
+            settings.numeric("BORDER_COLOR_B", 0),
+            settings.numeric("BORDER_COLOR_A", 255),
+        ]);
 
         // Reading image quality settings
-        let jpeg_quality = get_numeric("JPEG_QUALITY", 90);
+        let output_format = match settings
+            .var("OUTPUT_FORMAT")
+            .unwrap_or_else(|_| "auto".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "auto" => OutputFormat::Auto,
+            "jpeg" | "jpg" => OutputFormat::Jpeg,
+            "png" => OutputFormat::Png,
+            "webp" => OutputFormat::WebP,
+            "smallest" => OutputFormat::Smallest,
+            other => {
+                warn!(
+                    "Invalid value for OUTPUT_FORMAT '{}', using default: auto",
+                    other
+                );
+                OutputFormat::Auto
+            }
+        };
+        // Parsed wider than u8 so out-of-range values are clamped rather than rejected
+        let jpeg_quality = clamp_jpeg_quality(settings.numeric("JPEG_QUALITY", 90));
+        #[cfg(feature = "svg")]
+        let svg_render_width = settings.numeric("SVG_RENDER_WIDTH", 1024).max(1);
+        let decode_brute_force = settings.numeric("DECODE_BRUTE_FORCE", false);
+        let tolerate_truncated = settings.numeric("TOLERATE_TRUNCATED", false);
+        let apply_exif_orientation = settings.numeric("APPLY_EXIF_ORIENTATION", true);
+        let verify_output = settings.numeric("VERIFY_OUTPUT", false);
+
+        // Reading plugin settings
+        #[cfg(feature = "plugins")]
+        let plugin_path = settings
+            .var("PLUGIN_PATH")
+            .ok()
+            .filter(|path| !path.is_empty());
+        #[cfg(feature = "plugins")]
+        let plugin_stage = match settings
+            .var("PLUGIN_STAGE")
+            .unwrap_or_else(|_| "after".to_string())
+            .to_lowercase()
+            .as_str()
+        {
+            "before" => PluginStage::Before,
+            "after" => PluginStage::After,
+            other => {
+                warn!(
+                    "Invalid value for PLUGIN_STAGE '{}', using default: after",
+                    other
+                );
+                PluginStage::After
+            }
+        };
 
         // Reading Minio settings
-        let minio_endpoint = env::var("MINIO_ENDPOINT").expect("MINIO_ENDPOINT must be set");
-        let minio_access_key = env::var("MINIO_ACCESS_KEY").expect("MINIO_ACCESS_KEY must be set");
-        let minio_secret_key = env::var("MINIO_SECRET_KEY").expect("MINIO_SECRET_KEY must be set");
-        let minio_secure = env::var("MINIO_SECURE").expect("MINIO_SECURE must be set");
+        let minio_endpoint = settings
+            .var("MINIO_ENDPOINT")
+            .expect("MINIO_ENDPOINT must be set");
+        let minio_access_key = settings
+            .var("MINIO_ACCESS_KEY")
+            .expect("MINIO_ACCESS_KEY must be set");
+        let minio_secret_key = settings
+            .var("MINIO_SECRET_KEY")
+            .expect("MINIO_SECRET_KEY must be set");
+        let minio_secure = settings
+            .var("MINIO_SECURE")
+            .expect("MINIO_SECURE must be set");
         let minio_secure = minio_secure.parse::<bool>().unwrap_or(false);
+        let minio_ignore_cert = settings.numeric("MINIO_IGNORE_CERT", false);
+        let minio_ca_cert_file = settings
+            .var("MINIO_CA_CERT_FILE")
+            .ok()
+            .filter(|path| !path.is_empty());
+        let minio_timeout_secs = settings.numeric("MINIO_TIMEOUT_SECS", 30);
+        let minio_max_retries = clamp_max_retries(settings.numeric("MINIO_MAX_RETRIES", 2));
+        let max_object_bytes = settings.numeric("MAX_OBJECT_BYTES", 0);
+        let healthcheck_bucket = settings
+            .var("HEALTHCHECK_BUCKET")
+            .ok()
+            .filter(|bucket| !bucket.is_empty());
+        let allowed_buckets = settings
+            .var("ALLOWED_BUCKETS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|bucket| bucket.trim().to_string())
+            .filter(|bucket| !bucket.is_empty())
+            .collect();
+
+        // Reading URL resolution settings
+        let url_template = match settings.var("URL_TEMPLATE") {
+            Ok(pattern) if !pattern.is_empty() => match Regex::new(&pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    warn!("Invalid URL_TEMPLATE '{}', ignoring it: {}", pattern, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let url_template_bucket = settings.var("URL_TEMPLATE_BUCKET").unwrap_or_default();
         Self {
             host,
             port,
             workers,
-            log_level,
+            slow_request_ms,
+            admin_token,
+            usercode_hash_secret,
+            watermark_signing_key,
+            expose_timing_headers,
+            require_output_route,
+            cors_allowed_origins,
+            access_log_format,
+            error_format,
+            cache_max_entries,
+            cache_max_bytes,
+            cache_ttl_secs,
+            max_concurrent_downloads,
+            max_concurrent_renders,
+            render_queue_limit,
             font_path,
+            font_mmap,
+            font_fallback_paths,
+            watermark_image_path,
             font_height_ratio,
-            font_height_min,
-            font_width_ratio,
+            measured_spacing,
+            tiny_image_mode,
+            watermark_mode,
+            missing_glyph_replacement,
+            watermark_line_sep,
+            font_poison_policy,
             watermark_color,
-            shadow_color,
-            shadow_offset_ratio,
-            char_spacing_x_ratio,
-            char_spacing_y_ratio,
-            global_offset_x_ratio,
-            global_offset_y_ratio,
+            min_watermark_alpha,
+            watermark_adaptive,
+            adaptive_grid_size,
+            layout: LayoutOptions {
+                font_height_min,
+                font_width_ratio,
+                shadow_enabled,
+                shadow_color,
+                shadow_offset_ratio,
+                shadow_blur_sigma,
+                char_spacing_x_ratio,
+                char_spacing_y_ratio,
+                global_offset_x_ratio,
+                global_offset_y_ratio,
+                coverage_guarantee,
+                safe_area_margin,
+                auto_density_cap,
+                aspect_adaptive,
+                rotation_interpolation,
+            },
+            watermark_angle,
+            alpha_mask_layer,
+            watermark_output,
+            preview_placeholder,
+            watermark_schedule,
+            watermark_param,
+            default_watermark_text,
+            max_watermark_text_length,
+            watermark_text_header,
+            watermark_text_sources,
+            skip_prefixes,
+            fallback_image_path,
+            serve_fallback,
+            tenant_config_path,
+            chroma_key,
+            target_width,
+            resize_before_watermark,
+            sharpen_sigma,
+            resize_filter,
+            max_pixels,
+            preserve_dimensions,
+            banner_text,
+            banner_color,
+            banner_angle,
+            layered_watermark,
+            layered_label_color,
+            layered_label_scale,
+            border_width,
+            border_color,
+            output_format,
             jpeg_quality,
+            #[cfg(feature = "svg")]
+            svg_render_width,
+            decode_brute_force,
+            tolerate_truncated,
+            apply_exif_orientation,
+            verify_output,
+            #[cfg(feature = "plugins")]
+            plugin_path,
+            #[cfg(feature = "plugins")]
+            plugin_stage,
             minio_endpoint,
             minio_access_key,
             minio_secret_key,
             minio_secure,
+            minio_ignore_cert,
+            minio_ca_cert_file,
+            minio_timeout_secs,
+            minio_max_retries,
+            max_object_bytes,
+            healthcheck_bucket,
+            allowed_buckets,
+            url_template,
+            url_template_bucket,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jpeg_quality_is_clamped_to_the_encoder_range() {
+        assert_eq!(clamp_jpeg_quality(0), 1);
+        assert_eq!(clamp_jpeg_quality(100), 100);
+        assert_eq!(clamp_jpeg_quality(300), 100);
+    }
+
+    #[test]
+    fn max_retries_are_clamped() {
+        assert_eq!(clamp_max_retries(0), 0);
+        assert_eq!(clamp_max_retries(MAX_MINIO_RETRIES), MAX_MINIO_RETRIES);
+        assert_eq!(clamp_max_retries(1000), MAX_MINIO_RETRIES);
+    }
+
+    #[test]
+    fn hex_colors_of_every_length_are_parsed() {
+        assert_eq!(parse_hex_color("#f80", 128), Some(Rgba([255, 136, 0, 128])));
+        assert_eq!(parse_hex_color("#f804", 128), Some(Rgba([255, 136, 0, 68])));
+        assert_eq!(
+            parse_hex_color("#1a2B3c", 128),
+            Some(Rgba([26, 43, 60, 128]))
+        );
+        assert_eq!(
+            parse_hex_color("1a2b3c4d", 128),
+            Some(Rgba([26, 43, 60, 77]))
+        );
+        assert_eq!(
+            parse_hex_color("  #ffffff  ", 7),
+            Some(Rgba([255, 255, 255, 7]))
+        );
+    }
+
+    #[test]
+    fn invalid_hex_colors_are_rejected() {
+        for value in [
+            "",
+            "#",
+            "#ff",
+            "#fffff",
+            "#fffffffff",
+            "#ggg",
+            "#+f+f+f",
+            "#ffé",
+            "red",
+        ] {
+            assert_eq!(parse_hex_color(value, 255), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn max_glyphs_caps_glyph_draws_rather_than_tiles() {
+        let cap = |settings: Settings| Config::from_settings(&settings).layout.auto_density_cap;
+        assert_eq!(cap(Settings::test().with("MAX_GLYPHS", "100")), 50);
+        assert_eq!(
+            cap(Settings::test()
+                .with("MAX_GLYPHS", "100")
+                .with("SHADOW_ENABLED", "false")),
+            100
+        );
+        assert_eq!(cap(Settings::test().with("MAX_GLYPHS", "1")), 1);
+        assert_eq!(
+            cap(Settings::test()
+                .with("MAX_GLYPHS", "100")
+                .with("AUTO_DENSITY_CAP", "80")),
+            80
+        );
+    }
+}
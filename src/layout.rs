@@ -0,0 +1,1069 @@
+use crate::{FontChain, WatermarkStyle};
+use image::{imageops, GrayImage, ImageBuffer, Luma, Pixel, Rgba, RgbaImage};
+use imageproc::drawing::draw_text_mut;
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use log::info;
+use rusttype::{point, Font, Rect, Scale};
+
+/// Pixels added to the measured spacing of `WatermarkStyle::measured_spacing`
+const MEASURED_PADDING: f32 = 2.0;
+
+/// Settings that size, space and shadow the tiles, shared by every bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayoutOptions {
+    /// Smallest font height in pixels, whatever the image height
+    pub font_height_min: f32,
+    /// Glyph width as a fraction of the font height
+    pub font_width_ratio: f32,
+    /// Whether glyphs get a shadow at all
+    pub shadow_enabled: bool,
+    pub shadow_color: Rgba<u8>,
+    /// Shadow offset as a fraction of the glyph size
+    pub shadow_offset_ratio: f32,
+    /// Standard deviation in pixels of the Gaussian blur softening the shadows, or 0
+    /// for hard shadows
+    pub shadow_blur_sigma: f32,
+    /// Horizontal spacing as a fraction of the glyph width
+    pub char_spacing_x_ratio: f32,
+    /// Vertical spacing as a fraction of the font height
+    pub char_spacing_y_ratio: f32,
+    /// Shift of the whole grid as a fraction of the horizontal spacing
+    pub global_offset_x_ratio: f32,
+    /// Shift of the whole grid as a fraction of the vertical spacing
+    pub global_offset_y_ratio: f32,
+    /// Fraction of the image, centered, that must hold one complete copy of the text,
+    /// or 0 to not guarantee it
+    pub coverage_guarantee: f32,
+    /// Fraction of the width and height on every side that tiles leave untouched
+    pub safe_area_margin: f32,
+    /// Most tiles drawn per image, or 0 for no limit
+    pub auto_density_cap: usize,
+    /// Rebalance the spacing by aspect ratio, see `aspect_adaptive_spacing`
+    pub aspect_adaptive: bool,
+    /// Resampling of rotated layers, trading edge smoothness for speed
+    pub rotation_interpolation: Interpolation,
+}
+
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        Self {
+            font_height_min: 40.0,
+            font_width_ratio: 0.6,
+            shadow_enabled: true,
+            shadow_color: Rgba([0, 0, 0, 46]),
+            shadow_offset_ratio: 0.065,
+            shadow_blur_sigma: 0.0,
+            char_spacing_x_ratio: 1.1,
+            char_spacing_y_ratio: 0.4,
+            global_offset_x_ratio: -0.5,
+            global_offset_y_ratio: -1.2,
+            coverage_guarantee: 0.0,
+            safe_area_margin: 0.0,
+            auto_density_cap: 0,
+            aspect_adaptive: false,
+            rotation_interpolation: Interpolation::Bilinear,
+        }
+    }
+}
+
+impl LayoutOptions {
+    /// Font height for an image `height` pixels tall.
+    pub fn font_height(&self, height: u32, style: &WatermarkStyle) -> f32 {
+        (height as f32 * style.font_height_ratio).max(self.font_height_min)
+    }
+
+    /// Inner rectangle `(left, top, right, bottom)` that tiles may cover, inset on every
+    /// side by `safe_area_margin` of the image's width and height.
+    pub fn safe_area(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let margin_x = (width as f32 * self.safe_area_margin).round() as u32;
+        let margin_y = (height as f32 * self.safe_area_margin).round() as u32;
+        (margin_x, margin_y, width - margin_x, height - margin_y)
+    }
+}
+
+/// One line of the watermark text, as a range of the glyphs drawn by the tiles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextLine {
+    /// Index of the line's first glyph
+    pub start: usize,
+    pub len: usize,
+    /// Shift of the line's glyph sequence, see `guarantee_center_coverage`
+    pub phase: usize,
+}
+
+pub struct WatermarkLayout {
+    pub scale: Scale,
+    pub shadow_offset_x: i32,
+    pub shadow_offset_y: i32,
+    pub char_spacing_x: f32,
+    pub char_spacing_y: f32,
+    pub chars_per_row: usize,
+    pub rows: usize,
+    pub global_offset_x: f32,
+    pub global_offset_y: f32,
+    /// Lines of the text. Each band of `lines.len()` consecutive rows draws every line
+    /// on a row of its own.
+    pub lines: Vec<TextLine>,
+    /// Shift of the rows against the bands, so a band can start at any row
+    pub row_phase: usize,
+}
+
+impl WatermarkLayout {
+    /// Layout for a text of lines with `line_lengths` glyphs each, laid out one after
+    /// the other in the glyph list. A single line fills every row. `glyph_extent` is
+    /// the measured size of the glyphs from `layout::glyph_extent`, only used with
+    /// `WatermarkStyle::measured_spacing`.
+    pub fn new(
+        width: u32,
+        height: u32,
+        line_lengths: &[usize],
+        glyph_extent: (f32, f32),
+        style: &WatermarkStyle,
+        options: &LayoutOptions,
+    ) -> Self {
+        let font_height = options.font_height(height, style);
+        let glyph_extent = if style.measured_spacing {
+            glyph_extent
+        } else {
+            (0.0, 0.0)
+        };
+        if style.angle != 0.0 {
+            // Rotated tiles fill the larger canvas, so the centered crop guarantee does not apply
+            let (canvas_width, canvas_height) = layer_canvas_size(width, height, style.angle);
+            let mut layout = Self::with_font_height(
+                canvas_width,
+                canvas_height,
+                font_height,
+                glyph_extent,
+                options,
+            );
+            layout.set_lines(line_lengths);
+            return layout;
+        }
+        let mut layout = Self::with_font_height(width, height, font_height, glyph_extent, options);
+        layout.set_lines(line_lengths);
+        if options.coverage_guarantee > 0.0 && !layout.lines.is_empty() {
+            layout.guarantee_center_coverage(width, height, glyph_extent, options);
+        }
+        layout
+    }
+
+    fn set_lines(&mut self, line_lengths: &[usize]) {
+        let mut start = 0;
+        self.lines = line_lengths
+            .iter()
+            .filter(|&&len| len > 0)
+            .map(|&len| {
+                start += len;
+                TextLine {
+                    start: start - len,
+                    len,
+                    phase: 0,
+                }
+            })
+            .collect();
+    }
+
+    /// Layout at `font_height`, with the spacing of the ratios, widened to `glyph_extent`
+    /// (fractions of the font height) when that is larger.
+    pub fn with_font_height(
+        width: u32,
+        height: u32,
+        font_height: f32,
+        glyph_extent: (f32, f32),
+        options: &LayoutOptions,
+    ) -> Self {
+        let scale = Scale {
+            x: font_height * options.font_width_ratio,
+            y: font_height,
+        };
+        let (char_spacing_x, char_spacing_y) = aspect_adaptive_spacing(
+            width,
+            height,
+            scale,
+            scale.x * options.char_spacing_x_ratio,
+            scale.y * options.char_spacing_y_ratio,
+            options,
+        );
+        // The whole layer is rotated, so in the frame of the grid the rotated bounding
+        // box of a glyph is its measured box, and this spacing keeps tiles apart at any
+        // angle. The padding covers the rounding of positions and antialiased edges.
+        let (char_spacing_x, char_spacing_y) = if glyph_extent == (0.0, 0.0) {
+            (char_spacing_x, char_spacing_y)
+        } else {
+            (
+                char_spacing_x.max(glyph_extent.0 * scale.y + MEASURED_PADDING),
+                char_spacing_y.max(glyph_extent.1 * scale.y + MEASURED_PADDING),
+            )
+        };
+
+        let mut layout = Self::with_spacing(
+            width,
+            height,
+            scale,
+            char_spacing_x,
+            char_spacing_y,
+            options,
+        );
+        if options.auto_density_cap > 0 {
+            layout.cap_density(width, height, options.auto_density_cap, options);
+        }
+        if glyph_extent != (0.0, 0.0) {
+            // Measured tiles are no taller than their spacing, so the grid itself has
+            // to reach the far edges
+            layout.reach_far_edges(width, height);
+        }
+        layout
+    }
+
+    /// Adds the columns and rows that the global offsets leave short of the right and
+    /// bottom edges, which may exceed the density cap by one of each.
+    fn reach_far_edges(&mut self, width: u32, height: u32) {
+        let columns = ((width as f32 - self.global_offset_x) / self.char_spacing_x).ceil();
+        let rows = ((height as f32 - self.global_offset_y) / self.char_spacing_y).ceil();
+        self.chars_per_row = self.chars_per_row.max(columns as usize);
+        self.rows = self.rows.max(rows as usize);
+    }
+
+    pub fn with_spacing(
+        width: u32,
+        height: u32,
+        scale: Scale,
+        char_spacing_x: f32,
+        char_spacing_y: f32,
+        options: &LayoutOptions,
+    ) -> Self {
+        let shadow_offset_ratio = options.shadow_offset_ratio;
+        let shadow_offset_x = (scale.x * shadow_offset_ratio).round() as i32;
+        let shadow_offset_y = (scale.y * shadow_offset_ratio).round() as i32;
+
+        let chars_per_row = ((width as f32 / char_spacing_x).ceil() as usize).max(1);
+        let rows = ((height as f32 / char_spacing_y).ceil() as usize).max(1) + 1;
+        let global_offset_x = char_spacing_x * options.global_offset_x_ratio;
+        let global_offset_y = char_spacing_y * options.global_offset_y_ratio;
+
+        Self {
+            scale,
+            shadow_offset_x,
+            shadow_offset_y,
+            char_spacing_x,
+            char_spacing_y,
+            chars_per_row,
+            rows,
+            global_offset_x,
+            global_offset_y,
+            lines: Vec::new(),
+            row_phase: 0,
+        }
+    }
+
+    pub fn tile_count(&self) -> usize {
+        self.rows * self.chars_per_row
+    }
+
+    /// Widens the spacing in both directions, keeping full coverage, until the
+    /// number of tiles is at most `cap`.
+    fn cap_density(&mut self, width: u32, height: u32, cap: usize, options: &LayoutOptions) {
+        let original_count = self.tile_count();
+        // Even the sparsest layout has two rows of one tile
+        let cap = cap.max(2);
+        if original_count <= cap {
+            return;
+        }
+
+        let mut factor = (original_count as f32 / cap as f32).sqrt();
+        loop {
+            let capped = Self::with_spacing(
+                width,
+                height,
+                self.scale,
+                self.char_spacing_x * factor,
+                self.char_spacing_y * factor,
+                options,
+            );
+            if capped.tile_count() <= cap {
+                info!(
+                    "AUTO_DENSITY_CAP: {} tiles exceed the cap of {}, spacing widened {:.2}x to {} tiles",
+                    original_count,
+                    cap,
+                    factor,
+                    capped.tile_count()
+                );
+                *self = capped;
+                return;
+            }
+            factor *= 1.1;
+        }
+    }
+
+    /// Shrinks the glyphs if needed, down to `font_height_min`, and shifts the character
+    /// sequences so that one complete, in-order copy of every line lands inside the
+    /// central crop region, on consecutive rows. `auto_density_cap` still applies to the
+    /// rows extended for the copy, widening the spacing even if the copy then no longer
+    /// fits the crop.
+    fn guarantee_center_coverage(
+        &mut self,
+        width: u32,
+        height: u32,
+        glyph_extent: (f32, f32),
+        options: &LayoutOptions,
+    ) {
+        let fraction = options.coverage_guarantee.min(1.0);
+        let crop_width = width as f32 * fraction;
+        let crop_height = height as f32 * fraction;
+        let line_count = self.lines.len();
+        let longest = self.lines.iter().map(|line| line.len).max().unwrap_or(0);
+
+        // One extra spacing of slack absorbs the rounding to whole tile columns below
+        let text_width = |layout: &Self| layout.char_spacing_x * longest as f32 + layout.scale.x;
+        let block_height =
+            |layout: &Self| layout.char_spacing_y * (line_count - 1) as f32 + layout.scale.y;
+        let fit = (crop_width / text_width(self)).min(crop_height / block_height(self));
+        if fit < 1.0 && self.scale.y > options.font_height_min {
+            let font_height = (self.scale.y * fit).max(options.font_height_min);
+            info!(
+                "Shrinking font height from {:.1} to {:.1} px so the text fits the central {:.0}% crop",
+                self.scale.y,
+                font_height,
+                fraction * 100.0
+            );
+            let lines = std::mem::take(&mut self.lines);
+            *self = Self::with_font_height(width, height, font_height, glyph_extent, options);
+            self.lines = lines;
+        }
+
+        while !self.center_lines(width, height, options.auto_density_cap) {
+            let lines = std::mem::take(&mut self.lines);
+            *self = Self::with_spacing(
+                width,
+                height,
+                self.scale,
+                self.char_spacing_x * 1.1,
+                self.char_spacing_y * 1.1,
+                options,
+            );
+            self.lines = lines;
+        }
+    }
+
+    /// Sets the phases that center one copy of every line, extending the rows when the
+    /// copy runs past the last column. Returns false, leaving the layout unchanged, when
+    /// the extended rows would hold more than `cap` tiles.
+    fn center_lines(&mut self, width: u32, height: u32, cap: usize) -> bool {
+        let line_count = self.lines.len();
+        let block_height =
+            |layout: &Self| layout.char_spacing_y * (line_count - 1) as f32 + layout.scale.y;
+        let (_, first_row_y) = self.position(0, 0);
+        let center_row = ((height as f32 / 2.0 - block_height(self) / 2.0 - first_row_y as f32)
+            / self.char_spacing_y)
+            .round()
+            .max(0.0) as usize;
+        let center_row = center_row.min(self.rows.saturating_sub(line_count));
+        let row_phase = (line_count - center_row % line_count) % line_count;
+        let band = (center_row + row_phase) / line_count;
+
+        let mut chars_per_row = self.chars_per_row;
+        let mut phases = Vec::with_capacity(line_count);
+        for (offset, line) in self.lines.iter().enumerate() {
+            let (first_col_x, _) = self.position(center_row + offset, 0);
+            let start_x =
+                (width as f32 - (self.char_spacing_x * (line.len - 1) as f32 + self.scale.x)) / 2.0;
+            let start_col = ((start_x - first_col_x as f32) / self.char_spacing_x)
+                .round()
+                .max(0.0) as usize;
+
+            // Extend the rows if the centered copy would run past the last column
+            chars_per_row = chars_per_row.max(start_col + line.len);
+            phases.push((line.len - (band + start_col) % line.len) % line.len);
+        }
+        // Even the sparsest layout has two rows of one tile, like in `cap_density`
+        if cap > 0 && self.rows * chars_per_row > cap.max(2) && self.tile_count() > 2 {
+            return false;
+        }
+
+        self.row_phase = row_phase;
+        self.chars_per_row = chars_per_row;
+        for (line, phase) in self.lines.iter_mut().zip(phases) {
+            line.phase = phase;
+        }
+        true
+    }
+
+    /// Index into the glyphs of the text drawn at the given tile row and column.
+    pub fn char_index(&self, row: usize, col: usize) -> usize {
+        let row = row + self.row_phase;
+        let line = &self.lines[row % self.lines.len()];
+        line.start + (row / self.lines.len() + col + line.phase) % line.len
+    }
+
+    /// Whether a tile at the given position is drawn. Tiles entirely inside the margin
+    /// are skipped; rotated tiles use canvas coordinates and are always drawn.
+    pub fn draws_tile(
+        &self,
+        x_pos: i32,
+        y_pos: i32,
+        (left, top, right, bottom): (u32, u32, u32, u32),
+        angle: f32,
+    ) -> bool {
+        angle != 0.0
+            || (x_pos as f32 + self.char_spacing_x > left as f32
+                && x_pos < right as i32
+                && y_pos as f32 + self.scale.y > top as f32
+                && y_pos < bottom as i32)
+    }
+
+    /// Number of glyphs `build_watermark_layer` draws for this layout, not counting
+    /// their shadows.
+    pub fn glyph_count(
+        &self,
+        width: u32,
+        height: u32,
+        angle: f32,
+        options: &LayoutOptions,
+    ) -> usize {
+        let safe_area = options.safe_area(width, height);
+        (0..self.rows)
+            .flat_map(|row| (0..self.chars_per_row).map(move |col| (row, col)))
+            .filter(|&(row, col)| {
+                let (x_pos, y_pos) = self.position(row, col);
+                self.draws_tile(x_pos, y_pos, safe_area, angle)
+            })
+            .count()
+    }
+
+    /// Top-left position of the glyph drawn at the given tile row and column.
+    pub fn position(&self, row: usize, col: usize) -> (i32, i32) {
+        let x_stagger = if row.is_multiple_of(2) {
+            0.0
+        } else {
+            self.char_spacing_x / 2.0
+        };
+        let x_pos =
+            (col as f32 * self.char_spacing_x + x_stagger + self.global_offset_x).round() as i32;
+        let y_pos = (row as f32 * self.char_spacing_y + self.global_offset_y).round() as i32;
+        (x_pos, y_pos)
+    }
+}
+
+/// Width and height of the box around every glyph of `chars` drawn at the same
+/// position, shadow included, as fractions of the font height, for
+/// `WatermarkStyle::measured_spacing`. Tiles at least this far apart do not overlap.
+pub fn glyph_extent(chars: &[String], fonts: &FontChain, options: &LayoutOptions) -> (f32, f32) {
+    const UNIT: f32 = 100.0;
+    let scale = Scale {
+        x: UNIT * options.font_width_ratio,
+        y: UNIT,
+    };
+    let mut bounds: Option<Rect<f32>> = None;
+    for grapheme in chars {
+        let font = fonts.font_for(grapheme);
+        let origin = point(0.0, font.v_metrics(scale).ascent);
+        for glyph in font.layout(grapheme, scale, origin) {
+            let Some(bb) = glyph.unpositioned().exact_bounding_box() else {
+                continue;
+            };
+            let position = glyph.position();
+            let (min, max) = (
+                point(position.x + bb.min.x, position.y + bb.min.y),
+                point(position.x + bb.max.x, position.y + bb.max.y),
+            );
+            let bounds = bounds.get_or_insert(Rect { min, max });
+            bounds.min = point(bounds.min.x.min(min.x), bounds.min.y.min(min.y));
+            bounds.max = point(bounds.max.x.max(max.x), bounds.max.y.max(max.y));
+        }
+    }
+    let Some(bounds) = bounds else {
+        return (0.0, 0.0);
+    };
+    let shadow = if options.shadow_enabled {
+        options.shadow_offset_ratio
+    } else {
+        0.0
+    };
+    (
+        bounds.width() / UNIT + shadow * options.font_width_ratio,
+        bounds.height() / UNIT + shadow,
+    )
+}
+
+/// Rebalances the spacing for `aspect_adaptive`. With `a` the aspect ratio (width over
+/// height), the horizontal spacing is multiplied by `sqrt(a)` and the vertical spacing
+/// divided by it, with `sqrt(a)` clamped to 0.5..2. Rows and columns then keep the
+/// proportions they have on a square image while the covered fraction of the image stays
+/// the same. Glyphs in a row are not pushed closer than their own width, and the rows
+/// then move closer instead.
+pub fn aspect_adaptive_spacing(
+    width: u32,
+    height: u32,
+    scale: Scale,
+    char_spacing_x: f32,
+    char_spacing_y: f32,
+    options: &LayoutOptions,
+) -> (f32, f32) {
+    if !options.aspect_adaptive || width == 0 || height == 0 {
+        return (char_spacing_x, char_spacing_y);
+    }
+    let factor = (width as f32 / height as f32).sqrt().clamp(0.5, 2.0);
+    let adapted_x = (char_spacing_x * factor).max(char_spacing_x.min(scale.x));
+    // Dividing by the adapted spacing rather than the factor keeps the area of a tile,
+    // and so the density, when the glyph width holds the horizontal spacing back
+    (adapted_x, char_spacing_x * char_spacing_y / adapted_x)
+}
+
+/// Size of the canvas the tiles are laid out on. With an angle set it is a square as
+/// wide as the image diagonal, so the tiles still reach every corner of the image after
+/// rotation.
+pub fn layer_canvas_size(width: u32, height: u32, angle: f32) -> (u32, u32) {
+    if angle == 0.0 {
+        return (width, height);
+    }
+    let diagonal = (width as f32).hypot(height as f32).ceil() as u32;
+    (diagonal, diagonal)
+}
+
+/// Draws each tile of `layout` with its glyph from `chars` and its shadow, and returns
+/// the finished layer with the number of glyph draws.
+pub fn build_watermark_layer(
+    width: u32,
+    height: u32,
+    chars: &[String],
+    fonts: &FontChain,
+    layout: &WatermarkLayout,
+    style: &WatermarkStyle,
+    options: &LayoutOptions,
+) -> (RgbaImage, usize) {
+    // Create a transparent layer for the watermark text and shadow
+    let (canvas_width, canvas_height) = layer_canvas_size(width, height, style.angle);
+    let watermark_layer = RgbaImage::new(canvas_width, canvas_height);
+    let (watermark_layer, glyph_draws) = draw_shadowed_tiles(
+        watermark_layer,
+        (options.shadow_color, style.color),
+        (width, height),
+        chars,
+        fonts,
+        layout,
+        style,
+        options,
+    );
+
+    (
+        finish_layer(watermark_layer, width, height, style.angle, options),
+        glyph_draws,
+    )
+}
+
+/// Like `build_watermark_layer`, but draws only the coverage of text and shadow, at
+/// their alpha, into a single-channel mask to be blended in one color with
+/// `blend_mask`. Only valid when the shadow is invisible or has the text's color.
+pub fn build_watermark_mask(
+    width: u32,
+    height: u32,
+    chars: &[String],
+    fonts: &FontChain,
+    layout: &WatermarkLayout,
+    style: &WatermarkStyle,
+    options: &LayoutOptions,
+) -> (GrayImage, usize) {
+    let (canvas_width, canvas_height) = layer_canvas_size(width, height, style.angle);
+    let mask = GrayImage::new(canvas_width, canvas_height);
+    let (mask, glyph_draws) = draw_shadowed_tiles(
+        mask,
+        (Luma([options.shadow_color[3]]), Luma([style.color[3]])),
+        (width, height),
+        chars,
+        fonts,
+        layout,
+        style,
+        options,
+    );
+
+    (
+        finish_layer(mask, width, height, style.angle, options),
+        glyph_draws,
+    )
+}
+
+/// Whether the tiles can be drawn as a `build_watermark_mask`, because the shadow adds
+/// no second color.
+pub fn is_single_color(style: &WatermarkStyle, options: &LayoutOptions) -> bool {
+    !options.shadow_enabled
+        || options.shadow_color[3] == 0
+        || options.shadow_color.0[..3] == style.color.0[..3]
+}
+
+/// Draws every tile onto `layer` in the given `(shadow, text)` pixel values, leaving
+/// out the shadows when they are disabled. Blurred shadows are all drawn and blurred
+/// first, so the glyphs drawn over them stay sharp. Returns the layer and the number
+/// of glyph draws.
+#[allow(clippy::too_many_arguments)]
+fn draw_shadowed_tiles<P>(
+    mut layer: ImageBuffer<P, Vec<u8>>,
+    (shadow, text): (P, P),
+    image_size: (u32, u32),
+    chars: &[String],
+    fonts: &FontChain,
+    layout: &WatermarkLayout,
+    style: &WatermarkStyle,
+    options: &LayoutOptions,
+) -> (ImageBuffer<P, Vec<u8>>, usize)
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let draw = |layer: &mut ImageBuffer<P, Vec<u8>>, pixels| {
+        draw_tiles(
+            layer, pixels, image_size, chars, fonts, layout, style, options,
+        )
+    };
+    if !options.shadow_enabled {
+        let glyph_draws = draw(&mut layer, (None, Some(text)));
+        return (layer, glyph_draws);
+    }
+    if options.shadow_blur_sigma <= 0.0 {
+        let glyph_draws = draw(&mut layer, (Some(shadow), Some(text)));
+        return (layer, glyph_draws);
+    }
+
+    let shadow_draws = draw(&mut layer, (Some(shadow), None));
+    let mut layer = gaussian_blur_f32(&layer, options.shadow_blur_sigma);
+    let text_draws = draw(&mut layer, (None, Some(text)));
+    (layer, shadow_draws + text_draws)
+}
+
+/// Draws the shadow and then the glyph of every tile onto `layer` in the given
+/// `(shadow, text)` pixel values, skipping either when `None`. Returns the number of
+/// glyph draws.
+#[allow(clippy::too_many_arguments)]
+fn draw_tiles<P>(
+    layer: &mut ImageBuffer<P, Vec<u8>>,
+    (shadow, text): (Option<P>, Option<P>),
+    (width, height): (u32, u32),
+    chars: &[String],
+    fonts: &FontChain,
+    layout: &WatermarkLayout,
+    style: &WatermarkStyle,
+    options: &LayoutOptions,
+) -> usize
+where
+    P: Pixel<Subpixel = u8>,
+{
+    let mut glyph_draws = 0;
+    let safe_area = options.safe_area(width, height);
+    let char_fonts: Vec<&Font<'static>> = chars.iter().map(|c| fonts.font_for(c)).collect();
+
+    for row in 0..layout.rows {
+        for col in 0..layout.chars_per_row {
+            let (x_pos, y_pos) = layout.position(row, col);
+            if !layout.draws_tile(x_pos, y_pos, safe_area, style.angle) {
+                continue;
+            }
+            let char_idx = layout.char_index(row, col);
+
+            // Draw shadow on the watermark layer
+            if let Some(shadow) = shadow {
+                draw_text_mut(
+                    layer,
+                    shadow,
+                    x_pos + layout.shadow_offset_x,
+                    y_pos + layout.shadow_offset_y,
+                    layout.scale,
+                    char_fonts[char_idx],
+                    &chars[char_idx],
+                );
+                glyph_draws += 1;
+            }
+
+            // Draw watermark text on the watermark layer
+            if let Some(text) = text {
+                draw_text_mut(
+                    layer,
+                    text,
+                    x_pos,
+                    y_pos,
+                    layout.scale,
+                    char_fonts[char_idx],
+                    &chars[char_idx],
+                );
+                glyph_draws += 1;
+            }
+        }
+    }
+    glyph_draws
+}
+
+/// Rotates a layer drawn on the `layer_canvas_size` canvas by `angle`, crops it to the
+/// image and clears the safe area margin.
+pub fn finish_layer<P>(
+    mut watermark_layer: ImageBuffer<P, Vec<u8>>,
+    width: u32,
+    height: u32,
+    angle: f32,
+    options: &LayoutOptions,
+) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + Send + Sync + 'static,
+{
+    // All channels zero is transparent for RGBA layers and masks alike
+    let clear = *P::from_slice(&[0; 4][..P::CHANNEL_COUNT as usize]);
+    let (canvas_width, canvas_height) = watermark_layer.dimensions();
+    if angle != 0.0 {
+        // rotate_about_center turns clockwise, so negate for counterclockwise angles
+        let rotated = rotate_about_center(
+            &watermark_layer,
+            -angle.to_radians(),
+            options.rotation_interpolation,
+            clear,
+        );
+        watermark_layer = imageops::crop_imm(
+            &rotated,
+            (canvas_width - width) / 2,
+            (canvas_height - height) / 2,
+            width,
+            height,
+        )
+        .to_image();
+    }
+
+    if options.safe_area_margin > 0.0 {
+        // Clip tiles straddling the margin so it stays untouched
+        let (left, top, right, bottom) = options.safe_area(width, height);
+        for (x, y, pixel) in watermark_layer.enumerate_pixels_mut() {
+            if x < left || x >= right || y < top || y >= bottom {
+                *pixel = clear;
+            }
+        }
+    }
+
+    watermark_layer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coverage_options() -> LayoutOptions {
+        LayoutOptions {
+            coverage_guarantee: 0.5,
+            ..LayoutOptions::default()
+        }
+    }
+
+    #[test]
+    fn coverage_guarantee_does_not_shrink_below_the_minimum_font_height() {
+        let options = coverage_options();
+        let layout = WatermarkLayout::new(
+            1000,
+            1000,
+            &[1000],
+            (0.0, 0.0),
+            &WatermarkStyle::default(),
+            &options,
+        );
+        assert_eq!(layout.scale.y, options.font_height_min);
+    }
+
+    #[test]
+    fn coverage_guarantee_keeps_the_density_cap() {
+        let options = LayoutOptions {
+            auto_density_cap: 100,
+            ..coverage_options()
+        };
+        let layout = WatermarkLayout::new(
+            1000,
+            1000,
+            &[20],
+            (0.0, 0.0),
+            &WatermarkStyle::default(),
+            &options,
+        );
+        assert!(layout.tile_count() <= 100, "{} tiles", layout.tile_count());
+    }
+
+    /// Whether the glyphs of `line` are drawn, in order, on consecutive tiles of `row`
+    /// that all lie inside `crop`.
+    fn row_holds_line(
+        layout: &WatermarkLayout,
+        row: usize,
+        line: &TextLine,
+        (left, top, right, bottom): (f32, f32, f32, f32),
+    ) -> bool {
+        let inside = |col: usize| {
+            let (x, y) = layout.position(row, col);
+            x as f32 >= left
+                && x as f32 + layout.scale.x <= right
+                && y as f32 >= top
+                && y as f32 + layout.scale.y <= bottom
+        };
+        (0..layout.chars_per_row.saturating_sub(line.len - 1)).any(|first| {
+            (0..line.len)
+                .all(|i| inside(first + i) && layout.char_index(row, first + i) == line.start + i)
+        })
+    }
+
+    #[test]
+    fn coverage_guarantee_puts_every_line_in_the_central_crop() {
+        let (width, height) = (1200, 800);
+        for line_lengths in [vec![12], vec![12, 7], vec![5, 9, 3]] {
+            let layout = WatermarkLayout::new(
+                width,
+                height,
+                &line_lengths,
+                (0.0, 0.0),
+                &WatermarkStyle::default(),
+                &coverage_options(),
+            );
+            let crop = (
+                width as f32 * 0.25,
+                height as f32 * 0.25,
+                width as f32 * 0.75,
+                height as f32 * 0.75,
+            );
+            let line_count = layout.lines.len();
+            let copy_row = (0..=layout.rows - line_count).find(|&row| {
+                layout
+                    .lines
+                    .iter()
+                    .enumerate()
+                    .all(|(offset, line)| row_holds_line(&layout, row + offset, line, crop))
+            });
+            assert!(copy_row.is_some(), "no centered copy of {:?}", line_lengths);
+        }
+    }
+
+    #[test]
+    fn center_lines_leaves_the_layout_unchanged_past_the_cap() {
+        let mut layout = WatermarkLayout::new(
+            1000,
+            1000,
+            &[4, 3],
+            (0.0, 0.0),
+            &WatermarkStyle::default(),
+            &LayoutOptions::default(),
+        );
+        let (row_phase, chars_per_row, lines) =
+            (layout.row_phase, layout.chars_per_row, layout.lines.clone());
+        assert!(!layout.center_lines(1000, 1000, 3));
+        assert_eq!(layout.row_phase, row_phase);
+        assert_eq!(layout.chars_per_row, chars_per_row);
+        assert_eq!(layout.lines, lines);
+    }
+
+    /// Number of pixels of a white square rotated by 45 degrees whose alpha is neither
+    /// fully transparent nor fully opaque, i.e. the antialiased edge pixels.
+    fn rotated_edge_pixels(interpolation: Interpolation) -> usize {
+        let (canvas_width, canvas_height) = layer_canvas_size(100, 100, 45.0);
+        let mut layer = RgbaImage::new(canvas_width, canvas_height);
+        for y in 40..(canvas_height - 40) {
+            for x in 40..(canvas_width - 40) {
+                layer.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+            }
+        }
+        let options = LayoutOptions {
+            rotation_interpolation: interpolation,
+            ..LayoutOptions::default()
+        };
+        finish_layer(layer, 100, 100, 45.0, &options)
+            .pixels()
+            .filter(|pixel| pixel[3] != 0 && pixel[3] != 255)
+            .count()
+    }
+
+    #[test]
+    fn smooth_interpolations_antialias_rotated_edges() {
+        assert_eq!(rotated_edge_pixels(Interpolation::Nearest), 0);
+        assert!(rotated_edge_pixels(Interpolation::Bilinear) > 0);
+        assert!(rotated_edge_pixels(Interpolation::Bicubic) > 0);
+    }
+
+    const MEASURED_ANGLES: [f32; 4] = [0.0, 30.0, 45.0, 90.0];
+
+    fn measured_layout(
+        angle: f32,
+        measured_spacing: bool,
+    ) -> (WatermarkLayout, Vec<String>, FontChain, (u32, u32)) {
+        let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+        let fonts = FontChain::new(font, Vec::new());
+        let chars: Vec<String> = "user@example.com".chars().map(String::from).collect();
+        let style = WatermarkStyle {
+            angle,
+            measured_spacing,
+            ..WatermarkStyle::default()
+        };
+        let options = LayoutOptions::default();
+        let layout = WatermarkLayout::new(
+            400,
+            300,
+            &[chars.len()],
+            glyph_extent(&chars, &fonts, &options),
+            &style,
+            &options,
+        );
+        (layout, chars, fonts, layer_canvas_size(400, 300, angle))
+    }
+
+    /// Records which tile drew each pixel, counting the pixels drawn by several tiles.
+    struct OverlapCanvas {
+        width: u32,
+        height: u32,
+        tile: usize,
+        owners: Vec<usize>,
+        overlaps: usize,
+    }
+
+    impl imageproc::drawing::Canvas for OverlapCanvas {
+        type Pixel = Luma<u8>;
+
+        fn dimensions(&self) -> (u32, u32) {
+            (self.width, self.height)
+        }
+
+        fn get_pixel(&self, _x: u32, _y: u32) -> Luma<u8> {
+            Luma([0])
+        }
+
+        fn draw_pixel(&mut self, x: u32, y: u32, color: Luma<u8>) {
+            if color[0] == 0 {
+                return;
+            }
+            let owner = &mut self.owners[(y * self.width + x) as usize];
+            if *owner != 0 && *owner != self.tile {
+                self.overlaps += 1;
+            }
+            *owner = self.tile;
+        }
+    }
+
+    /// Pixels drawn by more than one tile, shadows included. The layer is rotated as a
+    /// whole, so tiles overlapping after rotation already overlap on the canvas.
+    fn overlapping_pixels(angle: f32, measured_spacing: bool) -> usize {
+        let (layout, chars, fonts, (width, height)) = measured_layout(angle, measured_spacing);
+        let mut canvas = OverlapCanvas {
+            width,
+            height,
+            tile: 0,
+            owners: vec![0; (width * height) as usize],
+            overlaps: 0,
+        };
+        for row in 0..layout.rows {
+            for col in 0..layout.chars_per_row {
+                canvas.tile += 1;
+                let (x, y) = layout.position(row, col);
+                let glyph = &chars[layout.char_index(row, col)];
+                let font = fonts.font_for(glyph);
+                for (dx, dy) in [(layout.shadow_offset_x, layout.shadow_offset_y), (0, 0)] {
+                    let color = Luma([255]);
+                    draw_text_mut(
+                        &mut canvas,
+                        color,
+                        x + dx,
+                        y + dy,
+                        layout.scale,
+                        font,
+                        glyph,
+                    );
+                }
+            }
+        }
+        canvas.overlaps
+    }
+
+    #[test]
+    fn default_spacing_lets_tiles_overlap() {
+        assert!(overlapping_pixels(0.0, false) > 0);
+    }
+
+    #[test]
+    fn measured_spacing_keeps_tiles_apart_at_any_angle() {
+        for angle in MEASURED_ANGLES {
+            assert_eq!(overlapping_pixels(angle, true), 0, "angle {}", angle);
+        }
+    }
+
+    #[test]
+    fn measured_spacing_covers_the_whole_image_at_any_angle() {
+        for angle in MEASURED_ANGLES {
+            let (layout, _, _, (canvas_width, canvas_height)) = measured_layout(angle, true);
+            let last_row = layout.rows - 1;
+            let last_col = layout.chars_per_row - 1;
+            // The span every row covers, whether staggered or not
+            let left = layout.position(0, 0).0.max(layout.position(1, 0).0) as f32;
+            let right = layout
+                .position(0, last_col)
+                .0
+                .min(layout.position(1, last_col).0) as f32
+                + layout.char_spacing_x;
+            let top = layout.position(0, 0).1 as f32;
+            let bottom = layout.position(last_row, 0).1 as f32 + layout.char_spacing_y;
+
+            // Image corners turned back by the angle onto the canvas
+            let (sin, cos) = angle.to_radians().sin_cos();
+            for (x, y) in [(0.0, 0.0), (400.0, 0.0), (0.0, 300.0), (400.0, 300.0)] {
+                let (x, y) = (x - 200.0, y - 150.0);
+                let canvas_x = x * cos - y * sin + canvas_width as f32 / 2.0;
+                let canvas_y = x * sin + y * cos + canvas_height as f32 / 2.0;
+                assert!(
+                    (left..=right).contains(&canvas_x) && (top..=bottom).contains(&canvas_y),
+                    "angle {}: corner ({}, {}) at ({}, {}) outside {:?}",
+                    angle,
+                    x,
+                    y,
+                    canvas_x,
+                    canvas_y,
+                    (left, top, right, bottom)
+                );
+            }
+        }
+    }
+
+    /// Tiles starting inside a `width` x `height` image per million pixels, at a fixed
+    /// font height so only the spacing differs.
+    fn tile_density(width: u32, height: u32, aspect_adaptive: bool) -> f32 {
+        let options = LayoutOptions {
+            aspect_adaptive,
+            ..LayoutOptions::default()
+        };
+        let layout = WatermarkLayout::with_font_height(width, height, 40.0, (0.0, 0.0), &options);
+        let inside = (0..layout.rows)
+            .flat_map(|row| (0..layout.chars_per_row).map(move |col| (row, col)))
+            .filter(|&(row, col)| {
+                let (x, y) = layout.position(row, col);
+                (0..width as i32).contains(&x) && (0..height as i32).contains(&y)
+            })
+            .count();
+        inside as f32 * 1e6 / (width * height) as f32
+    }
+
+    #[test]
+    fn aspect_adaptive_spacing_keeps_tile_density_across_aspect_ratios() {
+        let square = tile_density(800, 800, true);
+        for (width, height) in [(3200, 400), (400, 3200)] {
+            let density = tile_density(width, height, true);
+            assert!(
+                (density / square - 1.0).abs() < 0.1,
+                "{}x{}: {} tiles per megapixel, {} on a square image",
+                width,
+                height,
+                density,
+                square
+            );
+        }
+    }
+
+    #[test]
+    fn aspect_adaptive_spacing_gives_wide_images_more_rows() {
+        let options = LayoutOptions {
+            aspect_adaptive: true,
+            ..LayoutOptions::default()
+        };
+        let fixed = WatermarkLayout::with_font_height(
+            3200,
+            400,
+            40.0,
+            (0.0, 0.0),
+            &LayoutOptions::default(),
+        );
+        let adaptive = WatermarkLayout::with_font_height(3200, 400, 40.0, (0.0, 0.0), &options);
+        assert!(adaptive.rows > fixed.rows);
+        assert!(adaptive.chars_per_row < fixed.chars_per_row);
+    }
+}
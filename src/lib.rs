@@ -0,0 +1,533 @@
+//! The watermark renderer behind the service, usable without the web server or MinIO.
+//!
+//! [`watermark`] tiles the text over an encoded image and returns the image encoded
+//! again. The [`layout`] module exposes the tile layout and layer building it uses.
+
+use image::{GrayImage, ImageFormat, ImageOutputFormat, Rgba, RgbaImage};
+use rusttype::Font;
+use std::io::Cursor;
+use unicode_bidi::BidiInfo;
+use unicode_segmentation::UnicodeSegmentation;
+
+pub mod layout;
+
+use layout::{build_watermark_layer, glyph_extent, LayoutOptions, WatermarkLayout};
+
+/// The watermark settings that can be overridden per bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatermarkStyle {
+    pub color: Rgba<u8>,
+    /// Counterclockwise rotation of the tiles in degrees
+    pub angle: f32,
+    /// Font height as a fraction of the image height
+    pub font_height_ratio: f32,
+    /// Space the tiles at least as far apart as the measured glyphs are wide and tall,
+    /// so they never overlap, see `layout::glyph_extent`
+    pub measured_spacing: bool,
+}
+
+impl Default for WatermarkStyle {
+    fn default() -> Self {
+        Self {
+            color: Rgba([255, 255, 255, 46]),
+            angle: 0.0,
+            font_height_ratio: 0.10,
+            measured_spacing: false,
+        }
+    }
+}
+
+/// The watermark font and the fallback fonts tried, in order, for characters it has
+/// no glyph for. Fonts are picked per grapheme, so each tile is drawn in one font.
+#[derive(Clone)]
+pub struct FontChain {
+    fonts: Vec<Font<'static>>,
+}
+
+impl FontChain {
+    pub fn new(primary: Font<'static>, fallbacks: Vec<Font<'static>>) -> Self {
+        let mut fonts = Vec::with_capacity(fallbacks.len() + 1);
+        fonts.push(primary);
+        fonts.extend(fallbacks);
+        Self { fonts }
+    }
+
+    pub fn primary(&self) -> &Font<'static> {
+        &self.fonts[0]
+    }
+
+    /// The first font with glyphs for every character of `grapheme`, or the primary
+    /// font when none has them all.
+    pub fn font_for(&self, grapheme: &str) -> &Font<'static> {
+        self.fonts
+            .iter()
+            .find(|font| grapheme.chars().all(|c| has_glyph(font, c)))
+            .unwrap_or(&self.fonts[0])
+    }
+
+    /// Whether some font of the chain has glyphs for every character of `grapheme`.
+    pub fn has_glyphs(&self, grapheme: &str) -> bool {
+        self.fonts
+            .iter()
+            .any(|font| grapheme.chars().all(|c| has_glyph(font, c)))
+    }
+
+    /// Position in the chain of the first font with a glyph for `c`, 0 being the
+    /// primary font, or `None` when no font has one.
+    pub fn font_index(&self, c: char) -> Option<usize> {
+        self.fonts.iter().position(|font| has_glyph(font, c))
+    }
+}
+
+fn has_glyph(font: &Font<'static>, c: char) -> bool {
+    // Glyph id 0 is the font's .notdef glyph, used when it has no coverage
+    font.glyph(c).id().0 != 0
+}
+
+/// Everything [`watermark`] needs besides the image and the text.
+pub struct WatermarkOptions {
+    pub font: Font<'static>,
+    /// Tried in order for the characters `font` has no glyph for
+    pub fallback_fonts: Vec<Font<'static>>,
+    pub style: WatermarkStyle,
+    pub layout: LayoutOptions,
+    /// Format of the output, or `None` to keep the format of the input
+    pub format: Option<ImageFormat>,
+    pub jpeg_quality: u8,
+    /// Splits the text into lines drawn on rows of their own, or `None` for one line
+    pub line_separator: Option<String>,
+}
+
+impl WatermarkOptions {
+    /// Options with the service's default style and layout.
+    pub fn new(font: Font<'static>) -> Self {
+        Self {
+            font,
+            fallback_fonts: Vec::new(),
+            style: WatermarkStyle::default(),
+            layout: LayoutOptions::default(),
+            format: None,
+            jpeg_quality: 90,
+            line_separator: Some("\n".to_string()),
+        }
+    }
+}
+
+/// Tiles `text` over the encoded image in `image_bytes` and returns the encoded result.
+/// An empty text leaves the pixels untouched.
+///
+/// ```
+/// use dynamic_minio_watermark::{watermark, WatermarkOptions};
+/// use image::{ImageOutputFormat, Rgba, RgbaImage};
+/// use rusttype::Font;
+/// use std::io::Cursor;
+///
+/// let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+/// let mut png = Cursor::new(Vec::new());
+/// RgbaImage::from_pixel(400, 300, Rgba([20, 40, 60, 255]))
+///     .write_to(&mut png, ImageOutputFormat::Png)
+///     .unwrap();
+///
+/// let marked = watermark(png.get_ref(), "ABCD1234", &WatermarkOptions::new(font)).unwrap();
+///
+/// let marked = image::load_from_memory(&marked).unwrap().into_rgba8();
+/// assert_eq!(marked.dimensions(), (400, 300));
+/// assert!(marked.pixels().any(|pixel| *pixel != Rgba([20, 40, 60, 255])));
+/// ```
+pub fn watermark(
+    image_bytes: &[u8],
+    text: &str,
+    options: &WatermarkOptions,
+) -> Result<Vec<u8>, String> {
+    let input_format = image::guess_format(image_bytes)
+        .map_err(|e| format!("Could not guess image format: {}", e))?;
+    let mut image = image::load_from_memory_with_format(image_bytes, input_format)
+        .map_err(|e| format!("Failed to decode image: {}", e))?
+        .into_rgba8();
+
+    let (chars, line_lengths) = visual_lines(text, options.line_separator.as_deref());
+    if !chars.is_empty() {
+        let (width, height) = image.dimensions();
+        let fonts = FontChain::new(options.font.clone(), options.fallback_fonts.clone());
+        let layout = WatermarkLayout::new(
+            width,
+            height,
+            &line_lengths,
+            glyph_extent(&chars, &fonts, &options.layout),
+            &options.style,
+            &options.layout,
+        );
+        let (layer, _) = build_watermark_layer(
+            width,
+            height,
+            &chars,
+            &fonts,
+            &layout,
+            &options.style,
+            &options.layout,
+        );
+        blend_layer(&mut image, &layer);
+    }
+
+    let format = options.format.unwrap_or(input_format);
+    let output_format = match format {
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(options.jpeg_quality),
+        other => ImageOutputFormat::from(other),
+    };
+    let mut output_buffer = Cursor::new(Vec::new());
+    image
+        .write_to(&mut output_buffer, output_format)
+        .map_err(|e| format!("Failed to encode image to {:?}: {}", format, e))?;
+    Ok(output_buffer.into_inner())
+}
+
+/// Grapheme clusters of `text` in display order. Runs are reordered by their bidi
+/// embedding levels (rule L2 of the Unicode Bidirectional Algorithm) as whole
+/// graphemes, because reordering single characters would move combining marks away
+/// from their base. Glyphs are not shaped, so Arabic letters keep their isolated forms.
+pub fn visual_graphemes(text: &str) -> Vec<String> {
+    let graphemes = text.grapheme_indices(true);
+    let bidi = BidiInfo::new(text, None);
+    if !bidi.has_rtl() {
+        return graphemes
+            .map(|(_, grapheme)| grapheme.to_string())
+            .collect();
+    }
+
+    let mut runs: Vec<(u8, &str)> = graphemes
+        .map(|(index, grapheme)| (bidi.levels[index].number(), grapheme))
+        .collect();
+    let highest = runs.iter().map(|&(level, _)| level).max().unwrap_or(0);
+    let lowest_odd = runs
+        .iter()
+        .map(|&(level, _)| level)
+        .filter(|level| level % 2 == 1)
+        .min()
+        .unwrap_or(highest + 1);
+    // From the highest level down to the lowest odd one, reverse every maximal run
+    // of graphemes at that level or above
+    for level in (lowest_odd..=highest).rev() {
+        let mut start = 0;
+        while start < runs.len() {
+            if runs[start].0 < level {
+                start += 1;
+                continue;
+            }
+            let end = runs[start..]
+                .iter()
+                .position(|&(run_level, _)| run_level < level)
+                .map_or(runs.len(), |offset| start + offset);
+            runs[start..end].reverse();
+            start = end;
+        }
+    }
+    runs.into_iter()
+        .map(|(_, grapheme)| grapheme.to_string())
+        .collect()
+}
+
+/// `visual_graphemes` of every line of `text`, split at `separator`, one line after the
+/// other, with the number of graphemes in each line. Empty lines are dropped.
+pub fn visual_lines(text: &str, separator: Option<&str>) -> (Vec<String>, Vec<usize>) {
+    let Some(separator) = separator.filter(|separator| !separator.is_empty()) else {
+        let chars = visual_graphemes(text);
+        let line_lengths = vec![chars.len()];
+        return (chars, line_lengths);
+    };
+
+    let mut chars = Vec::new();
+    let mut line_lengths = Vec::new();
+    for line in text.split(separator).filter(|line| !line.is_empty()) {
+        let graphemes = visual_graphemes(line);
+        line_lengths.push(graphemes.len());
+        chars.extend(graphemes);
+    }
+    (chars, line_lengths)
+}
+
+/// Merges an overlay layer of the same size onto the base image using alpha blending.
+/// Integer arithmetic with a fixed pixel order keeps the output byte-identical
+/// for identical inputs and config, independent of float rounding behavior.
+pub fn blend_layer(base_image: &mut RgbaImage, layer: &RgbaImage) {
+    // Walk both buffers as contiguous RGBA chunks instead of indexing pixel by pixel
+    for (base_pixel, layer_pixel) in base_image.chunks_exact_mut(4).zip(layer.chunks_exact(4)) {
+        blend_pixel(
+            base_pixel,
+            [layer_pixel[0], layer_pixel[1], layer_pixel[2]],
+            layer_pixel[3] as u32,
+        );
+    }
+}
+
+/// Blends `color` onto the base image with the alpha of each pixel taken from `mask`,
+/// which must be the same size. Equivalent to `blend_layer` with a layer of that one
+/// color, at a quarter of its memory.
+pub fn blend_mask(base_image: &mut RgbaImage, mask: &GrayImage, color: Rgba<u8>) {
+    let color = [color[0], color[1], color[2]];
+    for (base_pixel, &alpha) in base_image.chunks_exact_mut(4).zip(mask.iter()) {
+        blend_pixel(base_pixel, color, alpha as u32);
+    }
+}
+
+/// Which regions of an image are light, for watermark colors that contrast with what
+/// lies underneath. The image is divided into a grid of regions, and a region is light
+/// when the mean luminance of its pixels, weighted by their alpha, is at least half of
+/// the range. Regions without visible pixels count as dark.
+pub struct LightRegions {
+    width: u32,
+    height: u32,
+    columns: u32,
+    rows: u32,
+    light: Vec<bool>,
+}
+
+impl LightRegions {
+    /// Measures `image` in a grid of `columns` by `rows` regions, at most one pixel each.
+    pub fn new(image: &RgbaImage, columns: u32, rows: u32) -> Self {
+        let (width, height) = image.dimensions();
+        let mut regions = Self {
+            width: width.max(1),
+            height: height.max(1),
+            columns: columns.clamp(1, width.max(1)),
+            rows: rows.clamp(1, height.max(1)),
+            light: Vec::new(),
+        };
+
+        // Alpha-weighted luminance sum and alpha sum of every region
+        let mut sums = vec![(0u64, 0u64); (regions.columns * regions.rows) as usize];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let [r, g, b, a] = pixel.0;
+            let luminance = (299 * r as u64 + 587 * g as u64 + 114 * b as u64) / 1000;
+            let sum = &mut sums[regions.index(x, y)];
+            sum.0 += luminance * a as u64;
+            sum.1 += a as u64;
+        }
+        regions.light = sums
+            .into_iter()
+            .map(|(weighted, weight)| weight > 0 && weighted >= 128 * weight)
+            .collect();
+        regions
+    }
+
+    /// Whether the region holding pixel `(x, y)` is light.
+    pub fn is_light(&self, x: u32, y: u32) -> bool {
+        self.light[self.index(x, y)]
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        let column = x as u64 * self.columns as u64 / self.width as u64;
+        let row = y as u64 * self.rows as u64 / self.height as u64;
+        (row * self.columns as u64 + column) as usize
+    }
+}
+
+/// `blend_layer` with the colors of the layer inverted over the light regions, so white
+/// text on a black shadow turns into black text on a white one where the image is light.
+/// `regions` must be measured on `base_image` before anything is blended onto it.
+pub fn blend_layer_adaptive(base_image: &mut RgbaImage, layer: &RgbaImage, regions: &LightRegions) {
+    let width = base_image.width() as usize;
+    let pixels = base_image.chunks_exact_mut(4).zip(layer.chunks_exact(4));
+    for (index, (base_pixel, layer_pixel)) in pixels.enumerate() {
+        let mut color = [layer_pixel[0], layer_pixel[1], layer_pixel[2]];
+        if layer_pixel[3] != 0 && regions.is_light((index % width) as u32, (index / width) as u32) {
+            color = color.map(|channel| 255 - channel);
+        }
+        blend_pixel(base_pixel, color, layer_pixel[3] as u32);
+    }
+}
+
+/// `blend_mask` with `color` inverted over the light regions, like `blend_layer_adaptive`.
+pub fn blend_mask_adaptive(
+    base_image: &mut RgbaImage,
+    mask: &GrayImage,
+    color: Rgba<u8>,
+    regions: &LightRegions,
+) {
+    let width = base_image.width() as usize;
+    let color = [color[0], color[1], color[2]];
+    let inverted = color.map(|channel| 255 - channel);
+    for (index, (base_pixel, &alpha)) in base_image.chunks_exact_mut(4).zip(mask.iter()).enumerate()
+    {
+        if alpha == 0 {
+            continue;
+        }
+        let light = regions.is_light((index % width) as u32, (index / width) as u32);
+        blend_pixel(
+            base_pixel,
+            if light { inverted } else { color },
+            alpha as u32,
+        );
+    }
+}
+
+#[inline]
+fn blend_pixel(base_pixel: &mut [u8], layer_color: [u8; 3], layer_alpha: u32) {
+    // Fully transparent layer pixels leave the base unchanged, which is most of them
+    if layer_alpha == 0 {
+        return;
+    }
+    let base_alpha = base_pixel[3] as u32;
+
+    // Transparent base pixels take the watermark's coverage into their alpha
+    if base_alpha < 255 {
+        let base_weight = base_alpha * (255 - layer_alpha);
+        let out_alpha = layer_alpha * 255 + base_weight;
+        for i in 0..3 {
+            let weighted = layer_color[i] as u32 * layer_alpha * 255
+                + base_pixel[i] as u32 * base_weight
+                + out_alpha / 2;
+            if let Some(value) = weighted.checked_div(out_alpha) {
+                base_pixel[i] = value as u8;
+            }
+        }
+        base_pixel[3] = ((out_alpha + 127) / 255) as u8;
+        return;
+    }
+
+    for i in 0..3 {
+        base_pixel[i] = ((layer_color[i] as u32 * layer_alpha
+            + base_pixel[i] as u32 * (255 - layer_alpha)
+            + 127)
+            / 255) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graphemes_stay_whole() {
+        assert_eq!(visual_graphemes("A🇧🇷e\u{301}"), ["A", "🇧🇷", "e\u{301}"]);
+    }
+
+    #[test]
+    fn arabic_is_returned_in_visual_order() {
+        assert_eq!(visual_graphemes("سلام"), ["م", "ا", "ل", "س"]);
+        // Numbers after Arabic keep their digit order inside the right-to-left run
+        assert_eq!(
+            visual_graphemes("ID سلام 42"),
+            ["I", "D", " ", "4", "2", " ", "م", "ا", "ل", "س"]
+        );
+    }
+
+    #[test]
+    fn flags_in_right_to_left_text_are_not_split() {
+        // Regional indicators are left-to-right characters, so a leading flag makes the
+        // whole line left-to-right
+        assert_eq!(visual_graphemes("🇸🇦 سلام"), ["🇸🇦", " ", "م", "ا", "ل", "س"]);
+        assert_eq!(visual_graphemes("سلام 🇸🇦"), ["🇸🇦", " ", "م", "ا", "ل", "س"]);
+    }
+
+    #[test]
+    fn lines_are_split_and_counted_in_graphemes() {
+        let (chars, line_lengths) = visual_lines("AB🇧🇷\nسلام", Some("\n"));
+        assert_eq!(chars, ["A", "B", "🇧🇷", "م", "ا", "ل", "س"]);
+        assert_eq!(line_lengths, [3, 4]);
+    }
+
+    #[test]
+    fn empty_lines_and_separators_are_ignored() {
+        let (chars, line_lengths) = visual_lines("A\n\nB\n", Some("\n"));
+        assert_eq!(chars, ["A", "B"]);
+        assert_eq!(line_lengths, [1, 1]);
+        assert_eq!(visual_lines("A|B", None).1, [3]);
+        assert_eq!(visual_lines("A|B", Some("")).1, [3]);
+    }
+
+    /// Pixels covering every combination of transparent, translucent and opaque base and
+    /// layer alphas.
+    fn blend_fixture() -> (RgbaImage, RgbaImage) {
+        let alphas = [0u8, 1, 46, 128, 254, 255];
+        let size = alphas.len() as u32;
+        let base = RgbaImage::from_fn(size, size, |x, y| {
+            Rgba([200, (x * 40) as u8, (y * 40) as u8, alphas[y as usize]])
+        });
+        let layer =
+            RgbaImage::from_fn(size, size, |x, _| Rgba([255, 255, 255, alphas[x as usize]]));
+        (base, layer)
+    }
+
+    #[test]
+    fn blend_layer_output_is_pinned() {
+        let (mut base, layer) = blend_fixture();
+        blend_layer(&mut base, &layer);
+        let pixel = |x, y| base.get_pixel(x, y).0;
+        // Transparent layer pixels leave the base as it was
+        assert_eq!(pixel(0, 3), [200, 0, 120, 128]);
+        // Opaque base: plain alpha blend, rounded
+        assert_eq!(pixel(2, 5), [210, 112, 210, 255]);
+        assert_eq!(pixel(3, 5), [228, 188, 228, 255]);
+        // Opaque layer pixels replace the color and make the pixel opaque
+        assert_eq!(pixel(5, 0), [255, 255, 255, 255]);
+        assert_eq!(pixel(5, 3), [255, 255, 255, 255]);
+        // Translucent base: the coverage adds to the alpha
+        assert_eq!(pixel(3, 3), [237, 210, 210, 192]);
+        // Transparent base takes the layer pixel as is
+        assert_eq!(pixel(3, 0), [255, 255, 255, 128]);
+    }
+
+    /// The watermark blended once through `build_watermark_layer` and `blend_layer` and
+    /// once through `build_watermark_mask` and `blend_mask`, with the mask.
+    fn blend_both_ways(style: &WatermarkStyle) -> (RgbaImage, RgbaImage, GrayImage) {
+        let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+        let fonts = FontChain::new(font, Vec::new());
+        let chars: Vec<String> = "user@example.com".chars().map(String::from).collect();
+        let options = LayoutOptions {
+            shadow_enabled: false,
+            ..LayoutOptions::default()
+        };
+        let (width, height) = (400, 300);
+        let layout = WatermarkLayout::new(
+            width,
+            height,
+            &[chars.len()],
+            glyph_extent(&chars, &fonts, &options),
+            style,
+            &options,
+        );
+        let base = RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x % 256) as u8, (y % 256) as u8, 90, 255])
+        });
+
+        let (layer, _) =
+            build_watermark_layer(width, height, &chars, &fonts, &layout, style, &options);
+        let mut from_layer = base.clone();
+        blend_layer(&mut from_layer, &layer);
+        let (mask, _) =
+            layout::build_watermark_mask(width, height, &chars, &fonts, &layout, style, &options);
+        let mut from_mask = base;
+        blend_mask(&mut from_mask, &mask, style.color);
+        (from_layer, from_mask, mask)
+    }
+
+    #[test]
+    fn alpha_mask_matches_the_rgba_layer_within_edge_tolerance() {
+        for angle in [0.0, 30.0] {
+            let style = WatermarkStyle {
+                color: Rgba([255, 255, 255, 128]),
+                angle,
+                ..WatermarkStyle::default()
+            };
+            let (from_layer, from_mask, mask) = blend_both_ways(&style);
+            // Partly covered glyph edges of the RGBA layer are the color faded towards
+            // black, by at most a quarter of the watermark alpha at half coverage
+            let tolerance = style.color[3] / 4 + 1;
+            for ((layer_pixel, mask_pixel), coverage) in
+                from_layer.pixels().zip(from_mask.pixels()).zip(mask.iter())
+            {
+                let diff = (0..3)
+                    .map(|i| layer_pixel[i].abs_diff(mask_pixel[i]))
+                    .max()
+                    .unwrap();
+                // Fully covered and uncovered pixels only differ by rounding
+                let allowed = if *coverage == 0 || *coverage == style.color[3] {
+                    1
+                } else {
+                    tolerance
+                };
+                assert!(diff <= allowed, "angle {}: {} > {}", angle, diff, allowed);
+            }
+        }
+    }
+}
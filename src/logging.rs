@@ -0,0 +1,75 @@
+use crate::config::{self, LogFormat};
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, Key, Value, VisitSource};
+use log::warn;
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+
+/// Log target for slow request warnings, enabled independently of `LOG_LEVEL`.
+pub const SLOW_REQUEST_TARGET: &str = "slow_request";
+
+/// Log target for privileged admin actions, always logged at info level.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// Log target for `ACCESS_LOG_FORMAT` lines, always logged at info level.
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+/// Sets up `env_logger` with `LOG_LEVEL` and `LOG_FORMAT`. Must run before `CONFIG`
+/// is first read so its warnings are not lost.
+pub fn init() {
+    let log_format = config::log_format();
+
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::new().default_filter_or(config::log_level()),
+    );
+    // Slow request warnings bypass the global level so outliers stay visible
+    builder.filter_module(SLOW_REQUEST_TARGET, log::LevelFilter::Warn);
+    builder.filter_module(AUDIT_TARGET, log::LevelFilter::Info);
+    builder.filter_module(ACCESS_LOG_TARGET, log::LevelFilter::Info);
+    if log_format == Ok(LogFormat::Json) {
+        builder.format(|buf, record| {
+            let mut entry = Map::new();
+            entry.insert(
+                "timestamp".to_string(),
+                Utc::now()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+            );
+            entry.insert("level".to_string(), record.level().as_str().into());
+            entry.insert("target".to_string(), record.target().into());
+            entry.insert("message".to_string(), record.args().to_string().into());
+            let _ = record.key_values().visit(&mut JsonFields(&mut entry));
+            writeln!(buf, "{}", JsonValue::Object(entry))
+        });
+    }
+    builder.init();
+
+    if let Err(value) = log_format {
+        warn!(
+            "Invalid value for LOG_FORMAT '{}', using default: text",
+            value
+        );
+    }
+}
+
+/// Copies the structured fields of a log record into the JSON entry, keeping numbers
+/// and booleans typed so they can be queried as such.
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(number) = value.to_f64() {
+            number.into()
+        } else if let Some(flag) = value.to_bool() {
+            flag.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
@@ -1,24 +1,138 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_cors::Cors;
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{Condition, Logger};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use bytes::Bytes;
+use chrono::Utc;
 use dotenv::dotenv;
+use dynamic_minio_watermark::layout::{
+    aspect_adaptive_spacing, build_watermark_layer, build_watermark_mask, finish_layer,
+    glyph_extent, is_single_color, layer_canvas_size, WatermarkLayout,
+};
+use dynamic_minio_watermark::{
+    blend_layer, blend_layer_adaptive, blend_mask, blend_mask_adaptive, visual_graphemes,
+    visual_lines, FontChain, LightRegions, WatermarkStyle,
+};
+use hmac::{Hmac, Mac};
 use image::io::Reader as ImageReader;
-use image::{ImageOutputFormat, RgbaImage};
-use imageproc::drawing::draw_text_mut;
+use image::{imageops, DynamicImage, GrayImage, ImageFormat, ImageOutputFormat, Rgba, RgbaImage};
+use imageproc::drawing::{draw_text_mut, text_size};
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::geometric_transformations::rotate_about_center;
 use lazy_static::lazy_static;
-use log::{error, info, warn};
-use minio::s3::args::GetObjectArgs;
+use log::{debug, error, info, warn};
+use memmap2::Mmap;
+use minio::s3::args::{BucketExistsArgs, GetObjectArgs, StatObjectArgs};
 use minio::s3::client::Client as MinioClient;
 use minio::s3::creds::StaticProvider;
+use minio::s3::error::Error as S3Error;
+use minio::s3::http::BaseUrl;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use rusttype::{Font, Scale};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::collections::HashMap;
 use std::io::Cursor;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit};
 use url::Url;
 
+mod cache;
 mod config;
-use config::CONFIG;
+mod logging;
+mod metrics;
+#[cfg(feature = "plugins")]
+mod plugin;
+mod tenant;
+mod text_provider;
+
+#[cfg(test)]
+mod minio_e2e;
+
+use cache::{CacheKey, RenderCache};
+use config::{
+    AccessLogFormat, AdaptiveColor, ErrorFormat, FontPoisonPolicy, OutputFormat, TinyImageMode,
+    WatermarkMode, WatermarkOutput, CONFIG,
+};
+use logging::{ACCESS_LOG_TARGET, AUDIT_TARGET, SLOW_REQUEST_TARGET};
+use metrics::Metrics;
+#[cfg(feature = "plugins")]
+use plugin::Plugin;
+use tenant::TenantStyles;
+use text_provider::{TextRequest, WatermarkTextProvider};
+
+/// Input formats accepted without `DECODE_BRUTE_FORCE`, besides SVG with the `svg` feature.
+const SUPPORTED_INPUT_FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::WebP,
+    ImageFormat::Gif,
+];
+
+/// Prefix of `process_image` errors for inputs that are not a supported image, which
+/// are answered with 415 instead of 500.
+const UNSUPPORTED_MEDIA_ERROR: &str = "Unsupported media type";
+
+/// Prefix of `download_image` errors for downloads that exceeded `MINIO_TIMEOUT_SECS`
+/// on every attempt, which are answered with 504.
+const DOWNLOAD_TIMEOUT_ERROR: &str = "Timed out downloading object from MinIO";
+
+/// Prefix of `download_image` errors for missing objects or buckets, which are
+/// answered with 404.
+const OBJECT_NOT_FOUND_ERROR: &str = "Object not found in MinIO";
+
+/// Prefix of download errors for objects larger than `MAX_OBJECT_BYTES`.
+const OBJECT_TOO_LARGE_ERROR: &str = "Object exceeds MAX_OBJECT_BYTES";
+
+/// Prefix of `render_with_limit` errors for requests turned away because
+/// `RENDER_QUEUE_LIMIT` requests already wait for a render slot, answered with 503.
+const RENDER_QUEUE_FULL_ERROR: &str = "Too many requests waiting to render";
+
+/// `Retry-After` seconds of the 503 answers sent when the render queue is full.
+const RENDER_RETRY_AFTER_SECS: u64 = 1;
+
+/// Delay before the first download retry, doubled for every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// Longest wait between two download attempts.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Largest download buffer allocated up front from the `Content-Length` header, so a
+/// wrong header cannot reserve more memory than that. Larger bodies grow the buffer.
+const MAX_PREALLOCATED_BODY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Header carrying the `ADMIN_TOKEN` for privileged request options.
+const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+/// Bytes escaped in the bucket and object of `/wm-svg` links; `/` separates object
+/// key segments and is kept.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Largest request body accepted by `POST /watermark`.
+const MAX_UPLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+/// Largest object-lambda JSON payload accepted by `POST /`.
+const MAX_LAMBDA_PAYLOAD_BYTES: usize = 256 * 1024;
+
+/// Local font tried when a remote `FONT_PATH` could not be downloaded.
+const LOCAL_FONT_FALLBACK: &str = "assets/DejaVuSans.ttf";
+
+/// Font bytes downloaded at startup when `FONT_PATH` is an `s3://` or `http(s)://` URL,
+/// read by `load_font` in place of a local file.
+static REMOTE_FONT_DATA: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Plugin compiled at startup from `PLUGIN_PATH`, applied by `add_watermark`.
+#[cfg(feature = "plugins")]
+static PLUGIN: OnceLock<Plugin> = OnceLock::new();
 
 lazy_static! {
     static ref WATERMARK_FONT: Arc<RwLock<Option<Font<'static>>>> = {
@@ -31,26 +145,145 @@ lazy_static! {
             }
         }
     };
+    static ref FALLBACK_FONTS: Arc<Vec<Font<'static>>> = Arc::new(load_fallback_fonts());
+}
+
+/// The `MAX_CONCURRENT_RENDERS` slots, with the number of requests waiting for one.
+struct RenderSlots {
+    slots: Arc<Semaphore>,
+    waiting: AtomicUsize,
 }
 
 struct AppState {
     minio_client: MinioClient,
     font: Arc<RwLock<Option<Font<'static>>>>,
+    /// `FONT_FALLBACK_PATHS` fonts, in order, for the glyphs `font` lacks. Loaded once
+    /// at startup and not affected by font reloads.
+    fallback_fonts: Arc<Vec<Font<'static>>>,
+    /// `WATERMARK_IMAGE_PATH` logo, tiled in place of the text when set. Shared with
+    /// the blocking threads rendering it.
+    logo: Option<Arc<RgbaImage>>,
+    cache: Option<RenderCache>,
+    /// `FALLBACK_IMAGE_PATH` contents, loaded at startup when `SERVE_FALLBACK` is enabled.
+    fallback_image: Option<Bytes>,
+    /// Bounds in-flight MinIO downloads when `MAX_CONCURRENT_DOWNLOADS` is set.
+    downloads: Option<Semaphore>,
+    /// Bounds running renders when `MAX_CONCURRENT_RENDERS` is set.
+    renders: Option<RenderSlots>,
+    metrics: Metrics,
+    /// `WATERMARK_TEXT_SOURCES` providers, in precedence order.
+    text_providers: Vec<Box<dyn WatermarkTextProvider>>,
+    /// `TENANT_CONFIG_PATH` per-bucket styles, reloaded on SIGHUP.
+    tenants: Option<TenantStyles>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ObjectContext {
     #[serde(rename = "inputS3Url")]
     input_s3_url: String,
-    #[serde(rename = "outputRoute")]
+    #[serde(rename = "outputRoute", default)]
     output_route: String,
-    #[serde(rename = "outputToken")]
+    #[serde(rename = "outputToken", default)]
     output_token: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct UserRequest {
+    #[serde(default)]
     url: String,
+    #[serde(default)]
+    headers: Option<HashMap<String, serde_json::Value>>,
+    /// Takes the place of the `WATERMARK_PARAM` parameter in `url`
+    #[serde(default)]
+    watermark_text: Option<String>,
+    /// Opacity of the watermark color from 0 to 1, replacing its alpha
+    #[serde(default)]
+    opacity: Option<f32>,
+    /// Counterclockwise rotation of the tiles in degrees
+    #[serde(default)]
+    angle: Option<f32>,
+}
+
+impl UserRequest {
+    /// The requested style changes, with `angle` falling back to `query_angle` from
+    /// `url`. Outside admin requests the opacity is raised to `MIN_WATERMARK_ALPHA`, so
+    /// callers cannot make the watermark invisible.
+    fn style_overrides(
+        &self,
+        admin: bool,
+        query_angle: Option<f32>,
+    ) -> Result<StyleOverrides, String> {
+        let alpha = match self.opacity {
+            Some(opacity) if !(0.0..=1.0).contains(&opacity) => {
+                return Err(format!(
+                    "Invalid opacity {}, expected a value from 0 to 1",
+                    opacity
+                ))
+            }
+            opacity => opacity.map(|opacity| {
+                let alpha = (opacity * 255.0).round() as u8;
+                if admin {
+                    alpha
+                } else {
+                    alpha.max(CONFIG.min_watermark_alpha)
+                }
+            }),
+        };
+        Ok(StyleOverrides {
+            alpha,
+            ..StyleOverrides::with_angle(self.angle.or(query_angle))?
+        })
+    }
+}
+
+/// The `angle` query parameter, in degrees.
+fn query_angle(params: &HashMap<String, String>) -> Result<Option<f32>, String> {
+    params
+        .get("angle")
+        .map(|angle| {
+            angle
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format!("Invalid angle {}", angle))
+        })
+        .transpose()
+}
+
+/// Per-request changes to the bucket's watermark style, from the structured fields
+/// of an object-lambda `userRequest`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+struct StyleOverrides {
+    alpha: Option<u8>,
+    /// The angle as `f32` bits, so the overrides can be part of a `CacheKey`
+    angle_bits: Option<u32>,
+}
+
+impl StyleOverrides {
+    fn with_angle(angle: Option<f32>) -> Result<Self, String> {
+        let angle = match angle {
+            Some(angle) if !angle.is_finite() => {
+                return Err(format!("Invalid angle {}", angle));
+            }
+            angle => angle.map(|angle| angle % 360.0),
+        };
+        Ok(Self {
+            alpha: None,
+            angle_bits: angle.map(f32::to_bits),
+        })
+    }
+
+    /// Requested angles were not tuned with the spacing ratios, so their tiles are
+    /// spaced by the measured glyphs to keep them from overlapping.
+    fn apply(&self, mut style: WatermarkStyle) -> WatermarkStyle {
+        if let Some(alpha) = self.alpha {
+            style.color[3] = alpha;
+        }
+        if let Some(angle_bits) = self.angle_bits {
+            style.angle = f32::from_bits(angle_bits);
+            style.measured_spacing = true;
+        }
+        style
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,6 +292,41 @@ struct GenerateRequest {
     get_object_context: ObjectContext,
     #[serde(rename = "userRequest")]
     user_request: UserRequest,
+    #[serde(rename = "userIdentity", default)]
+    user_identity: Option<UserIdentity>,
+}
+
+/// The identity that made the original S3 request, as sent by the object-lambda caller.
+#[derive(Debug, Default, Deserialize)]
+struct UserIdentity {
+    #[serde(rename = "principalId", default)]
+    principal_id: String,
+    #[serde(default)]
+    arn: String,
+}
+
+impl GenerateRequest {
+    /// Who the result is served to, for audit and counter logs: the principal or ARN of
+    /// the `userIdentity`, or else the address of the client that called the service.
+    fn requester(&self, req: &HttpRequest) -> String {
+        self.user_identity
+            .iter()
+            .flat_map(|identity| [&identity.principal_id, &identity.arn])
+            .find(|id| !id.is_empty())
+            .cloned()
+            .unwrap_or_else(|| {
+                req.connection_info()
+                    .realip_remote_addr()
+                    .unwrap_or("unknown")
+                    .to_string()
+            })
+    }
+}
+
+/// `url` without its query string and fragment, which may hold presigned credentials
+/// and signatures that must not reach the logs.
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
 }
 
 #[derive(Debug, Serialize)]
@@ -67,11 +335,109 @@ struct GenerateResponse {
     message: String,
 }
 
+#[derive(Debug, Clone, Default, Serialize)]
+struct RenderStats {
+    input_format: Option<String>,
+    input_width: u32,
+    input_height: u32,
+    output_format: String,
+    output_quality: Option<u8>,
+    glyph_draws: usize,
+    layout_cols: usize,
+    layout_rows: usize,
+    layout_scale: f32,
+    layer_build_ms: f64,
+    blend_ms: f64,
+    encode_ms: f64,
+    output_bytes: usize,
+    /// Set when `FALLBACK_IMAGE_PATH` was served in place of the requested object
+    fallback: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DebugStatsResponse<'a> {
+    download_ms: f64,
+    process_ms: f64,
+    #[serde(flatten)]
+    render: &'a RenderStats,
+}
+
+/// `/preview` answer: the tiled layout computed for the given dimensions and text.
+#[derive(Debug, Serialize)]
+struct PreviewResponse {
+    width: u32,
+    height: u32,
+    scale: PreviewScale,
+    chars_per_row: usize,
+    rows: usize,
+    char_spacing_x: f32,
+    char_spacing_y: f32,
+    /// Glyphs drawn, not counting shadows. With `fits` false it reflects `TINY_IMAGE_MODE`.
+    glyph_count: usize,
+    /// Whether a single glyph fits in the image
+    fits: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct PreviewScale {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Clone)]
+struct RenderedImage {
+    body: Bytes,
+    content_type: &'static str,
+    svg_overlay: Option<String>,
+    /// Placeholder for the `X-Blurhash` header when `PREVIEW_PLACEHOLDER` is enabled
+    blurhash: Option<String>,
+    stats: RenderStats,
+}
+
+impl RenderedImage {
+    /// Approximate memory held by this result, counted against `CACHE_MAX_BYTES`.
+    fn cached_size(&self) -> usize {
+        self.body.len()
+            + self.svg_overlay.as_ref().map_or(0, String::len)
+            + self.blurhash.as_ref().map_or(0, String::len)
+    }
+}
+
 fn load_font() -> Result<Font<'static>, String> {
     let font_path = &CONFIG.font_path;
 
     info!("Attempting to load font from: {}", font_path);
 
+    if is_remote_font_path(font_path) {
+        let font_data = match REMOTE_FONT_DATA.get() {
+            Some(data) => data.clone(),
+            None => match std::fs::read(LOCAL_FONT_FALLBACK) {
+                Ok(data) => {
+                    warn!(
+                        "Font could not be downloaded from {}. Using local font {} as fallback",
+                        font_path, LOCAL_FONT_FALLBACK
+                    );
+                    data
+                }
+                Err(e) => embedded_font_fallback(format!(
+                    "Font could not be downloaded from {} and local font {} failed to load: {}",
+                    font_path, LOCAL_FONT_FALLBACK, e
+                ))?,
+            },
+        };
+        return parse_font(font_data);
+    }
+
+    if CONFIG.font_mmap {
+        match mapped_font(font_path) {
+            Ok(font) => {
+                info!("Successfully memory-mapped font from {}", font_path);
+                return Ok(font);
+            }
+            Err(e) => warn!("{}. Reading the font into memory instead", e),
+        }
+    }
+
     let font_data = match std::fs::read(font_path) {
         Ok(data) => {
             info!("Successfully loaded font from {}", font_path);
@@ -101,47 +467,147 @@ fn load_font() -> Result<Font<'static>, String> {
                         alt_path, e2
                     );
 
-                    #[cfg(feature = "embedded_font")]
-                    {
-                        info!("Using embedded font as fallback");
-                        include_bytes!("../assets/DejaVuSans.ttf").to_vec()
-                    }
-
-                    #[cfg(not(feature = "embedded_font"))]
-                    {
-                        error!("Embedded font feature not enabled. Cannot load font.");
-                        return Err(format!(
-                            "Failed to load font file: {} (also tried {}). Embedded font not available.",
-                            e1, e2
-                        ));
-                    }
+                    embedded_font_fallback(format!(
+                        "Failed to load font file: {} (also tried {})",
+                        e1, e2
+                    ))?
                 }
             }
         }
     };
 
-    let static_font_data: &'static [u8] = Box::leak(font_data.into_boxed_slice());
-    Font::try_from_bytes(static_font_data).ok_or_else(|| "Failed to parse font data".to_string())
+    parse_font(font_data)
+}
+
+/// Builds the font over a read-only memory map of `path` for `FONT_MMAP`, so only the
+/// pages that are actually used stay resident. The file is mapped once and the mapping
+/// is kept for the lifetime of the process, so reloads reuse it.
+fn mapped_font(path: &str) -> Result<Font<'static>, String> {
+    static FONT_MMAP: OnceLock<Mmap> = OnceLock::new();
+
+    let mmap = match FONT_MMAP.get() {
+        Some(mmap) => mmap,
+        None => {
+            let file = std::fs::File::open(path)
+                .map_err(|e| format!("Failed to open font file {}: {}", path, e))?;
+            // SAFETY: the mapping is read-only, and FONT_MMAP documents that the font
+            // file must not be modified or truncated while the service runs
+            let mmap = unsafe { Mmap::map(&file) }
+                .map_err(|e| format!("Failed to memory-map font file {}: {}", path, e))?;
+            FONT_MMAP.get_or_init(|| mmap)
+        }
+    };
+    Font::try_from_bytes(&mmap[..]).ok_or_else(|| "Failed to parse font data".to_string())
+}
+
+/// Parses font data into a font that owns it behind an `Arc`, so clones are cheap and
+/// the bytes are freed once the last clone is dropped, e.g. after a font reload.
+fn parse_font(font_data: Vec<u8>) -> Result<Font<'static>, String> {
+    Font::try_from_vec(font_data).ok_or_else(|| "Failed to parse font data".to_string())
+}
+
+/// Loads the `FONT_FALLBACK_PATHS` fonts in order, skipping the ones that cannot be
+/// read or parsed so a bad entry only costs its own glyphs.
+fn load_fallback_fonts() -> Vec<Font<'static>> {
+    let mut fonts = Vec::new();
+    for path in &CONFIG.font_fallback_paths {
+        match std::fs::read(path)
+            .map_err(|e| format!("Failed to read font file: {}", e))
+            .and_then(parse_font)
+        {
+            Ok(font) => {
+                info!("Loaded fallback font from {}", path);
+                fonts.push(font);
+            }
+            Err(e) => error!("Skipping fallback font {}: {}", path, e),
+        }
+    }
+    fonts
+}
+
+/// Returns the embedded font when the `embedded_font` feature is enabled, or fails
+/// with `reason` otherwise.
+fn embedded_font_fallback(reason: String) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "embedded_font")]
+    {
+        warn!("{}. Using embedded font as fallback", reason);
+        Ok(include_bytes!("../assets/DejaVuSans.ttf").to_vec())
+    }
+
+    #[cfg(not(feature = "embedded_font"))]
+    {
+        error!("Embedded font feature not enabled. Cannot load font.");
+        Err(format!("{}. Embedded font not available.", reason))
+    }
+}
+
+/// Whether `FONT_PATH` points at an `s3://` or `http(s)://` location rather than a file.
+fn is_remote_font_path(font_path: &str) -> bool {
+    ["s3://", "http://", "https://"]
+        .iter()
+        .any(|scheme| font_path.starts_with(scheme))
+}
+
+/// Downloads the font from a remote `FONT_PATH`: `s3://` locations through the MinIO
+/// client and `http(s)://` URLs with a plain GET.
+async fn fetch_remote_font(minio_client: &MinioClient, font_path: &str) -> Result<Vec<u8>, String> {
+    if font_path.starts_with("s3://") {
+        let (bucket, object) = parse_s3_url(font_path)?;
+        return download_image(minio_client, &bucket, &object)
+            .await
+            .map(|bytes| bytes.to_vec());
+    }
+
+    let response = reqwest::get(font_path)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to download font: {}", e))?;
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to read font bytes: {}", e))?;
+    Ok(bytes.to_vec())
 }
 
 async fn generate(
+    req: HttpRequest,
     payload: web::Json<GenerateRequest>,
     app_state: web::Data<AppState>,
 ) -> impl Responder {
-    let start_time = Instant::now();
     info!(
+        input_s3_url = without_query(&payload.get_object_context.input_s3_url);
         "Received watermarking request for: {}",
-        payload.get_object_context.input_s3_url
+        without_query(&payload.get_object_context.input_s3_url)
     );
+    app_state.metrics.record_request();
 
-    let url_params = extract_url_params(&payload.user_request.url);
-    let watermark_text = url_params
-        .get("usercode")
-        .cloned()
-        .unwrap_or_else(|| "WATERMARK".to_string());
+    let mut url_params = extract_url_params(&payload.user_request.url);
+    if let Some(text) = &payload.user_request.watermark_text {
+        url_params.insert(CONFIG.watermark_param.clone(), text.clone());
+    }
+    let admin = is_admin_request(&req, payload.user_request.headers.as_ref());
+    let style_overrides = match query_angle(&url_params)
+        .and_then(|angle| payload.user_request.style_overrides(admin, angle))
+    {
+        Ok(style_overrides) => style_overrides,
+        Err(e) => {
+            error!("Rejected request: {}", e);
+            app_state.metrics.record_error("invalid_request");
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+    };
+    let debug_stats = wants_debug_stats(&url_params, &req, payload.user_request.headers.as_ref());
+    let debug_layout = wants_debug_layout(&url_params, &req, payload.user_request.headers.as_ref());
 
-    if watermark_text.is_empty() {
-        warn!("Received request with empty watermark text parameter.");
+    let output_route = &payload.get_object_context.output_route;
+    let output_token = &payload.get_object_context.output_token;
+    if CONFIG.require_output_route && (output_route.is_empty() || output_token.is_empty()) {
+        error!("Rejected request without outputRoute or outputToken");
+        app_state.metrics.record_error("invalid_request");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing outputRoute or outputToken".to_string(),
+        );
     }
 
     let input_s3_url = &payload.get_object_context.input_s3_url;
@@ -149,254 +615,3198 @@ async fn generate(
         Ok((bucket, object)) => (bucket, object),
         Err(e) => {
             error!("Failed to parse S3 URL: {}", e);
-            return HttpResponse::BadRequest().json(GenerateResponse {
-                status: "error".to_string(),
-                message: format!("Invalid input S3 URL format: {}", e),
-            });
+            app_state.metrics.record_error("invalid_request");
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid input S3 URL format: {}", e),
+            );
         }
     };
-
-    let image_bytes =
-        match download_image(&app_state.minio_client, &bucket_name, &object_name).await {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                error!("Failed to download image from MinIO: {}", e);
-                return HttpResponse::InternalServerError().json(GenerateResponse {
-                    status: "error".to_string(),
-                    message: format!("Failed to download image from MinIO: {}", e),
-                });
-            }
+    if !bucket_allowed(&bucket_name) {
+        app_state.metrics.record_error("forbidden");
+        return bucket_forbidden_response(&bucket_name);
+    }
+    let user_headers = payload.user_request.headers.as_ref();
+    let requester = payload.requester(&req);
+    let webp_allowed = webp_allowed(&req, user_headers);
+    let mut watermark_hash = None;
+    let passthrough = wants_passthrough(&url_params, &req, user_headers);
+    let result = if passthrough || wants_original(&url_params, &req, user_headers) {
+        info!(
+            target: AUDIT_TARGET,
+            "Serving original of {}/{} without watermark to admin requester {}",
+            bucket_name, object_name, requester
+        );
+        download_original(
+            &app_state,
+            &bucket_name,
+            &object_name,
+            passthrough,
+            webp_allowed,
+        )
+        .await
+    } else {
+        let watermark_text = match text_provider::resolve(
+            &app_state.text_providers,
+            &TextRequest {
+                params: &url_params,
+                req: &req,
+                user_headers,
+                bucket: &bucket_name,
+                object: &object_name,
+                requester: &requester,
+            },
+        ) {
+            Ok(watermark_text) => watermark_text,
+            Err(e) => return invalid_token_response(&app_state, e),
         };
-    let download_duration = start_time.elapsed();
-
-    match add_watermark(image_bytes, &watermark_text, &app_state.font).await {
-        Ok(watermarked_image) => {
-            let process_duration = start_time.elapsed() - download_duration;
-            info!(
-                "Successfully processed image with watermark '{}'. Download: {:?}, Process: {:?}",
-                watermark_text, download_duration, process_duration
-            );
+        watermark_hash = usercode_hash(&watermark_text.text);
+        fetch_and_render(
+            &app_state,
+            &bucket_name,
+            &object_name,
+            &watermark_text.text,
+            watermark_text.cacheable,
+            &style_overrides,
+            webp_allowed,
+        )
+        .await
+    };
+    let (rendered, download_duration, process_duration) = match result {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    log_slow_request(
+        without_query(input_s3_url),
+        download_duration,
+        process_duration,
+    );
 
-            HttpResponse::Ok()
-                .content_type("image/jpeg")
-                .append_header((
-                    "x-amz-request-route",
-                    payload.get_object_context.output_route.clone(),
-                ))
-                .append_header((
-                    "x-amz-request-token",
-                    payload.get_object_context.output_token.clone(),
-                ))
-                .body(watermarked_image)
-        }
-        Err(e) => {
-            error!("Failed to add watermark: {}", e);
-            HttpResponse::InternalServerError().json(GenerateResponse {
-                status: "error".to_string(),
-                message: format!("Failed to add watermark: {}", e),
-            })
-        }
+    if debug_stats {
+        return debug_stats_response(&rendered, download_duration, process_duration);
     }
-}
 
-fn parse_s3_url(s3_url: &str) -> Result<(String, String), String> {
-    if s3_url.starts_with("s3://") {
-        let parsed_url = Url::parse(s3_url).map_err(|_| "Failed to parse S3 URL".to_string())?;
-        if parsed_url.scheme() != "s3" {
-            return Err("URL scheme is not 's3'".to_string());
-        }
-        if let Some(host) = parsed_url.host_str() {
-            let path_segments: Vec<&str> = parsed_url
-                .path_segments()
-                .map(|c| c.collect())
-                .unwrap_or_default();
-            if !host.is_empty() && !path_segments.is_empty() {
-                Ok((host.to_string(), path_segments.join("/")))
-            } else {
-                Err("Invalid S3 URL format: missing bucket or object key".to_string())
-            }
-        } else {
-            Err("Invalid S3 URL format: missing bucket".to_string())
-        }
-    } else {
-        // Tentativa de parsing para URLs HTTP que podem conter bucket e objeto no path
-        // Exemplo: http://minio.example.com/mybucket/myimage.jpg?param=value
-        if let Ok(parsed_url) = Url::parse(s3_url) {
-            let segments: Vec<&str> = parsed_url
-                .path_segments()
-                .map(|c| c.collect())
-                .unwrap_or_default();
-            if segments.len() >= 2 {
-                let bucket = segments[0].to_string();
-                let object = segments[1..].join("/");
-                if !bucket.is_empty() && !object.is_empty() {
-                    return Ok((bucket, object));
-                }
-            }
-        }
-        Err("S3 URL does not start with 's3://' and could not be parsed as HTTP URL with bucket/object".to_string())
-    }
+    with_timing_headers(
+        image_response(
+            &rendered,
+            debug_layout,
+            Some((output_route.as_str(), output_token.as_str())),
+            watermark_hash.as_deref(),
+            // A relative link would resolve against the S3 endpoint the client called
+            None,
+        ),
+        Some(download_duration),
+        Some(process_duration),
+    )
 }
 
-fn extract_url_params(url: &str) -> HashMap<String, String> {
-    let mut params = HashMap::new();
-    if let Some(query_str) = url.split('?').nth(1) {
-        for pair in query_str.split('&') {
-            if let Some((key, value)) = pair.split_once('=') {
-                params.insert(key.to_string(), value.to_string());
-            }
-        }
-    }
-    params
+/// Accepts the object-lambda payload as JSON whatever content type it is declared
+/// with, e.g. `application/x-amz-json-1.1` or none at all, and reports malformed
+/// bodies in the usual error format. Bodies over `MAX_LAMBDA_PAYLOAD_BYTES` are
+/// answered with 413.
+fn lambda_json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(MAX_LAMBDA_PAYLOAD_BYTES)
+        .content_type_required(false)
+        .content_type(|_| true)
+        .error_handler(|err, _req| {
+            let status = match err {
+                JsonPayloadError::OverflowKnownLength { .. }
+                | JsonPayloadError::Overflow { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let message = format!("Invalid request body: {}", err);
+            error!("{}", message);
+            actix_web::error::InternalError::from_response(err, error_response(status, message))
+                .into()
+        })
 }
 
-async fn download_image(
-    client: &MinioClient,
-    bucket_name: &str,
-    object_name: &str,
-) -> Result<Bytes, String> {
+async fn generate_from_path(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let (bucket_name, object_name) = path.into_inner();
     info!(
-        "Downloading object '{}' from bucket '{}' in MinIO",
-        object_name, bucket_name
+        bucket = bucket_name,
+        object = object_name;
+        "Received path-style watermarking request for: {}/{}",
+        bucket_name, object_name
     );
+    app_state.metrics.record_request();
 
-    let args_result = GetObjectArgs::new(bucket_name, object_name);
+    let requester = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    let debug_stats = wants_debug_stats(&query, &req, None);
+    let debug_layout = wants_debug_layout(&query, &req, None);
 
-    let args = match args_result {
-        Ok(args) => args,
-        Err(e) => return Err(format!("Failed to create GetObjectArgs: {}", e)),
+    if bucket_name.is_empty() || object_name.is_empty() {
+        app_state.metrics.record_error("invalid_request");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing bucket or object key in path".to_string(),
+        );
+    }
+    if !bucket_allowed(&bucket_name) {
+        app_state.metrics.record_error("forbidden");
+        return bucket_forbidden_response(&bucket_name);
+    }
+    let style_overrides = match query_angle(&query).and_then(StyleOverrides::with_angle) {
+        Ok(style_overrides) => style_overrides,
+        Err(e) => {
+            error!("Rejected request: {}", e);
+            app_state.metrics.record_error("invalid_request");
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
     };
 
-    let response = client
-        .get_object(&args)
+    let webp_allowed = webp_allowed(&req, None);
+    let mut watermark_hash = None;
+    let passthrough = wants_passthrough(&query, &req, None);
+    let result = if passthrough || wants_original(&query, &req, None) {
+        info!(
+            target: AUDIT_TARGET,
+            "Serving original of {}/{} without watermark to admin requester {}",
+            bucket_name, object_name, requester
+        );
+        download_original(
+            &app_state,
+            &bucket_name,
+            &object_name,
+            passthrough,
+            webp_allowed,
+        )
         .await
-        .map_err(|e| format!("Failed to get object from MinIO: {}", e))?;
-
-    let bytes = response
-        .bytes()
+    } else {
+        let watermark_text = match text_provider::resolve(
+            &app_state.text_providers,
+            &TextRequest {
+                params: &query,
+                req: &req,
+                user_headers: None,
+                bucket: &bucket_name,
+                object: &object_name,
+                requester: &requester,
+            },
+        ) {
+            Ok(watermark_text) => watermark_text,
+            Err(e) => return invalid_token_response(&app_state, e),
+        };
+        watermark_hash = usercode_hash(&watermark_text.text);
+        fetch_and_render(
+            &app_state,
+            &bucket_name,
+            &object_name,
+            &watermark_text.text,
+            watermark_text.cacheable,
+            &style_overrides,
+            webp_allowed,
+        )
         .await
-        .map_err(|e| format!("Failed to read object bytes from MinIO: {}", e))?;
+    };
+    let (rendered, download_duration, process_duration) = match result {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+    log_slow_request(
+        &format!("{}/{}", bucket_name, object_name),
+        download_duration,
+        process_duration,
+    );
+
+    if debug_stats {
+        return debug_stats_response(&rendered, download_duration, process_duration);
+    }
 
-    Ok(bytes)
+    with_timing_headers(
+        image_response(
+            &rendered,
+            debug_layout,
+            None,
+            watermark_hash.as_deref(),
+            Some(svg_overlay_link(&bucket_name, &object_name, &query)),
+        ),
+        Some(download_duration),
+        Some(process_duration),
+    )
 }
 
-async fn add_watermark(
-    image_bytes: Bytes,
-    watermark_text: &str,
-    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
-) -> Result<Vec<u8>, String> {
-    let start_time = Instant::now();
+/// Serves the SVG overlay of an object for `WATERMARK_OUTPUT=svg-overlay`, linked from
+/// the `X-Watermark-SVG` header of the image response. The text is resolved from the
+/// same query and headers as the image, so frontends must send both alike. Answers 204
+/// when there is no overlay to draw, e.g. for an empty watermark text.
+async fn svg_overlay_from_path(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let (bucket_name, object_name) = path.into_inner();
+    info!(
+        bucket = bucket_name,
+        object = object_name;
+        "Received SVG overlay request for: {}/{}",
+        bucket_name, object_name
+    );
+    app_state.metrics.record_request();
 
-    if watermark_text.is_empty() {
-        warn!("Watermark text is empty, returning original image bytes.");
-        return Ok(image_bytes.to_vec());
+    if CONFIG.watermark_output != WatermarkOutput::SvgOverlay {
+        app_state.metrics.record_error("invalid_request");
+        return error_response(
+            StatusCode::NOT_FOUND,
+            "SVG overlays are only served with WATERMARK_OUTPUT=svg-overlay".to_string(),
+        );
     }
+    if bucket_name.is_empty() || object_name.is_empty() {
+        app_state.metrics.record_error("invalid_request");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing bucket or object key in path".to_string(),
+        );
+    }
+    if !bucket_allowed(&bucket_name) {
+        app_state.metrics.record_error("forbidden");
+        return bucket_forbidden_response(&bucket_name);
+    }
+    let style_overrides = match query_angle(&query).and_then(StyleOverrides::with_angle) {
+        Ok(style_overrides) => style_overrides,
+        Err(e) => {
+            error!("Rejected request: {}", e);
+            app_state.metrics.record_error("invalid_request");
+            return error_response(StatusCode::BAD_REQUEST, e);
+        }
+    };
 
-    let img = ImageReader::new(Cursor::new(image_bytes))
-        .with_guessed_format()
-        .map_err(|e| format!("Could not guess image format: {}", e))?
-        .decode()
-        .map_err(|e| format!("Failed to decode image: {}", e))?;
-
-    let width = img.width();
-    let height = img.height();
-    info!("Image decoded: {}x{} pixels", width, height);
-
-    let font = {
-        let maybe_font_guard = watermark_font_ref
-            .read()
-            .map_err(|_| "Failed to acquire read lock on font".to_string())?;
-        maybe_font_guard
-            .as_ref()
-            .ok_or("Font not available (failed to load?)")?
-            .clone()
+    let requester = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    let watermark_text = match text_provider::resolve(
+        &app_state.text_providers,
+        &TextRequest {
+            params: &query,
+            req: &req,
+            user_headers: None,
+            bucket: &bucket_name,
+            object: &object_name,
+            requester: &requester,
+        },
+    ) {
+        Ok(watermark_text) => watermark_text,
+        Err(e) => return invalid_token_response(&app_state, e),
+    };
+    let (rendered, download_duration, process_duration) = match fetch_and_render(
+        &app_state,
+        &bucket_name,
+        &object_name,
+        &watermark_text.text,
+        watermark_text.cacheable,
+        &style_overrides,
+        webp_allowed(&req, None),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(response) => return response,
     };
 
-    let font_height = (height as f32 * CONFIG.font_height_ratio).max(CONFIG.font_height_min);
-    let scale = Scale {
-        x: font_height * CONFIG.font_width_ratio,
-        y: font_height,
+    let response = match rendered.svg_overlay {
+        Some(svg) => HttpResponse::Ok().content_type("image/svg+xml").body(svg),
+        None => HttpResponse::NoContent().finish(),
     };
+    with_timing_headers(response, Some(download_duration), Some(process_duration))
+}
 
-    let watermark_color = CONFIG.watermark_color;
-    let shadow_color = CONFIG.shadow_color;
-    let shadow_offset_ratio = CONFIG.shadow_offset_ratio;
-    let shadow_offset_x = (scale.x * shadow_offset_ratio).round() as i32;
-    let shadow_offset_y = (scale.y * shadow_offset_ratio).round() as i32;
+/// Path of the `/wm-svg` route serving the overlay of an object, carrying `params` so
+/// the overlay is drawn with the same text and angle as the image.
+fn svg_overlay_link(
+    bucket_name: &str,
+    object_name: &str,
+    params: &HashMap<String, String>,
+) -> String {
+    let mut link = format!(
+        "/wm-svg/{}/{}",
+        utf8_percent_encode(bucket_name, PATH_SEGMENT),
+        utf8_percent_encode(object_name, PATH_SEGMENT)
+    );
+    if !params.is_empty() {
+        let mut pairs: Vec<_> = params.iter().collect();
+        pairs.sort();
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(pairs)
+            .finish();
+        link.push('?');
+        link.push_str(&query);
+    }
+    link
+}
 
-    let chars: Vec<char> = watermark_text.chars().collect();
-    let char_spacing_x = scale.x * CONFIG.char_spacing_x_ratio;
-    let char_spacing_y = scale.y * CONFIG.char_spacing_y_ratio;
-    let chars_per_row = ((width as f32 / char_spacing_x).ceil() as usize).max(1);
-    let rows = ((height as f32 / char_spacing_y).ceil() as usize).max(1) + 1;
-    let global_offset_x = char_spacing_x * CONFIG.global_offset_x_ratio;
-    let global_offset_y = char_spacing_y * CONFIG.global_offset_y_ratio;
+/// Watermarks the image sent as the request body and returns it directly, without a
+/// MinIO round trip. The text is resolved like for other requests, from the query or
+/// the `WATERMARK_TEXT_HEADER` header depending on `WATERMARK_TEXT_SOURCES`.
+async fn watermark_upload(
+    req: HttpRequest,
+    body: web::Bytes,
+    query: web::Query<HashMap<String, String>>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    info!(
+        body_bytes = body.len();
+        "Received upload watermarking request of {} bytes",
+        body.len()
+    );
+    app_state.metrics.record_request();
 
-    // Create a transparent layer for the watermark text and shadow
-    let mut watermark_layer = RgbaImage::new(width, height);
+    if body.is_empty() {
+        app_state.metrics.record_error("invalid_request");
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing image in request body".to_string(),
+        );
+    }
 
-    for row in 0..rows {
-        let x_stagger = if row % 2 == 0 {
-            0.0
-        } else {
-            char_spacing_x / 2.0
-        };
-        let y_pos = (row as f32 * char_spacing_y + global_offset_y).round() as i32;
-
-        for col in 0..chars_per_row {
-            let x_pos = (col as f32 * char_spacing_x + x_stagger + global_offset_x).round() as i32;
-            let char_idx = (row + col) % chars.len();
-
-            // Draw shadow on the watermark layer
-            draw_text_mut(
-                &mut watermark_layer,
-                shadow_color,
-                x_pos + shadow_offset_x,
-                y_pos + shadow_offset_y,
-                scale,
-                &font,
-                &chars[char_idx].to_string(),
-            );
+    let requester = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string();
+    let watermark_text = match text_provider::resolve(
+        &app_state.text_providers,
+        &TextRequest {
+            params: &query,
+            req: &req,
+            user_headers: None,
+            bucket: "",
+            object: "",
+            requester: &requester,
+        },
+    ) {
+        Ok(watermark_text) => watermark_text,
+        Err(e) => return invalid_token_response(&app_state, e),
+    };
+    let debug_stats = wants_debug_stats(&query, &req, None);
+    let debug_layout = wants_debug_layout(&query, &req, None);
 
-            // Draw watermark text on the watermark layer
-            draw_text_mut(
-                &mut watermark_layer,
-                watermark_color,
-                x_pos,
-                y_pos,
-                scale,
-                &font,
-                &chars[char_idx].to_string(),
-            );
+    let webp_allowed = webp_allowed(&req, None);
+    let start_time = Instant::now();
+    let timed = |response| with_timing_headers(response, None, Some(start_time.elapsed()));
+    let rendered = match render_with_limit(
+        &app_state,
+        body,
+        "",
+        &watermark_text.text,
+        &CONFIG.watermark_style(),
+        webp_allowed,
+    )
+    .await
+    {
+        Ok(rendered) => rendered,
+        Err(e) if e.starts_with(RENDER_QUEUE_FULL_ERROR) => {
+            warn!("Rejected uploaded image: {}", e);
+            app_state.metrics.record_error("overloaded");
+            return timed(render_queue_full_response(e));
+        }
+        Err(e) if e.starts_with(UNSUPPORTED_MEDIA_ERROR) => {
+            warn!("Rejected uploaded image: {}", e);
+            app_state.metrics.record_error("unsupported_media");
+            return timed(error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, e));
         }
+        Err(e) => {
+            error!("Failed to add watermark: {}", e);
+            app_state.metrics.record_error(render_error_category(&e));
+            return timed(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to add watermark: {}", e),
+            ));
+        }
+    };
+    let process_duration = start_time.elapsed();
+    app_state
+        .metrics
+        .record_durations(Duration::ZERO, process_duration);
+    log_slow_request("uploaded image", Duration::ZERO, process_duration);
+
+    if debug_stats {
+        return debug_stats_response(&rendered, Duration::ZERO, process_duration);
     }
 
-    // Convert the original image to RGBA if it's not already
-    let mut base_image = img.into_rgba8();
+    with_timing_headers(
+        image_response(
+            &rendered,
+            debug_layout,
+            None,
+            usercode_hash(&watermark_text.text).as_deref(),
+            None,
+        ),
+        None,
+        Some(process_duration),
+    )
+}
 
-    // Merge the watermark layer onto the base image using alpha blending
-    for y in 0..height {
-        for x in 0..width {
-            let watermark_pixel = watermark_layer.get_pixel(x, y);
-            let base_pixel = base_image.get_pixel_mut(x, y);
+/// Downloads and renders an object, going through the result cache when it is
+/// enabled and the result is `cacheable`. Returns the download and processing durations,
+/// both zero on a cache hit. Concurrent misses for the same key render once while the
+/// others wait.
+async fn fetch_and_render(
+    app_state: &AppState,
+    bucket_name: &str,
+    object_name: &str,
+    watermark_text: &str,
+    cacheable: bool,
+    style_overrides: &StyleOverrides,
+    webp_allowed: bool,
+) -> Result<(RenderedImage, Duration, Duration), HttpResponse> {
+    let Some(cache) = app_state.cache.as_ref().filter(|_| cacheable) else {
+        return download_and_render(
+            app_state,
+            bucket_name,
+            object_name,
+            watermark_text,
+            style_overrides,
+            webp_allowed,
+        )
+        .await;
+    };
+    let etag = match object_etag(&app_state.minio_client, bucket_name, object_name).await {
+        Ok(etag) => etag,
+        Err(e) => {
+            // The download reports the failure, or succeeds without the cache
+            warn!(
+                "Bypassing the cache for {}/{}: {}",
+                bucket_name, object_name, e
+            );
+            return download_and_render(
+                app_state,
+                bucket_name,
+                object_name,
+                watermark_text,
+                style_overrides,
+                webp_allowed,
+            )
+            .await;
+        }
+    };
+
+    let key = CacheKey {
+        bucket: bucket_name.to_string(),
+        object: object_name.to_string(),
+        etag,
+        text: watermark_text.to_string(),
+        watermarked: CONFIG
+            .watermark_schedule
+            .as_ref()
+            .is_none_or(|schedule| schedule.is_active(Utc::now())),
+        style: *style_overrides,
+        webp_allowed,
+    };
+    if let Some(rendered) = cache.get(&key) {
+        return Ok((rendered, Duration::ZERO, Duration::ZERO));
+    }
+
+    let _flight = cache.claim(&key).await;
+    if let Some(rendered) = cache.recheck(&key) {
+        info!(
+            "Using result rendered by a concurrent request for {}/{}",
+            bucket_name, object_name
+        );
+        return Ok((rendered, Duration::ZERO, Duration::ZERO));
+    }
+
+    let result = download_and_render(
+        app_state,
+        bucket_name,
+        object_name,
+        watermark_text,
+        style_overrides,
+        webp_allowed,
+    )
+    .await?;
+    // Fallbacks stand in for transient failures and must not outlive them
+    if !result.0.stats.fallback {
+        cache.insert(key, result.0.clone());
+    }
+    Ok(result)
+}
+
+async fn download_and_render(
+    app_state: &AppState,
+    bucket_name: &str,
+    object_name: &str,
+    watermark_text: &str,
+    style_overrides: &StyleOverrides,
+    webp_allowed: bool,
+) -> Result<(RenderedImage, Duration, Duration), HttpResponse> {
+    let style = style_overrides.apply(app_state.tenants.as_ref().map_or_else(
+        || CONFIG.watermark_style(),
+        |tenants| tenants.style_for(bucket_name),
+    ));
+    let start_time = Instant::now();
+    let download_result = {
+        let _permit = acquire_download_permit(app_state).await;
+        download_image(&app_state.minio_client, bucket_name, object_name).await
+    };
+    let image_bytes = match download_result {
+        Ok(image_bytes) => image_bytes,
+        Err(e) => {
+            error!("Failed to download image from MinIO: {}", e);
+            app_state.metrics.record_error("download");
+            if let Some(fallback) =
+                render_fallback(app_state, watermark_text, &style, webp_allowed).await
+            {
+                return Ok(fallback);
+            }
+            return Err(with_timing_headers(
+                download_error_response(&e),
+                Some(start_time.elapsed()),
+                None,
+            ));
+        }
+    };
+    let download_duration = start_time.elapsed();
+    let timed = |response| {
+        let process_duration = start_time.elapsed() - download_duration;
+        with_timing_headers(response, Some(download_duration), Some(process_duration))
+    };
+
+    let rendered = match render_with_limit(
+        app_state,
+        image_bytes,
+        object_name,
+        watermark_text,
+        &style,
+        webp_allowed,
+    )
+    .await
+    {
+        Ok(rendered) => rendered,
+        Err(e) if e.starts_with(RENDER_QUEUE_FULL_ERROR) => {
+            warn!("Rejected {}/{}: {}", bucket_name, object_name, e);
+            app_state.metrics.record_error("overloaded");
+            return Err(timed(render_queue_full_response(e)));
+        }
+        Err(e) => {
+            let unsupported = e.starts_with(UNSUPPORTED_MEDIA_ERROR);
+            if unsupported {
+                warn!("Rejected {}/{}: {}", bucket_name, object_name, e);
+                app_state.metrics.record_error("unsupported_media");
+            } else {
+                error!("Failed to add watermark: {}", e);
+                app_state.metrics.record_error(render_error_category(&e));
+            }
+
+            if unsupported || render_error_category(&e) == "decode" {
+                if let Some(fallback) =
+                    render_fallback(app_state, watermark_text, &style, webp_allowed).await
+                {
+                    return Ok(fallback);
+                }
+            }
+            if unsupported {
+                return Err(timed(error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, e)));
+            }
+            return Err(timed(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to add watermark: {}", e),
+            )));
+        }
+    };
+    let process_duration = start_time.elapsed() - download_duration;
+    info!(
+        bucket = bucket_name,
+        object = object_name,
+        watermark_text = watermark_text,
+        download_ms = duration_ms(download_duration),
+        process_ms = duration_ms(process_duration);
+        "Successfully processed image with watermark '{}'. Download: {:?}, Process: {:?}",
+        watermark_text, download_duration, process_duration
+    );
+    app_state
+        .metrics
+        .record_durations(download_duration, process_duration);
+
+    Ok((rendered, download_duration, process_duration))
+}
+
+/// Answers a request whose `X-Watermark-Token` does not verify with 403.
+fn invalid_token_response(app_state: &AppState, message: String) -> HttpResponse {
+    warn!("Rejected watermark token: {}", message);
+    app_state.metrics.record_error("invalid_request");
+    error_response(StatusCode::FORBIDDEN, message)
+}
+
+/// Answers a failed download with 504 for timeouts, 404 for missing objects, 413 for
+/// objects over `MAX_OBJECT_BYTES` and 500 for anything else.
+fn download_error_response(error: &str) -> HttpResponse {
+    let status = if error.starts_with(DOWNLOAD_TIMEOUT_ERROR) {
+        StatusCode::GATEWAY_TIMEOUT
+    } else if error.starts_with(OBJECT_NOT_FOUND_ERROR) {
+        StatusCode::NOT_FOUND
+    } else if error.starts_with(OBJECT_TOO_LARGE_ERROR) {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    error_response(
+        status,
+        format!("Failed to download image from MinIO: {}", error),
+    )
+}
+
+/// Downloads an object and re-encodes it without a watermark, for admin `?original=1`
+/// requests, or returns its bytes unchanged with `passthrough`. The cache is bypassed
+/// so originals are never served to other requests.
+async fn download_original(
+    app_state: &AppState,
+    bucket_name: &str,
+    object_name: &str,
+    passthrough: bool,
+    webp_allowed: bool,
+) -> Result<(RenderedImage, Duration, Duration), HttpResponse> {
+    let start_time = Instant::now();
+    let download_result = {
+        let _permit = acquire_download_permit(app_state).await;
+        download_image(&app_state.minio_client, bucket_name, object_name).await
+    };
+    let image_bytes = download_result.map_err(|e| {
+        error!("Failed to download image from MinIO: {}", e);
+        app_state.metrics.record_error("download");
+        with_timing_headers(
+            download_error_response(&e),
+            Some(start_time.elapsed()),
+            None,
+        )
+    })?;
+    let download_duration = start_time.elapsed();
+    let timed = |response| {
+        let process_duration = start_time.elapsed() - download_duration;
+        with_timing_headers(response, Some(download_duration), Some(process_duration))
+    };
+
+    if passthrough {
+        let rendered = passthrough_image(image_bytes);
+        return Ok((
+            rendered,
+            download_duration,
+            start_time.elapsed() - download_duration,
+        ));
+    }
+    let rendered = check_supported_media(&image_bytes)
+        .and_then(|()| reencode_original(image_bytes, webp_allowed))
+        .and_then(verified)
+        .map_err(|e| {
+            if e.starts_with(UNSUPPORTED_MEDIA_ERROR) {
+                app_state.metrics.record_error("unsupported_media");
+                return timed(error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, e));
+            }
+            error!("Failed to re-encode original image: {}", e);
+            app_state.metrics.record_error(render_error_category(&e));
+            timed(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to re-encode original image: {}", e),
+            ))
+        })?;
+
+    Ok((
+        rendered,
+        download_duration,
+        start_time.elapsed() - download_duration,
+    ))
+}
+
+/// Watermarks `FALLBACK_IMAGE_PATH` in place of a source that could not be downloaded
+/// or decoded, when `SERVE_FALLBACK` is enabled. Returns `None` otherwise, or when the
+/// fallback itself fails to render.
+async fn render_fallback(
+    app_state: &AppState,
+    watermark_text: &str,
+    style: &WatermarkStyle,
+    webp_allowed: bool,
+) -> Option<(RenderedImage, Duration, Duration)> {
+    let fallback_image = app_state.fallback_image.clone()?;
+
+    let start_time = Instant::now();
+    match render_with_limit(
+        app_state,
+        fallback_image,
+        "",
+        watermark_text,
+        style,
+        webp_allowed,
+    )
+    .await
+    {
+        Ok(mut rendered) => {
+            warn!("Serving fallback image in place of the requested object");
+            rendered.stats.fallback = true;
+            Some((rendered, Duration::ZERO, start_time.elapsed()))
+        }
+        Err(e) => {
+            error!("Failed to render fallback image: {}", e);
+            None
+        }
+    }
+}
+
+/// Waits for a free `MAX_CONCURRENT_DOWNLOADS` slot, or returns `None` right away when
+/// downloads are not limited. The wait counts towards the download duration.
+async fn acquire_download_permit(app_state: &AppState) -> Option<SemaphorePermit<'_>> {
+    let downloads = app_state.downloads.as_ref()?;
+    let permit = downloads.acquire().await.ok()?;
+    debug!(
+        "Download slot acquired: {} of {} in use",
+        CONFIG.max_concurrent_downloads - downloads.available_permits(),
+        CONFIG.max_concurrent_downloads
+    );
+    Some(permit)
+}
+
+/// Runs `process_image` on the blocking thread pool, so rendering does not hold up the
+/// workers answering other requests such as health checks. With
+/// `MAX_CONCURRENT_RENDERS` set it first waits for a free render slot, kept until the
+/// render finishes even when the client goes away, and fails with
+/// `RENDER_QUEUE_FULL_ERROR` when `RENDER_QUEUE_LIMIT` requests are already waiting.
+async fn render_with_limit(
+    app_state: &AppState,
+    image_bytes: Bytes,
+    object_name: &str,
+    watermark_text: &str,
+    style: &WatermarkStyle,
+    webp_allowed: bool,
+) -> Result<RenderedImage, String> {
+    let permit = acquire_render_permit(app_state).await?;
+    let object_name = object_name.to_string();
+    let watermark_text = watermark_text.to_string();
+    let font = app_state.font.clone();
+    let fallback_fonts = Arc::clone(&app_state.fallback_fonts);
+    let logo = app_state.logo.clone();
+    let style = *style;
+    web::block(move || {
+        let _permit = permit;
+        process_image(
+            image_bytes,
+            &object_name,
+            &watermark_text,
+            &font,
+            &fallback_fonts,
+            logo.as_deref(),
+            &style,
+            webp_allowed,
+        )
+    })
+    .await
+    .map_err(|e| format!("Render task failed: {}", e))?
+}
+
+/// Takes a free `MAX_CONCURRENT_RENDERS` slot, waiting for one unless
+/// `RENDER_QUEUE_LIMIT` requests already do, or returns `None` right away when renders
+/// are not limited.
+async fn acquire_render_permit(
+    app_state: &AppState,
+) -> Result<Option<OwnedSemaphorePermit>, String> {
+    let Some(renders) = app_state.renders.as_ref() else {
+        return Ok(None);
+    };
+    let permit = match renders.slots.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let waiting = WaitingRender::enter(&renders.waiting);
+            if CONFIG.render_queue_limit > 0 && waiting.position >= CONFIG.render_queue_limit {
+                return Err(format!(
+                    "{}: all {} render slots are busy and {} requests are waiting",
+                    RENDER_QUEUE_FULL_ERROR, CONFIG.max_concurrent_renders, waiting.position
+                ));
+            }
+            renders
+                .slots
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|e| format!("Render slots closed: {}", e))?
+        }
+    };
+    debug!(
+        "Render slot acquired: {} of {} in use, {} waiting",
+        CONFIG.max_concurrent_renders - renders.slots.available_permits(),
+        CONFIG.max_concurrent_renders,
+        renders.waiting.load(Ordering::Relaxed)
+    );
+    Ok(Some(permit))
+}
+
+/// Counts a request in `RenderSlots::waiting` for as long as it lives, so requests
+/// dropped while waiting are not counted forever.
+struct WaitingRender<'a> {
+    waiting: &'a AtomicUsize,
+    /// Requests that were already waiting when this one arrived
+    position: usize,
+}
+
+impl<'a> WaitingRender<'a> {
+    fn enter(waiting: &'a AtomicUsize) -> Self {
+        let position = waiting.fetch_add(1, Ordering::Relaxed);
+        Self { waiting, position }
+    }
+}
+
+impl Drop for WaitingRender<'_> {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Answers a request turned away by `RENDER_QUEUE_LIMIT` with 503 and a `Retry-After`.
+fn render_queue_full_response(message: String) -> HttpResponse {
+    let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, message);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(RENDER_RETRY_AFTER_SECS));
+    response
+}
+
+/// Maps a `process_image` error message to its `watermark_errors_total` category.
+fn render_error_category(message: &str) -> &'static str {
+    const DECODE_ERRORS: [&str; 5] = [
+        "Could not guess image format",
+        "Failed to decode image",
+        "Failed to read image dimensions",
+        "Failed to parse SVG",
+        "SVG input is not supported",
+    ];
+    const FONT_ERRORS: [&str; 3] = [
+        "Font not available",
+        "Failed to acquire read lock on font",
+        "Failed to parse font",
+    ];
+
+    if DECODE_ERRORS
+        .iter()
+        .any(|prefix| message.starts_with(prefix))
+    {
+        "decode"
+    } else if FONT_ERRORS.iter().any(|prefix| message.starts_with(prefix)) {
+        "font"
+    } else {
+        "render"
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FontCoverageResponse {
+    present: Vec<GlyphCoverage>,
+    missing: Vec<GlyphCoverage>,
+}
+
+#[derive(Debug, Serialize)]
+struct GlyphCoverage {
+    char: char,
+    codepoint: String,
+    /// Absent for missing characters, which map to the font's .notdef glyph
+    #[serde(skip_serializing_if = "Option::is_none")]
+    glyph_id: Option<u16>,
+    /// Font the glyph comes from: 0 for `FONT_PATH`, then the `FONT_FALLBACK_PATHS`
+    /// fonts from 1. Absent for missing characters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    font_index: Option<usize>,
+}
+
+/// Admin-only report of which distinct characters of `text` the loaded fonts have glyphs
+/// for, to confirm whether reports of invisible watermarks come from missing coverage.
+async fn font_coverage_endpoint(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    if !is_admin_request(&req, None) {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "A valid admin token is required".to_string(),
+        );
+    }
+    let Some(text) = query.get("text") else {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Missing 'text' parameter".to_string(),
+        );
+    };
+    let fonts = match current_fonts(&app_state.font, &app_state.fallback_fonts) {
+        Ok(fonts) => fonts,
+        Err(e) => {
+            error!("Failed to check font coverage: {}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+    };
+
+    let mut seen = Vec::new();
+    let mut coverage = FontCoverageResponse {
+        present: Vec::new(),
+        missing: Vec::new(),
+    };
+    for c in text.chars() {
+        if seen.contains(&c) {
+            continue;
+        }
+        seen.push(c);
+        let font_index = fonts.font_index(c);
+        let entry = GlyphCoverage {
+            char: c,
+            codepoint: format!("U+{:04X}", c as u32),
+            glyph_id: font_index.map(|_| fonts.font_for(&c.to_string()).glyph(c).id().0),
+            font_index,
+        };
+        if font_index.is_none() {
+            coverage.missing.push(entry);
+        } else {
+            coverage.present.push(entry);
+        }
+    }
+    if !coverage.missing.is_empty() {
+        info!(
+            "Font has no glyphs for {} of {} distinct characters checked",
+            coverage.missing.len(),
+            seen.len()
+        );
+    }
+    HttpResponse::Ok().json(coverage)
+}
+
+/// Largest `width` or `height` accepted by `/preview`, the JPEG and WebP limit.
+const MAX_PREVIEW_DIMENSION: u32 = 65535;
+
+/// Computes the tiled text layout for `width`, `height` and `text` without downloading
+/// or encoding anything, so layout settings can be tuned quickly. `bucket` applies that
+/// bucket's `TENANT_CONFIG_PATH` style. The dimensions are those the watermark is laid
+/// out on, after any `MAX_PIXELS` or `TARGET_WIDTH` resizing.
+async fn preview_endpoint(
+    query: web::Query<HashMap<String, String>>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let dimension = |name: &str| -> Result<u32, String> {
+        let value = query
+            .get(name)
+            .ok_or_else(|| format!("Missing '{}' parameter", name))?;
+        value
+            .parse::<u32>()
+            .ok()
+            .filter(|value| (1..=MAX_PREVIEW_DIMENSION).contains(value))
+            .ok_or_else(|| {
+                format!(
+                    "Invalid '{}' parameter '{}', expected 1 to {}",
+                    name, value, MAX_PREVIEW_DIMENSION
+                )
+            })
+    };
+    let (width, height) = match dimension("width").and_then(|w| Ok((w, dimension("height")?))) {
+        Ok(dimensions) => dimensions,
+        Err(message) => {
+            return error_response(StatusCode::BAD_REQUEST, message);
+        }
+    };
+    let text = query.get("text").map_or("WATERMARK", String::as_str);
+    let style = match (&app_state.tenants, query.get("bucket")) {
+        (Some(tenants), Some(bucket)) => tenants.style_for(bucket),
+        _ => CONFIG.watermark_style(),
+    };
+
+    let fonts = match current_fonts(&app_state.font, &app_state.fallback_fonts) {
+        Ok(fonts) => fonts,
+        Err(e) => {
+            error!("Failed to compute preview: {}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, e);
+        }
+    };
+    if CONFIG.watermark_mode == WatermarkMode::Center {
+        let chars = watermark_chars(text, fonts.primary());
+        let layout = centered_layout(width, height, &chars.concat(), fonts.primary(), &style);
+        return HttpResponse::Ok().json(PreviewResponse {
+            width,
+            height,
+            scale: PreviewScale {
+                x: layout.scale.x,
+                y: layout.scale.y,
+            },
+            chars_per_row: chars.len(),
+            rows: 1,
+            char_spacing_x: layout.scale.x,
+            char_spacing_y: layout.scale.y,
+            glyph_count: chars.len(),
+            fits: true,
+        });
+    }
+    let (chars, line_lengths) = watermark_lines(text, &fonts);
+    let layout = WatermarkLayout::new(
+        width,
+        height,
+        &line_lengths,
+        glyph_extent(&chars, &fonts, &CONFIG.layout),
+        &style,
+        &CONFIG.layout,
+    );
+    let fits = fits_one_glyph(width, height, None, &style);
+    let glyph_count = if chars.is_empty() {
+        0
+    } else if fits {
+        layout.glyph_count(width, height, style.angle, &CONFIG.layout)
+    } else {
+        match CONFIG.tiny_image_mode {
+            TinyImageMode::Skip => 0,
+            TinyImageMode::Fit => 1,
+            TinyImageMode::Error => {
+                return error_response(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!(
+                        "Image {}x{} is too small for a single watermark glyph",
+                        width, height
+                    ),
+                );
+            }
+        }
+    };
+
+    HttpResponse::Ok().json(PreviewResponse {
+        width,
+        height,
+        scale: PreviewScale {
+            x: layout.scale.x,
+            y: layout.scale.y,
+        },
+        chars_per_row: layout.chars_per_row,
+        rows: layout.rows,
+        char_spacing_x: layout.char_spacing_x,
+        char_spacing_y: layout.char_spacing_y,
+        glyph_count,
+        fits,
+    })
+}
+
+/// Readiness probe: checks that `HEALTHCHECK_BUCKET` is reachable with the configured
+/// credentials and answers 503 otherwise. Without a bucket configured it only reports
+/// that the server is up, like `/health/`.
+async fn readiness_endpoint(app_state: web::Data<AppState>) -> impl Responder {
+    let Some(bucket) = CONFIG.healthcheck_bucket.as_deref() else {
+        return HttpResponse::Ok().body("OK");
+    };
+
+    let check = async {
+        let args = BucketExistsArgs::new(bucket).map_err(|e| e.to_string())?;
+        app_state
+            .minio_client
+            .bucket_exists(&args)
+            .await
+            .map_err(|e| e.to_string())
+    };
+    let result = if CONFIG.minio_timeout_secs > 0 {
+        tokio::time::timeout(Duration::from_secs(CONFIG.minio_timeout_secs), check)
+            .await
+            .unwrap_or_else(|_| Err(format!("timed out after {} s", CONFIG.minio_timeout_secs)))
+    } else {
+        check.await
+    };
+
+    let message = match result {
+        Ok(true) => return HttpResponse::Ok().body("OK"),
+        Ok(false) => format!("Bucket '{}' does not exist", bucket),
+        Err(e) => format!("Failed to reach bucket '{}': {}", bucket, e),
+    };
+    warn!("Readiness check failed: {}", message);
+    error_response(StatusCode::SERVICE_UNAVAILABLE, message)
+}
+
+async fn metrics_endpoint(app_state: web::Data<AppState>) -> impl Responder {
+    // Read at scrape time so the gauges reflect every insert and eviction so far
+    if let Some(cache) = &app_state.cache {
+        let (entries, bytes) = cache.usage();
+        app_state.metrics.set_cache_usage(entries, bytes);
+    }
+
+    match app_state.metrics.render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to encode metrics: {}", e),
+            )
+        }
+    }
+}
+
+/// Builds an error response with a body in `ERROR_FORMAT`.
+fn error_response(status: StatusCode, message: impl Into<String>) -> HttpResponse {
+    let message = message.into();
+    match CONFIG.error_format {
+        ErrorFormat::Json => HttpResponse::build(status).json(GenerateResponse {
+            status: "error".to_string(),
+            message,
+        }),
+        ErrorFormat::Xml => {
+            let mut escaped = String::with_capacity(message.len());
+            for c in message.chars() {
+                push_xml_escaped(&mut escaped, c);
+            }
+            HttpResponse::build(status)
+                .content_type("application/xml")
+                .body(format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message></Error>",
+                    s3_error_code(status),
+                    escaped
+                ))
+        }
+    }
+}
+
+/// S3 error code for an error status, or the status reason without spaces when S3 has
+/// no equivalent, e.g. `UnprocessableEntity`.
+fn s3_error_code(status: StatusCode) -> String {
+    match status {
+        StatusCode::BAD_REQUEST => "InvalidRequest".to_string(),
+        StatusCode::FORBIDDEN => "AccessDenied".to_string(),
+        StatusCode::NOT_FOUND => "NoSuchKey".to_string(),
+        StatusCode::PAYLOAD_TOO_LARGE => "EntityTooLarge".to_string(),
+        StatusCode::INTERNAL_SERVER_ERROR => "InternalError".to_string(),
+        _ => status
+            .canonical_reason()
+            .unwrap_or("Unknown")
+            .replace(' ', ""),
+    }
+}
+
+/// Builds the response carrying a rendered image, with a `Content-Type` and
+/// `Content-Length` matching the encoded body. `lambda` holds the object-lambda
+/// `outputRoute` and `outputToken`, echoed back with the content type forwarded to
+/// the end client. `svg_link` points to the route serving the SVG overlay, if any.
+fn image_response(
+    rendered: &RenderedImage,
+    debug_layout: bool,
+    lambda: Option<(&str, &str)>,
+    usercode_hash: Option<&str>,
+    svg_link: Option<String>,
+) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(rendered.content_type)
+        .insert_header((CONTENT_LENGTH, rendered.body.len()));
+    if let Some((output_route, output_token)) = lambda {
+        // Direct callers may not send these, and some proxies reject empty headers
+        if !output_route.is_empty() {
+            response.append_header(("x-amz-request-route", output_route));
+        }
+        if !output_token.is_empty() {
+            response.append_header(("x-amz-request-token", output_token));
+        }
+        response.append_header(("x-amz-fwd-header-Content-Type", rendered.content_type));
+    }
+    if let Some(svg_link) = svg_link.filter(|_| rendered.svg_overlay.is_some()) {
+        response.append_header(("X-Watermark-SVG", svg_link));
+    }
+    if let Some(blurhash) = &rendered.blurhash {
+        response.append_header(("X-Blurhash", blurhash.as_str()));
+    }
+    if let Some(hash) = usercode_hash {
+        response.append_header(("X-Usercode-Hash", hash));
+    }
+    if debug_layout {
+        let stats = &rendered.stats;
+        response
+            .append_header(("X-Wm-Cols", stats.layout_cols.to_string()))
+            .append_header(("X-Wm-Rows", stats.layout_rows.to_string()))
+            .append_header(("X-Wm-Scale", format!("{:.2}", stats.layout_scale)))
+            .append_header(("X-Wm-GlyphDraws", stats.glyph_draws.to_string()));
+    }
+    response.body(rendered.body.clone())
+}
+
+/// Hex HMAC-SHA256 of the watermark text keyed with `USERCODE_HASH_SECRET`, so
+/// responses can be matched to requests without exposing the text itself.
+fn usercode_hash(watermark_text: &str) -> Option<String> {
+    let secret = CONFIG.usercode_hash_secret.as_deref()?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(watermark_text.as_bytes());
+    Some(
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
+
+/// Adds `X-Watermark-Download-Ms`, `X-Watermark-Process-Ms` and `X-Watermark-Total-Ms`
+/// for the phases that ran when `EXPOSE_TIMING_HEADERS` is enabled. Cache hits report
+/// zero for both.
+fn with_timing_headers(
+    mut response: HttpResponse,
+    download_duration: Option<Duration>,
+    process_duration: Option<Duration>,
+) -> HttpResponse {
+    if !CONFIG.expose_timing_headers {
+        return response;
+    }
+    let total_duration =
+        download_duration.unwrap_or_default() + process_duration.unwrap_or_default();
+    for (name, duration) in [
+        ("x-watermark-download-ms", download_duration),
+        ("x-watermark-process-ms", process_duration),
+        ("x-watermark-total-ms", Some(total_duration)),
+    ] {
+        if let Some(duration) = duration {
+            let value = HeaderValue::from_str(&format!("{:.1}", duration_ms(duration)))
+                .expect("formatted durations are valid header values");
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+    response
+}
+
+fn debug_stats_response(
+    rendered: &RenderedImage,
+    download_duration: Duration,
+    process_duration: Duration,
+) -> HttpResponse {
+    HttpResponse::Ok().json(DebugStatsResponse {
+        download_ms: duration_ms(download_duration),
+        process_ms: duration_ms(process_duration),
+        render: &rendered.stats,
+    })
+}
+
+fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// `?debugstats=1` returns render statistics as JSON instead of the image, for
+/// admin requests only. Without a valid token the parameter is ignored.
+fn wants_debug_stats(
+    params: &HashMap<String, String>,
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    admin_flag(params, "debugstats", req, user_headers)
+}
+
+/// `?debug=1` adds the effective layout as `X-Wm-*` response headers, for admin
+/// requests only. Without a valid token the parameter is ignored.
+fn wants_debug_layout(
+    params: &HashMap<String, String>,
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    admin_flag(params, "debug", req, user_headers)
+}
+
+/// `?original=1` returns the source image re-encoded without a watermark, for admin
+/// requests only. Without a valid token the parameter is ignored.
+fn wants_original(
+    params: &HashMap<String, String>,
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    admin_flag(params, "original", req, user_headers)
+}
+
+/// `?watermark=none` or `?nowatermark=1` returns the downloaded object untouched, for
+/// admin requests only. Without a valid token the parameters are ignored.
+fn wants_passthrough(
+    params: &HashMap<String, String>,
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    if params.get("watermark").map(String::as_str) == Some("none") {
+        return admin_allowed("watermark", req, user_headers);
+    }
+    admin_flag(params, "nowatermark", req, user_headers)
+}
+
+fn admin_flag(
+    params: &HashMap<String, String>,
+    name: &str,
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    params.get(name).map(String::as_str) == Some("1") && admin_allowed(name, req, user_headers)
+}
+
+/// Whether the admin-only parameter `name` may be used, logging when it may not.
+fn admin_allowed(
+    name: &str,
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    if !is_admin_request(req, user_headers) {
+        warn!(
+            "Ignoring {} parameter on request without a valid admin token",
+            name
+        );
+        return false;
+    }
+    true
+}
+
+/// Whether objects of `bucket` may be served under `ALLOWED_BUCKETS`. Every bucket is
+/// allowed when it is unset.
+fn bucket_allowed(bucket: &str) -> bool {
+    is_allowed_bucket(&CONFIG.allowed_buckets, bucket)
+}
+
+fn is_allowed_bucket(allowed_buckets: &[String], bucket: &str) -> bool {
+    allowed_buckets.is_empty() || allowed_buckets.iter().any(|allowed| allowed == bucket)
+}
+
+fn bucket_forbidden_response(bucket: &str) -> HttpResponse {
+    warn!(
+        "Rejected request for bucket '{}' outside ALLOWED_BUCKETS",
+        bucket
+    );
+    error_response(
+        StatusCode::FORBIDDEN,
+        format!("Bucket '{}' is not allowed", bucket),
+    )
+}
+
+/// Whether the request carries the configured `ADMIN_TOKEN`, either directly in the
+/// `X-Admin-Token` header or, for object-lambda calls, in the end user's request headers.
+fn is_admin_request(
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    let Some(admin_token) = CONFIG.admin_token.as_deref() else {
+        return false;
+    };
+
+    let direct = req
+        .headers()
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let forwarded = forwarded_header(user_headers, ADMIN_TOKEN_HEADER);
+
+    direct
+        .into_iter()
+        .chain(forwarded)
+        .any(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()))
+}
+
+/// Whether `OUTPUT_FORMAT=smallest` may answer with WebP, from the `Accept` header of
+/// the end user's request. Requests without one accept any format, and the other
+/// output modes do not consult it.
+fn webp_allowed(
+    req: &HttpRequest,
+    user_headers: Option<&HashMap<String, serde_json::Value>>,
+) -> bool {
+    if CONFIG.output_format != OutputFormat::Smallest {
+        return true;
+    }
+
+    let accept = forwarded_header(user_headers, "Accept").or_else(|| {
+        req.headers()
+            .get(actix_web::http::header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+    });
+    accept.is_none_or(|accept| {
+        accept.split(',').any(|range| {
+            let mut parts = range.split(';');
+            let media_type = parts.next().unwrap_or_default().trim();
+            let refused = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            !refused
+                && ["image/webp", "image/*", "*/*"]
+                    .iter()
+                    .any(|accepted| media_type.eq_ignore_ascii_case(accepted))
+        })
+    })
+}
+
+/// Looks up a header of the end user's request in an object-lambda payload.
+fn forwarded_header<'a>(
+    user_headers: Option<&'a HashMap<String, serde_json::Value>>,
+    name: &str,
+) -> Option<&'a str> {
+    // MinIO forwards the user's headers as lists of values
+    user_headers?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| match value {
+            serde_json::Value::String(value) => Some(value.as_str()),
+            serde_json::Value::Array(values) => values.first().and_then(|v| v.as_str()),
+            _ => None,
+        })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn format_name(format: ImageFormat) -> String {
+    format!("{:?}", format).to_lowercase()
+}
+
+fn log_slow_request(source: &str, download_duration: Duration, process_duration: Duration) {
+    if CONFIG.slow_request_ms == 0 {
+        return;
+    }
+
+    let total_duration = download_duration + process_duration;
+    if total_duration.as_millis() >= CONFIG.slow_request_ms as u128 {
+        warn!(
+            target: SLOW_REQUEST_TARGET,
+            source = source,
+            total_ms = duration_ms(total_duration),
+            download_ms = duration_ms(download_duration),
+            process_ms = duration_ms(process_duration);
+            "Slow request for '{}': total {:?} (download: {:?}, process: {:?}) exceeded {} ms",
+            source,
+            total_duration,
+            download_duration,
+            process_duration,
+            CONFIG.slow_request_ms
+        );
+    }
+}
+
+fn parse_s3_url(s3_url: &str) -> Result<(String, String), String> {
+    if let Some(template) = &CONFIG.url_template {
+        if let Some(captures) = template.captures(s3_url) {
+            let bucket = captures
+                .name("bucket")
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_else(|| CONFIG.url_template_bucket.clone());
+            let object = match captures.name("key") {
+                Some(key) => decode_path(key.as_str())?,
+                None => String::new(),
+            };
+            if !bucket.is_empty() && !object.is_empty() {
+                return Ok((bucket, object));
+            }
+            return Err(
+                "URL matched URL_TEMPLATE but did not resolve to a bucket and object key"
+                    .to_string(),
+            );
+        }
+    }
+
+    if s3_url.starts_with("s3://") {
+        let parsed_url = Url::parse(s3_url).map_err(|_| "Failed to parse S3 URL".to_string())?;
+        if parsed_url.scheme() != "s3" {
+            return Err("URL scheme is not 's3'".to_string());
+        }
+        if let Some(host) = parsed_url.host_str() {
+            let path_segments = decoded_path_segments(&parsed_url)?;
+            if !host.is_empty() && !path_segments.is_empty() {
+                Ok((host.to_string(), path_segments.join("/")))
+            } else {
+                Err("Invalid S3 URL format: missing bucket or object key".to_string())
+            }
+        } else {
+            Err("Invalid S3 URL format: missing bucket".to_string())
+        }
+    } else {
+        // Tentativa de parsing para URLs HTTP que podem conter bucket e objeto no path
+        // Exemplo: http://minio.example.com/mybucket/myimage.jpg?param=value
+        if let Ok(parsed_url) = Url::parse(s3_url) {
+            let segments = decoded_path_segments(&parsed_url)?;
+            if segments.len() >= 2 {
+                let bucket = segments[0].clone();
+                let object = segments[1..].join("/");
+                if !bucket.is_empty() && !object.is_empty() {
+                    return Ok((bucket, object));
+                }
+            }
+        }
+        Err("S3 URL does not start with 's3://' and could not be parsed as HTTP URL with bucket/object".to_string())
+    }
+}
+
+/// Path segments of `url` with percent-encoding removed, so keys such as
+/// `folder/my%20file.jpg` reach MinIO as `folder/my file.jpg`. The query string is
+/// not part of the path and never ends up in the object key.
+fn decoded_path_segments(url: &Url) -> Result<Vec<String>, String> {
+    url.path_segments()
+        .map(|segments| segments.map(decode_path).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))
+}
+
+/// Percent-decodes an object key or key segment. `+` is kept as is, as it is a
+/// literal character in URL paths.
+fn decode_path(path: &str) -> Result<String, String> {
+    percent_decode_str(path)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| format!("Object key '{}' is not valid UTF-8 once decoded", path))
+}
+
+/// Query parameters of the Lambda event's user request URL, percent-decoded like the
+/// query of the other endpoints. A parameter without `=` has an empty value, the last
+/// of repeated keys wins and the fragment is not part of the query.
+fn extract_url_params(url: &str) -> HashMap<String, String> {
+    if let Ok(url) = Url::parse(url) {
+        return url.query_pairs().into_owned().collect();
+    }
+    // Relative URLs carry no base to parse against, so decode their query directly
+    let without_fragment = url.split('#').next().unwrap_or_default();
+    without_fragment
+        .split_once('?')
+        .map(|(_, query)| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn download_image(
+    client: &MinioClient,
+    bucket_name: &str,
+    object_name: &str,
+) -> Result<Bytes, String> {
+    info!(
+        "Downloading object '{}' from bucket '{}' in MinIO",
+        object_name, bucket_name
+    );
+
+    let timeout =
+        (CONFIG.minio_timeout_secs > 0).then(|| Duration::from_secs(CONFIG.minio_timeout_secs));
+    let attempts = CONFIG.minio_max_retries + 1;
+    let mut attempt = 1;
+    loop {
+        let result = match timeout {
+            Some(timeout) => {
+                tokio::time::timeout(timeout, get_object_bytes(client, bucket_name, object_name))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(DownloadError {
+                            message: format!(
+                                "{} after {} s",
+                                DOWNLOAD_TIMEOUT_ERROR, CONFIG.minio_timeout_secs
+                            ),
+                            transient: true,
+                        })
+                    })
+            }
+            None => get_object_bytes(client, bucket_name, object_name).await,
+        };
+        let error = match result {
+            Ok(bytes) => return Ok(bytes),
+            Err(error) if !error.transient || attempt >= attempts => return Err(error.message),
+            Err(error) => error,
+        };
+
+        let backoff = retry_backoff(attempt);
+        warn!(
+            "Download attempt {} of {} for '{}/{}' failed: {}. Retrying in {:?}",
+            attempt, attempts, bucket_name, object_name, error.message, backoff
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// The ETag of an object, which changes whenever the object is replaced.
+async fn object_etag(
+    client: &MinioClient,
+    bucket_name: &str,
+    object_name: &str,
+) -> Result<String, String> {
+    let args = StatObjectArgs::new(bucket_name, object_name)
+        .map_err(|e| format!("Failed to create StatObjectArgs: {}", e))?;
+    let stat = client
+        .stat_object(&args)
+        .await
+        .map_err(|e| format!("Failed to stat object in MinIO: {}", e))?;
+    Ok(stat.etag)
+}
+
+/// The wait after the failed `attempt`, counted from 1: `RETRY_BASE_DELAY` doubled
+/// for every earlier attempt, up to `RETRY_MAX_DELAY`.
+fn retry_backoff(attempt: u32) -> Duration {
+    let factor = 2u32
+        .checked_pow(attempt.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    RETRY_BASE_DELAY.saturating_mul(factor).min(RETRY_MAX_DELAY)
+}
+
+/// A failed download attempt. `transient` failures are worth retrying.
+struct DownloadError {
+    message: String,
+    transient: bool,
+}
+
+/// Makes a single attempt at downloading an object.
+async fn get_object_bytes(
+    client: &MinioClient,
+    bucket_name: &str,
+    object_name: &str,
+) -> Result<Bytes, DownloadError> {
+    let args = GetObjectArgs::new(bucket_name, object_name).map_err(|e| DownloadError {
+        message: format!("Failed to create GetObjectArgs: {}", e),
+        transient: false,
+    })?;
+
+    let mut response = client.get_object(&args).await.map_err(|e| match e {
+        S3Error::S3Error(ref response)
+            if response.code == "NoSuchKey" || response.code == "NoSuchBucket" =>
+        {
+            DownloadError {
+                message: format!("{}: {}", OBJECT_NOT_FOUND_ERROR, e),
+                transient: false,
+            }
+        }
+        S3Error::InvalidResponse(404, _) => DownloadError {
+            message: format!("{}: {}", OBJECT_NOT_FOUND_ERROR, e),
+            transient: false,
+        },
+        _ => DownloadError {
+            transient: is_transient(&e),
+            message: format!("Failed to get object from MinIO: {}", e),
+        },
+    })?;
+
+    // The MinIO client follows redirects with reqwest's default policy and exposes
+    // no way to change it, so the best we can do is make redirects visible.
+    if let Ok(endpoint) = Url::parse(&CONFIG.minio_endpoint) {
+        let final_url = response.url();
+        if endpoint.host_str() != final_url.host_str()
+            || endpoint.port_or_known_default() != final_url.port_or_known_default()
+        {
+            info!(
+                "MinIO request for '{}/{}' was redirected to {}",
+                bucket_name, object_name, final_url
+            );
+        }
+    }
+
+    // The body is read chunk by chunk into one buffer that becomes the returned bytes,
+    // so the object is held in memory once and an oversized one is dropped early
+    let limit = CONFIG.max_object_bytes;
+    let too_large = |size: String| DownloadError {
+        message: format!(
+            "{}: '{}/{}' is {}, the limit is {} bytes",
+            OBJECT_TOO_LARGE_ERROR, bucket_name, object_name, size, limit
+        ),
+        transient: false,
+    };
+    let content_length = response.content_length();
+    if let Some(length) = content_length.filter(|length| limit > 0 && *length > limit) {
+        return Err(too_large(format!("{} bytes", length)));
+    }
+
+    // The length header may be missing or wrong, so the read is capped as well
+    let capacity = content_length.unwrap_or(0).min(MAX_PREALLOCATED_BODY_BYTES);
+    let mut body = Vec::with_capacity(capacity as usize);
+    while let Some(chunk) = response.chunk().await.map_err(|e| DownloadError {
+        message: format!("Failed to read object bytes from MinIO: {}", e),
+        transient: true,
+    })? {
+        if limit > 0 && (body.len() + chunk.len()) as u64 > limit {
+            return Err(too_large("larger".to_string()));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(body))
+}
+
+/// Whether a MinIO error is likely to go away on retry: connection problems,
+/// 5xx responses and throttling.
+fn is_transient(error: &S3Error) -> bool {
+    match error {
+        S3Error::HttpError(_) | S3Error::IOError(_) | S3Error::ServerError(_) => true,
+        S3Error::InvalidResponse(status, _) => *status >= 500,
+        S3Error::S3Error(response) => matches!(
+            response.code.as_str(),
+            "InternalError" | "ServiceUnavailable" | "SlowDown" | "RequestTimeout"
+        ),
+        _ => false,
+    }
+}
+
+/// Applies the request-independent policies that decide whether an image gets
+/// watermarked at all, then renders it.
+#[allow(clippy::too_many_arguments)]
+fn process_image(
+    image_bytes: Bytes,
+    object_name: &str,
+    watermark_text: &str,
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    fallback_fonts: &[Font<'static>],
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+    webp_allowed: bool,
+) -> Result<RenderedImage, String> {
+    check_supported_media(&image_bytes)?;
+
+    if let Some(prefix) = CONFIG
+        .skip_prefixes
+        .iter()
+        .find(|prefix| object_name.starts_with(prefix.as_str()))
+    {
+        debug!(
+            "Object '{}' matches SKIP_PREFIXES entry '{}', returning original image",
+            object_name, prefix
+        );
+        return verified(reencode_original(image_bytes, webp_allowed)?);
+    }
+
+    if let Some(schedule) = &CONFIG.watermark_schedule {
+        let now = Utc::now();
+        if !schedule.is_active(now) {
+            info!(
+                "Outside WATERMARK_SCHEDULE at {} ({}), returning original image",
+                now.with_timezone(&schedule.timezone()).format("%H:%M"),
+                schedule.timezone()
+            );
+            return verified(reencode_original(image_bytes, webp_allowed)?);
+        }
+        info!("Within WATERMARK_SCHEDULE, applying watermark");
+    }
+
+    verified(render_image(
+        image_bytes,
+        watermark_text,
+        watermark_font_ref,
+        fallback_fonts,
+        watermark_logo,
+        style,
+        webp_allowed,
+    )?)
+}
+
+/// With `VERIFY_OUTPUT` enabled, decodes the encoded output again so a corrupt
+/// image is reported as an error instead of reaching the client.
+fn verified(rendered: RenderedImage) -> Result<RenderedImage, String> {
+    if !CONFIG.verify_output {
+        return Ok(rendered);
+    }
+
+    let start_time = Instant::now();
+    image::load_from_memory(&rendered.body).map_err(|e| {
+        error!(
+            "Output verification failed for {} bytes of {}: {}",
+            rendered.body.len(),
+            rendered.content_type,
+            e
+        );
+        format!("Encoded output failed verification: {}", e)
+    })?;
+    info!("Output verified in {:?}", start_time.elapsed());
+
+    Ok(rendered)
+}
+
+/// Decodes and re-encodes the source image without a watermark, so the response
+/// format matches watermarked output.
+fn reencode_original(image_bytes: Bytes, webp_allowed: bool) -> Result<RenderedImage, String> {
+    let (img, input_format) = decode_image(&image_bytes)?;
+    let (input_width, input_height) = (img.width(), img.height());
+
+    let mut image = img.into_rgba8();
+    if let Some(resized) = resize_to_target_width(&image) {
+        image = resized;
+    }
+
+    let encode_start = Instant::now();
+    let (body, output_format) =
+        encode_output(&image, input_format, is_svg(&image_bytes), webp_allowed)?;
+    let body = Bytes::from(body);
+
+    Ok(RenderedImage {
+        stats: RenderStats {
+            input_format: input_format.map(format_name),
+            input_width,
+            input_height,
+            output_format: format_name(output_format),
+            output_quality: output_quality(output_format),
+            encode_ms: duration_ms(encode_start.elapsed()),
+            output_bytes: body.len(),
+            ..Default::default()
+        },
+        body,
+        content_type: output_format.to_mime_type(),
+        svg_overlay: None,
+        blurhash: None,
+    })
+}
+
+/// Picks the candidate encodings for a rendered image from `OUTPUT_FORMAT`. In `auto`
+/// mode JPEG, PNG and WebP sources keep their format and anything else becomes JPEG.
+/// `smallest` mode offers JPEG plus WebP when `webp_allowed`. Images that need
+/// transparency are never encoded as JPEG, which would flatten their alpha channel.
+fn output_formats(
+    input_format: Option<ImageFormat>,
+    needs_alpha: bool,
+    webp_allowed: bool,
+) -> Vec<ImageFormat> {
+    let formats = match CONFIG.output_format {
+        OutputFormat::Auto => match input_format {
+            Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => {
+                vec![format]
+            }
+            _ => vec![ImageFormat::Jpeg],
+        },
+        OutputFormat::Jpeg => vec![ImageFormat::Jpeg],
+        OutputFormat::Png => vec![ImageFormat::Png],
+        OutputFormat::WebP => vec![ImageFormat::WebP],
+        OutputFormat::Smallest if webp_allowed => vec![ImageFormat::Jpeg, ImageFormat::WebP],
+        OutputFormat::Smallest => vec![ImageFormat::Jpeg],
+    };
+
+    formats
+        .into_iter()
+        .map(|format| {
+            if needs_alpha && format == ImageFormat::Jpeg {
+                ImageFormat::Png
+            } else {
+                format
+            }
+        })
+        .collect()
+}
+
+/// Encodes `image` in each of the `output_formats` candidates and keeps the smallest
+/// result, returning it with the format used.
+fn encode_output(
+    image: &RgbaImage,
+    input_format: Option<ImageFormat>,
+    needs_alpha: bool,
+    webp_allowed: bool,
+) -> Result<(Vec<u8>, ImageFormat), String> {
+    let mut smallest: Option<(Vec<u8>, ImageFormat)> = None;
+    for format in output_formats(input_format, needs_alpha, webp_allowed) {
+        let body = encode_image(image, format)?;
+        if smallest
+            .as_ref()
+            .is_none_or(|(smallest_body, _)| body.len() < smallest_body.len())
+        {
+            smallest = Some((body, format));
+        }
+    }
+
+    let (body, format) =
+        smallest.ok_or_else(|| "No output format available for the image".to_string())?;
+    if CONFIG.output_format == OutputFormat::Smallest {
+        debug!(
+            "Smallest output format is {:?} at {} bytes",
+            format,
+            body.len()
+        );
+    }
+    Ok((body, format))
+}
+
+fn output_quality(format: ImageFormat) -> Option<u8> {
+    (format == ImageFormat::Jpeg).then_some(CONFIG.jpeg_quality)
+}
+
+fn encode_image(image: &RgbaImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let output_format = match format {
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(CONFIG.jpeg_quality),
+        other => ImageOutputFormat::from(other),
+    };
+
+    let mut output_buffer = Cursor::new(Vec::new());
+    image
+        .write_to(&mut output_buffer, output_format)
+        .map_err(|e| format!("Failed to encode image to {:?}: {}", format, e))?;
+    Ok(output_buffer.into_inner())
+}
+
+/// Decodes an image using the format guessed from its contents, optionally
+/// retrying every other readable format when that fails. SVG documents are
+/// rasterized when the `svg` feature is enabled and have no `ImageFormat`.
+/// With `APPLY_EXIF_ORIENTATION`, the pixels are turned upright.
+fn decode_image(image_bytes: &[u8]) -> Result<(DynamicImage, Option<ImageFormat>), String> {
+    if is_svg(image_bytes) {
+        return rasterize_svg(image_bytes).map(|img| (img, None));
+    }
+
+    let (img, format) = decode_raster(image_bytes)?;
+    if !CONFIG.apply_exif_orientation {
+        return Ok((img, format));
+    }
+    Ok((apply_exif_orientation(img, image_bytes), format))
+}
+
+fn decode_raster(image_bytes: &[u8]) -> Result<(DynamicImage, Option<ImageFormat>), String> {
+    let reader = ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("Could not guess image format: {}", e))?;
+    let guessed_format = reader.format();
+
+    let error = match reader.decode() {
+        Ok(img) => return Ok((img, guessed_format)),
+        Err(e) => e,
+    };
+
+    if CONFIG.tolerate_truncated && guessed_format == Some(ImageFormat::Jpeg) {
+        if let Some(img) = decode_truncated_jpeg(image_bytes) {
+            return Ok((img, guessed_format));
+        }
+    }
+
+    if CONFIG.decode_brute_force {
+        warn!(
+            "Failed to decode image as {:?}: {}. Trying other formats.",
+            guessed_format, error
+        );
+        for format in ImageFormat::all()
+            .filter(|format| format.reading_enabled() && Some(*format) != guessed_format)
+        {
+            if let Ok(img) = image::load_from_memory_with_format(image_bytes, format) {
+                info!("Image decoded as {:?} after format coercion", format);
+                return Ok((img, Some(format)));
+            }
+        }
+    }
+
+    Err(format!("Failed to decode image: {}", error))
+}
+
+/// Rotates and flips `img` as the EXIF orientation tag of its source says viewers
+/// display it. Images without EXIF data or with orientation 1 are returned as is.
+fn apply_exif_orientation(img: DynamicImage, image_bytes: &[u8]) -> DynamicImage {
+    let orientation = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(image_bytes))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+                .value
+                .get_uint(0)
+        });
+
+    let img = match orientation {
+        None | Some(1) => return img,
+        Some(2) => img.fliph(),
+        Some(3) => img.rotate180(),
+        Some(4) => img.flipv(),
+        Some(5) => img.rotate90().fliph(),
+        Some(6) => img.rotate90(),
+        Some(7) => img.rotate270().fliph(),
+        Some(8) => img.rotate270(),
+        Some(other) => {
+            warn!("Ignoring invalid EXIF orientation {}", other);
+            return img;
+        }
+    };
+    debug!(
+        "Applied EXIF orientation {}, image is now {}x{}",
+        orientation.unwrap_or(1),
+        img.width(),
+        img.height()
+    );
+    img
+}
+
+/// Decodes a JPEG cut off before its end-of-image marker by appending one. The
+/// decoder then fills the missing rows in gray, so most of the image survives.
+fn decode_truncated_jpeg(image_bytes: &[u8]) -> Option<DynamicImage> {
+    const END_OF_IMAGE: [u8; 2] = [0xFF, 0xD9];
+    if image_bytes.ends_with(&END_OF_IMAGE) {
+        return None;
+    }
+
+    let mut patched = Vec::with_capacity(image_bytes.len() + END_OF_IMAGE.len());
+    patched.extend_from_slice(image_bytes);
+    patched.extend_from_slice(&END_OF_IMAGE);
+    let img = image::load_from_memory_with_format(&patched, ImageFormat::Jpeg).ok()?;
+    warn!(
+        "Decoded truncated JPEG of {} bytes with missing rows filled in gray, image data is degraded",
+        image_bytes.len()
+    );
+    Some(img)
+}
+
+/// Rejects inputs whose guessed format is not in `SUPPORTED_INPUT_FORMATS` before the
+/// expensive decode. SVG passes only with the `svg` feature, and formats that cannot be
+/// guessed pass when `DECODE_BRUTE_FORCE` is enabled.
+fn check_supported_media(image_bytes: &[u8]) -> Result<(), String> {
+    if is_svg(image_bytes) {
+        return if cfg!(feature = "svg") {
+            Ok(())
+        } else {
+            Err(format!(
+                "{}: SVG input requires the `svg` feature",
+                UNSUPPORTED_MEDIA_ERROR
+            ))
+        };
+    }
+
+    match image::guess_format(image_bytes) {
+        Ok(format) if SUPPORTED_INPUT_FORMATS.contains(&format) => Ok(()),
+        Ok(format) if !CONFIG.decode_brute_force => Err(format!(
+            "{}: {} images are not supported",
+            UNSUPPORTED_MEDIA_ERROR,
+            format_name(format)
+        )),
+        Err(_) if !CONFIG.decode_brute_force => Err(format!(
+            "{}: input is not a recognized image",
+            UNSUPPORTED_MEDIA_ERROR
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Watermarks every frame of an animated GIF with the same tiles, keeping frame delays
+/// and the loop count. Still GIFs and empty texts go through `add_watermark`.
+#[cfg(feature = "gif")]
+fn add_watermark_gif(
+    image_bytes: Bytes,
+    watermark_text: &str,
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    fallback_fonts: &[Font<'static>],
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+    webp_allowed: bool,
+) -> Result<RenderedImage, String> {
+    use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+    use image::{AnimationDecoder, Frame};
+
+    if watermark_text.is_empty() {
+        return add_watermark(
+            image_bytes,
+            watermark_text,
+            watermark_font_ref,
+            fallback_fonts,
+            watermark_logo,
+            style,
+            webp_allowed,
+        );
+    }
+
+    let start_time = Instant::now();
+    let decoder = GifDecoder::new(Cursor::new(&image_bytes))
+        .map_err(|e| format!("Failed to decode image: {}", e))?;
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .map_err(|e| format!("Failed to decode image: {}", e))?;
+    if frames.len() < 2 {
+        return add_watermark(
+            image_bytes,
+            watermark_text,
+            watermark_font_ref,
+            fallback_fonts,
+            watermark_logo,
+            style,
+            webp_allowed,
+        );
+    }
+
+    let mut gif_reader = gif::DecodeOptions::new()
+        .read_info(Cursor::new(&image_bytes))
+        .map_err(|e| format!("Failed to decode image: {}", e))?;
+    // The loop count extension may follow the first frame, so read past it
+    let _ = gif_reader.next_frame_info();
+    let repeat = match gif_reader.repeat() {
+        gif::Repeat::Finite(count) => Repeat::Finite(count),
+        gif::Repeat::Infinite => Repeat::Infinite,
+    };
+
+    let (input_width, input_height) = frames[0].buffer().dimensions();
+    info!(
+        "Animated GIF decoded: {} frames of {}x{} pixels",
+        frames.len(),
+        input_width,
+        input_height
+    );
+
+    let mut frames: Vec<(RgbaImage, image::Delay)> = frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            let mut buffer = frame.into_buffer();
+            if CONFIG.resize_before_watermark {
+                if let Some(resized) = resize_to_target_width(&buffer) {
+                    buffer = resized;
+                }
+            }
+            (buffer, delay)
+        })
+        .collect();
+    let (width, height) = frames[0].0.dimensions();
+
+    let fonts = current_fonts(watermark_font_ref, fallback_fonts)?;
+    let font = fonts.primary();
+    let adaptive = adaptive_style(style, watermark_logo);
+    let style = adaptive.as_ref().unwrap_or(style);
+    let layer_start = Instant::now();
+    let (tile_layer, glyph_draws, layout) =
+        build_tile_layer(width, height, watermark_text, &fonts, watermark_logo, style)?;
+    let label_layer = CONFIG
+        .layered_watermark
+        .then(|| build_label_layer(width, height, watermark_text, font));
+    let banner_layer = CONFIG
+        .banner_text
+        .as_deref()
+        .map(|text| build_banner_layer(width, height, text, font));
+    let layer_build_duration = layer_start.elapsed();
+
+    let blend_start = Instant::now();
+    for (buffer, _) in &mut frames {
+        tile_layer.blend_onto(buffer, adaptive.is_some());
+        if let Some(label_layer) = &label_layer {
+            blend_layer(buffer, label_layer);
+        }
+        if let Some(banner_layer) = &banner_layer {
+            blend_layer(buffer, banner_layer);
+        }
+    }
+    let blend_duration = blend_start.elapsed();
+
+    let encode_start = Instant::now();
+    let mut body = Vec::new();
+    let frames = frames
+        .into_iter()
+        .map(|(mut buffer, delay)| {
+            if !CONFIG.resize_before_watermark {
+                if let Some(resized) = resize_to_target_width(&buffer) {
+                    buffer = resized;
+                }
+            }
+            if CONFIG.border_width > 0 {
+                buffer = add_border(&buffer, CONFIG.border_width, CONFIG.border_color)?;
+            }
+            Ok(Frame::from_parts(buffer, 0, 0, delay))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    {
+        let mut encoder = GifEncoder::new(&mut body);
+        encoder
+            .set_repeat(repeat)
+            .map_err(|e| format!("Failed to encode GIF: {}", e))?;
+        encoder
+            .encode_frames(frames)
+            .map_err(|e| format!("Failed to encode GIF: {}", e))?;
+    }
+    info!(
+        "Watermark added to animated GIF and encoded in {:?}",
+        start_time.elapsed()
+    );
+
+    let stats = RenderStats {
+        input_format: Some(format_name(ImageFormat::Gif)),
+        input_width,
+        input_height,
+        output_format: format_name(ImageFormat::Gif),
+        glyph_draws,
+        layout_cols: layout.chars_per_row,
+        layout_rows: layout.rows,
+        layout_scale: layout.scale.y,
+        layer_build_ms: duration_ms(layer_build_duration),
+        blend_ms: duration_ms(blend_duration),
+        encode_ms: duration_ms(encode_start.elapsed()),
+        output_bytes: body.len(),
+        ..Default::default()
+    };
+    Ok(RenderedImage {
+        body: Bytes::from(body),
+        content_type: ImageFormat::Gif.to_mime_type(),
+        svg_overlay: None,
+        blurhash: None,
+        stats,
+    })
+}
+
+/// Runs the `PLUGIN_PATH` plugin on the image, if one is loaded.
+#[cfg(feature = "plugins")]
+fn apply_plugin(image: &mut RgbaImage) -> Result<(), String> {
+    let Some(plugin) = PLUGIN.get() else {
+        return Ok(());
+    };
+    let start_time = Instant::now();
+    plugin.apply(image)?;
+    debug!("Plugin applied in {:?}", start_time.elapsed());
+    Ok(())
+}
+
+/// The downloaded bytes as they are, labeled with the type sniffed from their content.
+fn passthrough_image(image_bytes: Bytes) -> RenderedImage {
+    let content_type = if is_svg(&image_bytes) {
+        "image/svg+xml"
+    } else {
+        image::guess_format(&image_bytes)
+            .map(|format| format.to_mime_type())
+            .unwrap_or("application/octet-stream")
+    };
+    RenderedImage {
+        stats: RenderStats {
+            output_format: "original".to_string(),
+            output_bytes: image_bytes.len(),
+            ..Default::default()
+        },
+        body: image_bytes,
+        content_type,
+        svg_overlay: None,
+        blurhash: None,
+    }
+}
+
+/// Recognizes SVG documents, which the `image` crate cannot guess, by their
+/// leading `<svg` element or an XML declaration followed by one.
+fn is_svg(image_bytes: &[u8]) -> bool {
+    let head = &image_bytes[..image_bytes.len().min(1024)];
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    text.starts_with("<svg") || (text.starts_with("<?xml") && text.contains("<svg"))
+}
+
+/// Renders an SVG document to `SVG_RENDER_WIDTH` pixels wide, keeping its aspect ratio.
+#[cfg(feature = "svg")]
+fn rasterize_svg(image_bytes: &[u8]) -> Result<DynamicImage, String> {
+    use resvg::{tiny_skia, usvg};
+
+    let mut options = usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+    let tree = usvg::Tree::from_data(image_bytes, &options)
+        .map_err(|e| format!("Failed to parse SVG: {}", e))?;
+
+    let size = tree.size();
+    let scale = CONFIG.svg_render_width as f32 / size.width();
+    let width = CONFIG.svg_render_width;
+    let height = ((size.height() * scale).round() as u32).max(1);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| format!("Invalid SVG render size {}x{}", width, height))?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    info!(
+        "Rasterized {}x{} SVG to {}x{} pixels",
+        size.width(),
+        size.height(),
+        width,
+        height
+    );
+
+    // tiny-skia stores premultiplied alpha, the image crate expects straight alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "Failed to build image from rasterized SVG".to_string())
+}
+
+#[cfg(not(feature = "svg"))]
+fn rasterize_svg(_image_bytes: &[u8]) -> Result<DynamicImage, String> {
+    Err("SVG input is not supported; build with the `svg` feature to rasterize it".to_string())
+}
+
+fn render_image(
+    image_bytes: Bytes,
+    watermark_text: &str,
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    fallback_fonts: &[Font<'static>],
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+    webp_allowed: bool,
+) -> Result<RenderedImage, String> {
+    match CONFIG.watermark_output {
+        #[cfg(feature = "gif")]
+        WatermarkOutput::Image
+            if image::guess_format(&image_bytes).ok() == Some(ImageFormat::Gif) =>
+        {
+            add_watermark_gif(
+                image_bytes,
+                watermark_text,
+                watermark_font_ref,
+                fallback_fonts,
+                watermark_logo,
+                style,
+                webp_allowed,
+            )
+        }
+        WatermarkOutput::Image => add_watermark(
+            image_bytes,
+            watermark_text,
+            watermark_font_ref,
+            fallback_fonts,
+            watermark_logo,
+            style,
+            webp_allowed,
+        ),
+        WatermarkOutput::SvgOverlay => {
+            let reader = ImageReader::new(Cursor::new(&image_bytes))
+                .with_guessed_format()
+                .map_err(|e| format!("Could not guess image format: {}", e))?;
+            let input_format = reader.format();
+            let content_type = input_format
+                .map(|format| format.to_mime_type())
+                .unwrap_or("application/octet-stream");
+            let (width, height) = reader
+                .into_dimensions()
+                .map_err(|e| format!("Failed to read image dimensions: {}", e))?;
+
+            let svg_overlay = if watermark_text.is_empty() {
+                warn!("Watermark text is empty, returning original image without overlay.");
+                None
+            } else {
+                let fonts = current_fonts(watermark_font_ref, fallback_fonts)?;
+                Some(build_svg_overlay(
+                    width,
+                    height,
+                    watermark_text,
+                    &fonts,
+                    style,
+                ))
+            };
+
+            Ok(RenderedImage {
+                stats: RenderStats {
+                    input_format: input_format.map(format_name),
+                    input_width: width,
+                    input_height: height,
+                    output_format: input_format
+                        .map(format_name)
+                        .unwrap_or_else(|| "unknown".to_string()),
+                    output_bytes: image_bytes.len(),
+                    ..Default::default()
+                },
+                body: image_bytes.clone(),
+                content_type,
+                svg_overlay,
+                blurhash: None,
+            })
+        }
+        WatermarkOutput::AlphaMask => render_alpha_mask(
+            &image_bytes,
+            watermark_text,
+            watermark_font_ref,
+            fallback_fonts,
+            style,
+        ),
+    }
+}
+
+/// Renders only the watermark layer, as a PNG with the source dimensions that is
+/// transparent everywhere except the watermark and its shadow.
+fn render_alpha_mask(
+    image_bytes: &[u8],
+    watermark_text: &str,
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    fallback_fonts: &[Font<'static>],
+    style: &WatermarkStyle,
+) -> Result<RenderedImage, String> {
+    let reader = ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("Could not guess image format: {}", e))?;
+    let input_format = reader.format();
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| format!("Failed to read image dimensions: {}", e))?;
+
+    let layer_start = Instant::now();
+    let (watermark_layer, glyph_draws, layout) = if watermark_text.is_empty() {
+        warn!("Watermark text is empty, returning an empty alpha mask.");
+        (RgbaImage::new(width, height), 0, None)
+    } else {
+        let fonts = current_fonts(watermark_font_ref, fallback_fonts)?;
+        let (layer, glyph_draws, layout) =
+            build_tiled_layer(width, height, watermark_text, &fonts, None, style)?;
+        (layer, glyph_draws, Some(layout))
+    };
+    let layer_build_duration = layer_start.elapsed();
+
+    let encode_start = Instant::now();
+    let mut output_buffer = Cursor::new(Vec::new());
+    watermark_layer
+        .write_to(&mut output_buffer, ImageOutputFormat::Png)
+        .map_err(|e| format!("Failed to encode alpha mask to PNG: {}", e))?;
+    let body = Bytes::from(output_buffer.into_inner());
+
+    Ok(RenderedImage {
+        stats: RenderStats {
+            input_format: input_format.map(format_name),
+            input_width: width,
+            input_height: height,
+            output_format: "png".to_string(),
+            glyph_draws,
+            layout_cols: layout.as_ref().map_or(0, |layout| layout.chars_per_row),
+            layout_rows: layout.as_ref().map_or(0, |layout| layout.rows),
+            layout_scale: layout.as_ref().map_or(0.0, |layout| layout.scale.y),
+            layer_build_ms: duration_ms(layer_build_duration),
+            encode_ms: duration_ms(encode_start.elapsed()),
+            output_bytes: body.len(),
+            ..Default::default()
+        },
+        body,
+        content_type: "image/png",
+        svg_overlay: None,
+        blurhash: None,
+    })
+}
+
+fn current_font(
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+) -> Result<Font<'static>, String> {
+    read_font(watermark_font_ref, CONFIG.font_poison_policy)?
+        .ok_or_else(|| "Font not available (failed to load?)".to_string())
+}
+
+/// The font in the lock, handling a poisoned lock according to `policy`.
+fn read_font(
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    policy: FontPoisonPolicy,
+) -> Result<Option<Font<'static>>, String> {
+    match watermark_font_ref.read() {
+        Ok(guard) => Ok(guard.as_ref().cloned()),
+        Err(poisoned) => {
+            let font = poisoned.into_inner().as_ref().cloned();
+            recover_poisoned_font(watermark_font_ref, font, policy)
+        }
+    }
+}
+
+/// The current font followed by the `FONT_FALLBACK_PATHS` fonts, for the tiles.
+fn current_fonts(
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    fallback_fonts: &[Font<'static>],
+) -> Result<FontChain, String> {
+    Ok(FontChain::new(
+        current_font(watermark_font_ref)?,
+        fallback_fonts.to_vec(),
+    ))
+}
+
+/// Handles a poisoned font lock according to `policy`, from `FONT_POISON_POLICY`,
+/// returning the font to render with. Without recovery every later read would fail and
+/// rendering would stay broken until the process restarts.
+fn recover_poisoned_font(
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    font: Option<Font<'static>>,
+    policy: FontPoisonPolicy,
+) -> Result<Option<Font<'static>>, String> {
+    match policy {
+        FontPoisonPolicy::Fail => Err("Failed to acquire read lock on font".to_string()),
+        FontPoisonPolicy::Recover => {
+            warn!("Font lock was poisoned, clearing it and keeping the current font");
+            watermark_font_ref.clear_poison();
+            Ok(font)
+        }
+        FontPoisonPolicy::Reload => {
+            warn!("Font lock was poisoned, reloading the font");
+            let reloaded = load_font()?;
+            let mut guard = watermark_font_ref
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            *guard = Some(reloaded.clone());
+            watermark_font_ref.clear_poison();
+            Ok(Some(reloaded))
+        }
+    }
+}
+
+/// Splits the watermark text into the user-perceived characters (grapheme clusters)
+/// drawn per tile, so combining marks, emoji sequences and flags stay in one tile.
+/// Text with right-to-left runs is returned in visual order, so Arabic and Hebrew read
+/// correctly across a row. Graphemes with a character the font has no glyph for are
+/// replaced with `MISSING_GLYPH_REPLACEMENT`. The lines of the text are joined with
+/// spaces, for the marks drawn only once, which do not use the fallback fonts.
+fn watermark_chars(watermark_text: &str, font: &Font<'static>) -> Vec<String> {
+    let mut chars = match CONFIG.watermark_line_sep.as_deref() {
+        Some(separator) => visual_graphemes(
+            &watermark_text
+                .split(separator)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        None => visual_graphemes(watermark_text),
+    };
+    replace_missing_glyphs(&mut chars, &FontChain::new(font.clone(), Vec::new()));
+    chars
+}
+
+/// Like `watermark_chars`, but keeps the lines separated by `WATERMARK_LINE_SEP` apart
+/// for the tiles: returns the graphemes of every line, one line after the other, with
+/// the number of graphemes in each line. Only graphemes no font of `fonts` has glyphs
+/// for are replaced.
+fn watermark_lines(watermark_text: &str, fonts: &FontChain) -> (Vec<String>, Vec<usize>) {
+    let (mut chars, line_lengths) =
+        visual_lines(watermark_text, CONFIG.watermark_line_sep.as_deref());
+    replace_missing_glyphs(&mut chars, fonts);
+    (chars, line_lengths)
+}
+
+fn replace_missing_glyphs(chars: &mut [String], fonts: &FontChain) {
+    let Some(replacement) = CONFIG.missing_glyph_replacement else {
+        return;
+    };
+
+    let mut missing = 0;
+    for grapheme in chars {
+        if !fonts.has_glyphs(grapheme) {
+            missing += 1;
+            *grapheme = replacement.to_string();
+        }
+    }
+    if missing > 0 {
+        warn!(
+            "Font has no glyph for {} character(s) of the watermark text, replaced with '{}'",
+            missing, replacement
+        );
+    }
+}
+
+/// Builds the tiled layer from the `WATERMARK_IMAGE_PATH` logo when one is loaded, or
+/// from the glyphs of the watermark text otherwise.
+fn build_tiled_layer(
+    width: u32,
+    height: u32,
+    watermark_text: &str,
+    fonts: &FontChain,
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+) -> Result<(RgbaImage, usize, WatermarkLayout), String> {
+    if CONFIG.watermark_mode == WatermarkMode::Center {
+        return Ok(build_centered_layer(
+            width,
+            height,
+            watermark_text,
+            fonts.primary(),
+            watermark_logo,
+            style,
+        ));
+    }
+    if !fits_one_glyph(width, height, watermark_logo, style) {
+        match CONFIG.tiny_image_mode {
+            TinyImageMode::Skip => {
+                info!(
+                    "Image {}x{} is smaller than a single watermark glyph, skipping the watermark",
+                    width, height
+                );
+                let layout =
+                    WatermarkLayout::new(width, height, &[], (0.0, 0.0), style, &CONFIG.layout);
+                return Ok((RgbaImage::new(width, height), 0, layout));
+            }
+            TinyImageMode::Fit => {
+                info!(
+                    "Image {}x{} is smaller than a single watermark glyph, drawing one fitted mark",
+                    width, height
+                );
+                return Ok(build_fitted_mark(
+                    width,
+                    height,
+                    watermark_text,
+                    fonts.primary(),
+                    watermark_logo,
+                    style,
+                ));
+            }
+            TinyImageMode::Error => {
+                return Err(format!(
+                    "Image {}x{} is too small for a single watermark glyph",
+                    width, height
+                ));
+            }
+        }
+    }
+
+    if let Some(logo) = watermark_logo {
+        return Ok(build_logo_layer(width, height, logo, style));
+    }
+
+    let (chars, line_lengths) = watermark_lines(watermark_text, fonts);
+    let layout = WatermarkLayout::new(
+        width,
+        height,
+        &line_lengths,
+        glyph_extent(&chars, fonts, &CONFIG.layout),
+        style,
+        &CONFIG.layout,
+    );
+    let (layer, glyph_draws) =
+        build_watermark_layer(width, height, &chars, fonts, &layout, style, &CONFIG.layout);
+    Ok((layer, glyph_draws, layout))
+}
+
+/// The tiles of the watermark, as a full layer or as a mask in one color.
+enum TileLayer {
+    Rgba(RgbaImage),
+    Mask(GrayImage, Rgba<u8>),
+}
+
+impl TileLayer {
+    /// Blends the tiles onto the image, inverting their colors over its light regions
+    /// when `adaptive`, for tiles rendered with `adaptive_style`.
+    fn blend_onto(&self, base_image: &mut RgbaImage, adaptive: bool) {
+        if !adaptive {
+            match self {
+                TileLayer::Rgba(layer) => blend_layer(base_image, layer),
+                TileLayer::Mask(mask, color) => blend_mask(base_image, mask, *color),
+            }
+            return;
+        }
+
+        let grid_size = match CONFIG.watermark_adaptive {
+            AdaptiveColor::Grid => CONFIG.adaptive_grid_size,
+            AdaptiveColor::Image | AdaptiveColor::Off => 1,
+        };
+        let regions = LightRegions::new(base_image, grid_size, grid_size);
+        match self {
+            TileLayer::Rgba(layer) => blend_layer_adaptive(base_image, layer, &regions),
+            TileLayer::Mask(mask, color) => blend_mask_adaptive(base_image, mask, *color, &regions),
+        }
+    }
+}
+
+/// The style to render the tiles with under `WATERMARK_ADAPTIVE`: white at the
+/// configured alpha, turned black over light regions by `TileLayer::blend_onto`.
+/// Returns `None` when the mode is off, or for logo tiles, which keep their colors.
+fn adaptive_style(
+    style: &WatermarkStyle,
+    watermark_logo: Option<&RgbaImage>,
+) -> Option<WatermarkStyle> {
+    if CONFIG.watermark_adaptive == AdaptiveColor::Off || watermark_logo.is_some() {
+        return None;
+    }
+    Some(WatermarkStyle {
+        color: Rgba([255, 255, 255, style.color[3]]),
+        ..*style
+    })
+}
+
+/// Builds the tiles to blend onto the image, as a single-channel mask when
+/// `ALPHA_MASK_LAYER` is enabled and the tiles have one color, or as a full layer.
+fn build_tile_layer(
+    width: u32,
+    height: u32,
+    watermark_text: &str,
+    fonts: &FontChain,
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+) -> Result<(TileLayer, usize, WatermarkLayout), String> {
+    if let Some((mask, glyph_draws, layout)) =
+        build_tile_mask(width, height, watermark_text, fonts, watermark_logo, style)
+    {
+        return Ok((TileLayer::Mask(mask, style.color), glyph_draws, layout));
+    }
+    let (layer, glyph_draws, layout) =
+        build_tiled_layer(width, height, watermark_text, fonts, watermark_logo, style)?;
+    Ok((TileLayer::Rgba(layer), glyph_draws, layout))
+}
+
+/// Draws the tiles as a mask, or returns `None` when they need more than one color or
+/// are not a plain text grid: with a logo, a visible shadow of another color,
+/// `WATERMARK_MODE=center` or an image too small for one glyph.
+fn build_tile_mask(
+    width: u32,
+    height: u32,
+    watermark_text: &str,
+    fonts: &FontChain,
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+) -> Option<(GrayImage, usize, WatermarkLayout)> {
+    if !CONFIG.alpha_mask_layer
+        || watermark_logo.is_some()
+        || CONFIG.watermark_mode != WatermarkMode::Tile
+        || !fits_one_glyph(width, height, None, style)
+    {
+        return None;
+    }
+    if !is_single_color(style, &CONFIG.layout) {
+        debug!("Shadow color differs from the watermark color, using a full RGBA layer");
+        return None;
+    }
+
+    let (chars, line_lengths) = watermark_lines(watermark_text, fonts);
+    let layout = WatermarkLayout::new(
+        width,
+        height,
+        &line_lengths,
+        glyph_extent(&chars, fonts, &CONFIG.layout),
+        style,
+        &CONFIG.layout,
+    );
+    let (mask, glyph_draws) =
+        build_watermark_mask(width, height, &chars, fonts, &layout, style, &CONFIG.layout);
+    Some((mask, glyph_draws, layout))
+}
+
+/// Layout of the single mark drawn by `WATERMARK_MODE=center`: the text at the font
+/// height, shrunk to fit within 90% of the image width when it is longer.
+fn centered_layout(
+    width: u32,
+    height: u32,
+    text: &str,
+    font: &Font<'static>,
+    style: &WatermarkStyle,
+) -> WatermarkLayout {
+    let font_height = CONFIG.layout.font_height(height, style);
+    let mut scale = Scale {
+        x: font_height * CONFIG.layout.font_width_ratio,
+        y: font_height,
+    };
+    let (text_width, _) = text_size(scale, font, text);
+    let max_width = width as f32 * 0.9;
+    if text_width as f32 > max_width {
+        let factor = max_width / text_width as f32;
+        scale = Scale {
+            x: scale.x * factor,
+            y: scale.y * factor,
+        };
+    }
+
+    let mut layout = WatermarkLayout::with_spacing(
+        width,
+        height,
+        scale,
+        width as f32,
+        height as f32,
+        &CONFIG.layout,
+    );
+    layout.rows = 1;
+    layout
+}
+
+/// Draws the text, or the logo, once in the middle of the image for
+/// `WATERMARK_MODE=center`, with its shadow, rotated by the watermark angle.
+fn build_centered_layer(
+    width: u32,
+    height: u32,
+    watermark_text: &str,
+    font: &Font<'static>,
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+) -> (RgbaImage, usize, WatermarkLayout) {
+    let (canvas_width, canvas_height) = layer_canvas_size(width, height, style.angle);
+    let mut layer = RgbaImage::new(canvas_width, canvas_height);
+
+    if let Some(logo) = watermark_logo {
+        let font_height = CONFIG.layout.font_height(height, style);
+        let factor =
+            (font_height / logo.height() as f32).min(width as f32 * 0.9 / logo.width() as f32);
+        let logo_width = ((logo.width() as f32 * factor).round() as u32).max(1);
+        let logo_height = ((logo.height() as f32 * factor).round() as u32).max(1);
+        let tile = faded_logo(logo, logo_width, logo_height, style.color[3]);
+        imageops::overlay(
+            &mut layer,
+            &tile,
+            (canvas_width as i64 - logo_width as i64) / 2,
+            (canvas_height as i64 - logo_height as i64) / 2,
+        );
+        let scale = Scale {
+            x: logo_width as f32,
+            y: logo_height as f32,
+        };
+        let mut layout = WatermarkLayout::with_spacing(
+            width,
+            height,
+            scale,
+            width as f32,
+            height as f32,
+            &CONFIG.layout,
+        );
+        layout.rows = 1;
+        return (
+            finish_layer(layer, width, height, style.angle, &CONFIG.layout),
+            1,
+            layout,
+        );
+    }
+
+    let text = watermark_chars(watermark_text, font).concat();
+    let layout = centered_layout(width, height, &text, font, style);
+    let (text_width, text_height) = text_size(layout.scale, font, &text);
+    let x_pos = (canvas_width as i32 - text_width) / 2;
+    let y_pos = (canvas_height as i32 - text_height) / 2;
+    let mut glyph_draws = 1;
+    if CONFIG.layout.shadow_enabled {
+        draw_text_mut(
+            &mut layer,
+            CONFIG.layout.shadow_color,
+            x_pos + layout.shadow_offset_x,
+            y_pos + layout.shadow_offset_y,
+            layout.scale,
+            font,
+            &text,
+        );
+        if CONFIG.layout.shadow_blur_sigma > 0.0 {
+            layer = gaussian_blur_f32(&layer, CONFIG.layout.shadow_blur_sigma);
+        }
+        glyph_draws += 1;
+    }
+    draw_text_mut(
+        &mut layer,
+        style.color,
+        x_pos,
+        y_pos,
+        layout.scale,
+        font,
+        &text,
+    );
+    (
+        finish_layer(layer, width, height, style.angle, &CONFIG.layout),
+        glyph_draws,
+        layout,
+    )
+}
+
+/// Whether a single tiled glyph, or logo copy, fits in the image. Smaller images are
+/// handled by `TINY_IMAGE_MODE`.
+fn fits_one_glyph(
+    width: u32,
+    height: u32,
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+) -> bool {
+    let font_height = CONFIG.layout.font_height(height, style);
+    let glyph_width = match watermark_logo {
+        Some(logo) => logo.width() as f32 * font_height / logo.height() as f32,
+        None => font_height * CONFIG.layout.font_width_ratio,
+    };
+    font_height <= height as f32 && glyph_width <= width as f32
+}
+
+/// Draws the text, or the logo, once in the center of an image too small for a
+/// single tiled glyph, scaled to fit. Used by `TINY_IMAGE_MODE=fit`.
+fn build_fitted_mark(
+    width: u32,
+    height: u32,
+    watermark_text: &str,
+    font: &Font<'static>,
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+) -> (RgbaImage, usize, WatermarkLayout) {
+    // Leave a small margin so the mark does not touch the edges
+    let (max_width, max_height) = (width as f32 * 0.9, height as f32 * 0.9);
+    let mut layer = RgbaImage::new(width, height);
+
+    let scale = if let Some(logo) = watermark_logo {
+        let factor = (max_width / logo.width() as f32).min(max_height / logo.height() as f32);
+        let logo_width = ((logo.width() as f32 * factor).round() as u32).max(1);
+        let logo_height = ((logo.height() as f32 * factor).round() as u32).max(1);
+        let tile = faded_logo(logo, logo_width, logo_height, style.color[3]);
+        imageops::overlay(
+            &mut layer,
+            &tile,
+            (width as i64 - logo_width as i64) / 2,
+            (height as i64 - logo_height as i64) / 2,
+        );
+        Scale {
+            x: logo_width as f32,
+            y: logo_height as f32,
+        }
+    } else {
+        draw_centered_text(
+            &mut layer,
+            watermark_text,
+            font,
+            style.color,
+            (max_width, max_height),
+        )
+    };
+
+    let mut layout = WatermarkLayout::with_spacing(
+        width,
+        height,
+        scale,
+        width as f32,
+        height as f32,
+        &CONFIG.layout,
+    );
+    layout.rows = 1;
+    (layer, 1, layout)
+}
+
+/// Draws `text` once in the center of `layer`, in visual order, as large as fits in
+/// `max_size` while keeping the `FONT_WIDTH_RATIO` proportions. Returns the scale it
+/// was drawn at.
+fn draw_centered_text(
+    layer: &mut RgbaImage,
+    text: &str,
+    font: &Font<'static>,
+    color: Rgba<u8>,
+    (max_width, max_height): (f32, f32),
+) -> Scale {
+    let text = &watermark_chars(text, font).concat();
+    let unit = Scale {
+        x: 100.0 * CONFIG.layout.font_width_ratio,
+        y: 100.0,
+    };
+    let (unit_width, unit_height) = text_size(unit, font, text);
+    let factor = (max_width / unit_width.max(1) as f32).min(max_height / unit_height.max(1) as f32);
+    let scale = Scale {
+        x: unit.x * factor,
+        y: unit.y * factor,
+    };
+    let (text_width, text_height) = text_size(scale, font, text);
+    draw_text_mut(
+        layer,
+        color,
+        (layer.width() as i32 - text_width) / 2,
+        (layer.height() as i32 - text_height) / 2,
+        scale,
+        font,
+        text,
+    );
+    scale
+}
+
+/// Tiles the logo with the same spacing, stagger and offsets as text, scaled to the
+/// font height and faded to the watermark color's alpha. Returns the number of logo
+/// copies drawn in place of glyph draws.
+fn build_logo_layer(
+    width: u32,
+    height: u32,
+    logo: &RgbaImage,
+    style: &WatermarkStyle,
+) -> (RgbaImage, usize, WatermarkLayout) {
+    let font_height = CONFIG.layout.font_height(height, style);
+    let logo_height = (font_height.round() as u32).max(1);
+    let logo_width =
+        ((logo.width() as f32 * logo_height as f32 / logo.height() as f32).round() as u32).max(1);
+    let tile = faded_logo(logo, logo_width, logo_height, style.color[3]);
+
+    let (canvas_width, canvas_height) = layer_canvas_size(width, height, style.angle);
+    let scale = Scale {
+        x: logo_width as f32,
+        y: logo_height as f32,
+    };
+    let (char_spacing_x, char_spacing_y) = aspect_adaptive_spacing(
+        canvas_width,
+        canvas_height,
+        scale,
+        logo_width as f32 * CONFIG.layout.char_spacing_x_ratio,
+        logo_height as f32 * CONFIG.layout.char_spacing_y_ratio,
+        &CONFIG.layout,
+    );
+    let layout = WatermarkLayout::with_spacing(
+        canvas_width,
+        canvas_height,
+        scale,
+        char_spacing_x,
+        char_spacing_y,
+        &CONFIG.layout,
+    );
+
+    let mut layer = RgbaImage::new(canvas_width, canvas_height);
+    let mut draws = 0;
+    for row in 0..layout.rows {
+        for col in 0..layout.chars_per_row {
+            let (x_pos, y_pos) = layout.position(row, col);
+            imageops::overlay(&mut layer, &tile, x_pos as i64, y_pos as i64);
+            draws += 1;
+        }
+    }
+
+    (
+        finish_layer(layer, width, height, style.angle, &CONFIG.layout),
+        draws,
+        layout,
+    )
+}
+
+/// Scales the logo to `width` x `height` and fades it to the watermark color's `alpha`.
+fn faded_logo(logo: &RgbaImage, width: u32, height: u32, alpha: u8) -> RgbaImage {
+    let mut tile = imageops::resize(logo, width, height, CONFIG.resize_filter);
+    let alpha = alpha as u32;
+    for pixel in tile.pixels_mut() {
+        pixel[3] = ((pixel[3] as u32 * alpha + 127) / 255) as u8;
+    }
+    tile
+}
+
+/// Makes every pixel within `tolerance` of `color` on each RGB channel fully transparent.
+fn apply_chroma_key(image: &mut RgbaImage, color: [u8; 3], tolerance: u8) {
+    let mut keyed = 0;
+    for pixel in image.pixels_mut() {
+        if (0..3).all(|i| pixel[i].abs_diff(color[i]) <= tolerance) {
+            pixel[3] = 0;
+            keyed += 1;
+        }
+    }
+    info!("Chroma key made {} pixels transparent", keyed);
+}
+
+/// Renders `BANNER_TEXT` as a single large label through the image center, rotated
+/// counterclockwise by `BANNER_ANGLE` degrees, on a transparent layer.
+fn build_banner_layer(width: u32, height: u32, text: &str, font: &Font<'static>) -> RgbaImage {
+    let text = &watermark_chars(text, font).concat();
+    // Draw on a square canvas as wide as the image diagonal so the label is not
+    // clipped before rotation, then crop the centered image-sized region.
+    let diagonal = ((width as f32).hypot(height as f32)).ceil() as u32;
+    let mut canvas = RgbaImage::new(diagonal, diagonal);
+
+    // Size the label to span most of the diagonal, without growing taller than
+    // a fraction of the shorter image side.
+    let (unit_width, _) = text_size(Scale::uniform(100.0), font, text);
+    let max_height = width.min(height) as f32 * 0.3;
+    let font_height = (diagonal as f32 * 0.7 / unit_width.max(1) as f32 * 100.0).min(max_height);
+    let scale = Scale::uniform(font_height.max(1.0));
+
+    let (text_width, text_height) = text_size(scale, font, text);
+    draw_text_mut(
+        &mut canvas,
+        CONFIG.banner_color,
+        (diagonal as i32 - text_width) / 2,
+        (diagonal as i32 - text_height) / 2,
+        scale,
+        font,
+        text,
+    );
+
+    // rotate_about_center turns clockwise, so negate for a rising banner
+    let rotated = rotate_about_center(
+        &canvas,
+        -CONFIG.banner_angle.to_radians(),
+        CONFIG.layout.rotation_interpolation,
+        Rgba([0, 0, 0, 0]),
+    );
+    imageops::crop_imm(
+        &rotated,
+        (diagonal - width) / 2,
+        (diagonal - height) / 2,
+        width,
+        height,
+    )
+    .to_image()
+}
+
+/// Draws the watermark text once as a large centered label for `LAYERED_WATERMARK`,
+/// spanning `LAYERED_LABEL_SCALE` of the image width and at most a third of its height.
+fn build_label_layer(width: u32, height: u32, text: &str, font: &Font<'static>) -> RgbaImage {
+    let mut layer = RgbaImage::new(width, height);
+    draw_centered_text(
+        &mut layer,
+        text,
+        font,
+        CONFIG.layered_label_color,
+        (
+            width as f32 * CONFIG.layered_label_scale,
+            height as f32 / 3.0,
+        ),
+    );
+    layer
+}
+
+fn add_watermark(
+    image_bytes: Bytes,
+    watermark_text: &str,
+    watermark_font_ref: &Arc<RwLock<Option<Font<'static>>>>,
+    fallback_fonts: &[Font<'static>],
+    watermark_logo: Option<&RgbaImage>,
+    style: &WatermarkStyle,
+    webp_allowed: bool,
+) -> Result<RenderedImage, String> {
+    let start_time = Instant::now();
+
+    if watermark_text.is_empty() {
+        warn!("Watermark text is empty, returning original image bytes.");
+        return Ok(passthrough_image(image_bytes));
+    }
 
-            let watermark_alpha = watermark_pixel[3] as f32 / 255.0;
+    let (img, input_format) = decode_image(&image_bytes)?;
 
-            for i in 0..3 {
-                base_pixel[i] = (watermark_pixel[i] as f32 * watermark_alpha
-                    + base_pixel[i] as f32 * (1.0 - watermark_alpha))
-                    .round() as u8;
-            }
+    let (input_width, input_height) = (img.width(), img.height());
+    info!("Image decoded: {}x{} pixels", input_width, input_height);
+    let img = limit_pixels(img);
+    let mut restore_dimensions =
+        CONFIG.preserve_dimensions && (img.width(), img.height()) != (input_width, input_height);
+
+    // Convert the original image to RGBA if it's not already
+    let mut base_image = img.into_rgba8();
+    if let Some(key) = &CONFIG.chroma_key {
+        apply_chroma_key(&mut base_image, key.color, key.tolerance);
+    }
+    #[cfg(feature = "plugins")]
+    if CONFIG.plugin_stage == config::PluginStage::Before {
+        apply_plugin(&mut base_image)?;
+    }
+    if CONFIG.resize_before_watermark {
+        if let Some(resized) = resize_to_target_width(&base_image) {
+            base_image = resized;
+            // TARGET_WIDTH already chose the delivery size
+            restore_dimensions = false;
         }
     }
+    let (width, height) = base_image.dimensions();
 
-    let mut output_buffer = Cursor::new(Vec::new());
-    base_image
-        .write_to(
-            &mut output_buffer,
-            ImageOutputFormat::Jpeg(CONFIG.jpeg_quality),
-        )
-        .map_err(|e| format!("Failed to encode image to JPEG: {}", e))?;
+    let fonts = current_fonts(watermark_font_ref, fallback_fonts)?;
+    let font = fonts.primary();
+    let adaptive = adaptive_style(style, watermark_logo);
+    let style = adaptive.as_ref().unwrap_or(style);
+    let layer_start = Instant::now();
+    let (tile_layer, glyph_draws, layout) =
+        build_tile_layer(width, height, watermark_text, &fonts, watermark_logo, style)?;
+    let label_layer = CONFIG
+        .layered_watermark
+        .then(|| build_label_layer(width, height, watermark_text, font));
+    let layer_build_duration = layer_start.elapsed();
+
+    let banner_layer = CONFIG
+        .banner_text
+        .as_deref()
+        .map(|text| build_banner_layer(width, height, text, font));
+
+    let blend_start = Instant::now();
+    tile_layer.blend_onto(&mut base_image, adaptive.is_some());
+    if let Some(label_layer) = &label_layer {
+        blend_layer(&mut base_image, label_layer);
+    }
+    // The banner is blended separately so it always sits on top of the tiles
+    if let Some(banner_layer) = &banner_layer {
+        blend_layer(&mut base_image, banner_layer);
+    }
+    let blend_duration = blend_start.elapsed();
+
+    if restore_dimensions {
+        info!(
+            "Restoring {}x{} working image to {}x{} pixels",
+            width, height, input_width, input_height
+        );
+        base_image = imageops::resize(&base_image, input_width, input_height, CONFIG.resize_filter);
+    }
+
+    #[cfg(feature = "plugins")]
+    if CONFIG.plugin_stage == config::PluginStage::After {
+        apply_plugin(&mut base_image)?;
+    }
+    if !CONFIG.resize_before_watermark {
+        if let Some(resized) = resize_to_target_width(&base_image) {
+            base_image = resized;
+        }
+    }
+
+    // The border goes on last so its width is exact in the delivered image
+    if CONFIG.border_width > 0 {
+        base_image = add_border(&base_image, CONFIG.border_width, CONFIG.border_color)?;
+    }
+
+    let blurhash = if CONFIG.preview_placeholder {
+        compute_blurhash(&base_image)
+    } else {
+        None
+    };
+    let encode_start = Instant::now();
+    // Chroma-keyed images and rasterized SVGs carry transparency that must survive encoding
+    let (body, output_format) = encode_output(
+        &base_image,
+        input_format,
+        CONFIG.chroma_key.is_some() || is_svg(&image_bytes),
+        webp_allowed,
+    )?;
+    let body = Bytes::from(body);
 
     let encoding_duration = start_time.elapsed();
     info!(
@@ -404,7 +3814,373 @@ async fn add_watermark(
         encoding_duration
     );
 
-    Ok(output_buffer.into_inner())
+    let stats = RenderStats {
+        input_format: input_format.map(format_name),
+        input_width,
+        input_height,
+        output_format: format_name(output_format),
+        output_quality: output_quality(output_format),
+        glyph_draws,
+        layout_cols: layout.chars_per_row,
+        layout_rows: layout.rows,
+        layout_scale: layout.scale.y,
+        layer_build_ms: duration_ms(layer_build_duration),
+        blend_ms: duration_ms(blend_duration),
+        encode_ms: duration_ms(encode_start.elapsed()),
+        output_bytes: body.len(),
+        ..Default::default()
+    };
+    Ok(RenderedImage {
+        body,
+        content_type: output_format.to_mime_type(),
+        svg_overlay: None,
+        blurhash,
+        stats,
+    })
+}
+
+/// Width the output is downscaled to before computing its blurhash. The hash only
+/// keeps a few color components, so a tiny thumbnail gives the same result quickly.
+const BLURHASH_SAMPLE_WIDTH: u32 = 32;
+
+/// Computes a 4x3 component blurhash of `image` for frontends to show while the full
+/// image loads.
+fn compute_blurhash(image: &RgbaImage) -> Option<String> {
+    let (width, height) = image.dimensions();
+    let sample_width = width.min(BLURHASH_SAMPLE_WIDTH);
+    let sample_height =
+        ((height as f64 * sample_width as f64 / width as f64).round() as u32).max(1);
+    let sample = imageops::thumbnail(image, sample_width, sample_height);
+
+    match blurhash::encode(4, 3, sample_width, sample_height, sample.as_raw()) {
+        Ok(hash) => Some(hash),
+        Err(e) => {
+            warn!("Failed to compute blurhash: {}", e);
+            None
+        }
+    }
+}
+
+/// Builds an SVG document with the tiled watermark laid out exactly like
+/// `build_watermark_layer`, sized to the source image, on a single line so it
+/// can travel in a response header.
+fn build_svg_overlay(
+    width: u32,
+    height: u32,
+    watermark_text: &str,
+    fonts: &FontChain,
+    style: &WatermarkStyle,
+) -> String {
+    let (chars, line_lengths) = watermark_lines(watermark_text, fonts);
+    let layout = WatermarkLayout::new(
+        width,
+        height,
+        &line_lengths,
+        glyph_extent(&chars, fonts, &CONFIG.layout),
+        style,
+        &CONFIG.layout,
+    );
+    // SVG positions text by its baseline, while the raster layer positions by the top edge
+    let ascent = fonts.primary().v_metrics(layout.scale).ascent;
+    // Glyphs are stretched horizontally by FONT_WIDTH_RATIO, so x coordinates are
+    // expressed in the unscaled space of the enclosing transform
+    let x_ratio = layout.scale.x / layout.scale.y;
+
+    let mut rows = String::new();
+    for row in 0..layout.rows {
+        let mut x_positions = Vec::with_capacity(layout.chars_per_row);
+        let mut text = String::new();
+        let mut y_pos = 0;
+        for col in 0..layout.chars_per_row {
+            let (x_pos, y) = layout.position(row, col);
+            y_pos = y;
+            x_positions.push(format!("{:.1}", x_pos as f32 / x_ratio));
+            for c in chars[layout.char_index(row, col)].chars() {
+                push_xml_escaped(&mut text, c);
+            }
+        }
+        rows.push_str(&format!(
+            "<text x=\"{}\" y=\"{:.1}\">{}</text>",
+            x_positions.join(" "),
+            y_pos as f32 + ascent,
+            text
+        ));
+    }
+
+    let svg_fill = |color: Rgba<u8>| {
+        format!(
+            "fill=\"rgb({},{},{})\" fill-opacity=\"{:.3}\"",
+            color[0],
+            color[1],
+            color[2],
+            color[3] as f32 / 255.0
+        )
+    };
+
+    // Rotated layouts are laid out on a larger canvas centered over the image
+    let (canvas_width, canvas_height) = layer_canvas_size(width, height, style.angle);
+    let (open_group, close_group) = if style.angle == 0.0 {
+        (String::new(), "")
+    } else {
+        (
+            format!(
+                "<g transform=\"rotate({:.2} {} {}) translate({} {})\">",
+                -style.angle,
+                width as f32 / 2.0,
+                height as f32 / 2.0,
+                -((canvas_width - width) as f32 / 2.0),
+                -((canvas_height - height) as f32 / 2.0)
+            ),
+            "</g>",
+        )
+    };
+
+    let shadow_use = |attributes: &str| {
+        format!(
+            "<use xlink:href=\"#wm\" x=\"{}\" y=\"{}\" {}{}/>",
+            layout.shadow_offset_x,
+            layout.shadow_offset_y,
+            svg_fill(CONFIG.layout.shadow_color),
+            attributes
+        )
+    };
+    let (shadow_filter, shadow) = if !CONFIG.layout.shadow_enabled {
+        (String::new(), String::new())
+    } else if CONFIG.layout.shadow_blur_sigma > 0.0 {
+        (
+            format!(
+                "<filter id=\"shadow-blur\"><feGaussianBlur stdDeviation=\"{:.2}\"/></filter>",
+                CONFIG.layout.shadow_blur_sigma
+            ),
+            shadow_use(" filter=\"url(#shadow-blur)\""),
+        )
+    } else {
+        (String::new(), shadow_use(""))
+    };
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\
+<defs><g id=\"wm\" transform=\"scale({ratio:.4},1)\" font-family=\"DejaVu Sans, sans-serif\" font-size=\"{size:.1}\">{rows}</g>{shadow_filter}</defs>\
+{open_group}{shadow}\
+<use xlink:href=\"#wm\" {fill}/>{close_group}\
+</svg>",
+        w = width,
+        h = height,
+        open_group = open_group,
+        close_group = close_group,
+        ratio = x_ratio,
+        size = layout.scale.y,
+        rows = rows,
+        shadow_filter = shadow_filter,
+        shadow = shadow,
+        fill = svg_fill(style.color),
+    )
+}
+
+/// Escapes XML specials and encodes non-ASCII characters as numeric references,
+/// keeping the SVG valid as an HTTP header value. Also used for XML error bodies.
+fn push_xml_escaped(out: &mut String, c: char) {
+    match c {
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '&' => out.push_str("&amp;"),
+        '"' => out.push_str("&quot;"),
+        c if c.is_ascii() && !c.is_ascii_control() => out.push(c),
+        c => out.push_str(&format!("&#x{:X};", c as u32)),
+    }
+}
+
+/// Downscales images above `MAX_PIXELS` proportionally, bounding the size of the RGBA
+/// buffers allocated while rendering. They are sharpened with `SHARPEN_SIGMA` like after
+/// `TARGET_WIDTH`, unless `PRESERVE_DIMENSIONS` scales them back up.
+fn limit_pixels(img: DynamicImage) -> DynamicImage {
+    let sharpen_sigma = if CONFIG.preserve_dimensions {
+        0.0
+    } else {
+        CONFIG.sharpen_sigma
+    };
+    shrink_to_pixels(img, CONFIG.max_pixels, CONFIG.resize_filter, sharpen_sigma)
+}
+
+fn shrink_to_pixels(
+    img: DynamicImage,
+    max_pixels: u64,
+    filter: imageops::FilterType,
+    sharpen_sigma: f32,
+) -> DynamicImage {
+    let (width, height) = (img.width(), img.height());
+    let pixels = width as u64 * height as u64;
+    if max_pixels == 0 || pixels <= max_pixels {
+        return img;
+    }
+
+    let factor = (max_pixels as f64 / pixels as f64).sqrt();
+    let working_width = ((width as f64 * factor).floor() as u32).max(1);
+    let working_height = ((height as f64 * factor).floor() as u32).max(1);
+    info!(
+        "Image of {}x{} pixels exceeds MAX_PIXELS {}, rendering at {}x{}",
+        width, height, max_pixels, working_width, working_height
+    );
+    let resized = img.resize_exact(working_width, working_height, filter);
+    if sharpen_sigma > 0.0 {
+        debug!(
+            "Sharpening downscaled image with SHARPEN_SIGMA {}",
+            sharpen_sigma
+        );
+        return resized.unsharpen(sharpen_sigma, 0);
+    }
+    resized
+}
+
+/// Downscales the image proportionally to `TARGET_WIDTH`, or returns `None` when no
+/// target is configured or the image is already narrow enough. With `SHARPEN_SIGMA`
+/// set, an unsharp mask restores the detail softened by the downscale.
+fn resize_to_target_width(image: &RgbaImage) -> Option<RgbaImage> {
+    downscale(
+        image,
+        CONFIG.target_width,
+        CONFIG.resize_filter,
+        CONFIG.sharpen_sigma,
+    )
+}
+
+fn downscale(
+    image: &RgbaImage,
+    target_width: u32,
+    filter: imageops::FilterType,
+    sharpen_sigma: f32,
+) -> Option<RgbaImage> {
+    let (width, height) = image.dimensions();
+    if target_width == 0 || width <= target_width {
+        return None;
+    }
+
+    let target_height =
+        ((height as f64 * target_width as f64 / width as f64).round() as u32).max(1);
+    info!(
+        "Resizing image from {}x{} to {}x{} pixels",
+        width, height, target_width, target_height
+    );
+    let resized = imageops::resize(image, target_width, target_height, filter);
+
+    if sharpen_sigma > 0.0 {
+        debug!(
+            "Sharpening downscaled image with SHARPEN_SIGMA {}",
+            sharpen_sigma
+        );
+        // A threshold of 0 sharpens every difference, however small
+        return Some(imageops::unsharpen(&resized, sharpen_sigma, 0));
+    }
+
+    Some(resized)
+}
+
+/// Expands the canvas by `border_width` pixels on every side and fills the new area with
+/// `color`, failing when the framed size does not fit in `u32`.
+fn add_border(image: &RgbaImage, border_width: u32, color: Rgba<u8>) -> Result<RgbaImage, String> {
+    let framed_size = |size: u32| {
+        border_width
+            .checked_mul(2)
+            .and_then(|border| size.checked_add(border))
+            .ok_or_else(|| {
+                format!(
+                    "Border of {} px is too wide for a {}x{} image",
+                    border_width,
+                    image.width(),
+                    image.height()
+                )
+            })
+    };
+    let mut framed = RgbaImage::from_pixel(
+        framed_size(image.width())?,
+        framed_size(image.height())?,
+        color,
+    );
+    imageops::replace(&mut framed, image, border_width as i64, border_width as i64);
+    info!(
+        "Border of {} px added, output is {}x{} pixels",
+        border_width,
+        framed.width(),
+        framed.height()
+    );
+    Ok(framed)
+}
+
+fn build_cors() -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST"])
+        .allow_any_header()
+        .expose_any_header()
+        .max_age(3600);
+    if CONFIG
+        .cors_allowed_origins
+        .iter()
+        .any(|origin| origin == "*")
+    {
+        cors = cors.allow_any_origin();
+    } else {
+        for origin in &CONFIG.cors_allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+    }
+    cors
+}
+
+/// Access logger for `ACCESS_LOG_FORMAT`. `%t` is not in the Apache format, so the
+/// request time is formatted here instead.
+fn build_access_logger() -> Logger {
+    let format = match CONFIG.access_log_format {
+        AccessLogFormat::Combined => {
+            r#"%{r}a - - [%{clf_time}xi] "%r" %s %b "%{Referer}i" "%{User-Agent}i" %D"#
+        }
+        AccessLogFormat::Common | AccessLogFormat::Off => {
+            r#"%{r}a - - [%{clf_time}xi] "%r" %s %b %D"#
+        }
+    };
+    Logger::new(format)
+        .log_target(ACCESS_LOG_TARGET)
+        .custom_request_replace("clf_time", |_| {
+            chrono::Local::now()
+                .format("%d/%b/%Y:%H:%M:%S %z")
+                .to_string()
+        })
+}
+
+/// Reloads `TENANT_CONFIG_PATH` on SIGHUP and drops cached results rendered with the
+/// previous styles. An invalid file keeps the styles already loaded.
+#[cfg(unix)]
+fn spawn_tenant_reloader(app_state: web::Data<AppState>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    actix_web::rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let Some(tenants) = app_state.tenants.as_ref() else {
+                break;
+            };
+            match tenants.reload() {
+                Ok(count) => {
+                    info!(
+                        "Reloaded watermark overrides for {} bucket(s) from {}",
+                        count,
+                        tenants.path()
+                    );
+                    if let Some(cache) = &app_state.cache {
+                        cache.clear();
+                    }
+                }
+                Err(e) => error!("Keeping previous watermark overrides: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Whether MinIO is reached over https and without certificate checks. An endpoint
+/// without a scheme follows `MINIO_SECURE`, an explicit scheme wins as it did before
+/// `MINIO_SECURE` chose the scheme. Certificate checks only follow `MINIO_IGNORE_CERT`.
+fn minio_transport(explicit_https: Option<bool>, secure: bool, ignore_cert: bool) -> (bool, bool) {
+    (explicit_https.unwrap_or(secure), ignore_cert)
 }
 
 #[actix_web::main]
@@ -412,7 +4188,7 @@ async fn main() -> std::io::Result<()> {
     // Load variables from .env file
     dotenv().ok();
 
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or(CONFIG.log_level.as_str()));
+    logging::init();
 
     let host = &CONFIG.host;
     let port = CONFIG.port;
@@ -420,34 +4196,68 @@ async fn main() -> std::io::Result<()> {
     let minio_endpoint = CONFIG.minio_endpoint.clone();
     let minio_access_key = CONFIG.minio_access_key.clone();
     let minio_secret_key = CONFIG.minio_secret_key.clone();
-    let minio_secure = CONFIG.minio_secure;
 
     let credentials = StaticProvider::new(&minio_access_key, &minio_secret_key, None);
-    let endpoint = minio_endpoint.parse().map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to parse MinIO endpoint: {}", e),
-        )
-    })?;
+    let mut endpoint: BaseUrl = minio_endpoint
+        .parse()
+        .map_err(|e| std::io::Error::other(format!("Failed to parse MinIO endpoint: {}", e)))?;
+    let explicit_https = minio_endpoint.contains("://").then_some(endpoint.https);
+    if explicit_https.is_some_and(|https| https != CONFIG.minio_secure) {
+        warn!(
+            "MINIO_ENDPOINT {} does not match MINIO_SECURE={}; the endpoint scheme is used, which is deprecated and will stop the service in a future release",
+            minio_endpoint, CONFIG.minio_secure
+        );
+    }
+    let (minio_secure, minio_ignore_cert) = minio_transport(
+        explicit_https,
+        CONFIG.minio_secure,
+        CONFIG.minio_ignore_cert,
+    );
+    endpoint.https = minio_secure;
     let provider: Option<Box<dyn minio::s3::creds::Provider + Send + Sync + 'static>> =
         Some(Box::new(credentials));
-    let ssl_cert_file: Option<&std::path::Path> = None;
-    let ignore_cert_check: Option<bool> = Some(!minio_secure);
+    let ssl_cert_file = CONFIG
+        .minio_ca_cert_file
+        .as_deref()
+        .map(std::path::Path::new);
+    if ssl_cert_file.is_some() && !minio_secure {
+        let source = if explicit_https.is_some() {
+            format!("MINIO_ENDPOINT {} uses http", minio_endpoint)
+        } else {
+            "MINIO_SECURE is false".to_string()
+        };
+        return Err(std::io::Error::other(format!(
+            "MINIO_CA_CERT_FILE is set but {}; the CA certificate is only used over https",
+            source
+        )));
+    }
+    if minio_secure && minio_ignore_cert {
+        warn!("MINIO_IGNORE_CERT is enabled, the MinIO server certificate is not verified");
+    }
+    let ignore_cert_check = Some(minio_ignore_cert);
 
     info!("Creating MinIO client...");
     let minio_client =
         minio::s3::client::Client::new(endpoint, provider, ssl_cert_file, ignore_cert_check)
-            .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Failed to create MinIO client: {}", e),
-                )
-            })?;
+            .map_err(|e| std::io::Error::other(format!("Failed to create MinIO client: {}", e)))?;
+
+    if is_remote_font_path(&CONFIG.font_path) {
+        info!("Downloading font from {}", CONFIG.font_path);
+        match fetch_remote_font(&minio_client, &CONFIG.font_path).await {
+            Ok(data) => {
+                info!("Downloaded {} bytes of font data", data.len());
+                let _ = REMOTE_FONT_DATA.set(data);
+            }
+            Err(e) => error!("Failed to download font from {}: {}", CONFIG.font_path, e),
+        }
+    }
 
     info!("Preloading font...");
     let font_ref_clone = Arc::clone(&WATERMARK_FONT);
     {
-        let font_guard = WATERMARK_FONT.read().expect("Font RwLock poisoned");
+        let font_guard = WATERMARK_FONT
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
         match *font_guard {
             Some(_) => info!("Font loaded successfully at startup."),
             None => error!("Font is None after attempted loading. Watermarking will fail!"),
@@ -463,15 +4273,110 @@ async fn main() -> std::io::Result<()> {
     };
     info!("Using {} worker threads", workers);
 
+    let cors_enabled = !CONFIG.cors_allowed_origins.is_empty();
+    if cors_enabled {
+        info!(
+            "CORS enabled for origins: {}",
+            CONFIG.cors_allowed_origins.join(", ")
+        );
+    }
+
+    let logo = match &CONFIG.watermark_image_path {
+        Some(path) => {
+            let logo = image::open(path)
+                .map_err(|e| {
+                    std::io::Error::other(format!("Failed to load watermark image {}: {}", path, e))
+                })?
+                .into_rgba8();
+            info!(
+                "Watermark image loaded from {} ({}x{} pixels)",
+                path,
+                logo.width(),
+                logo.height()
+            );
+            Some(Arc::new(logo))
+        }
+        None => None,
+    };
+
+    let fallback_image = match &CONFIG.fallback_image_path {
+        Some(path) if CONFIG.serve_fallback => {
+            let data = std::fs::read(path).map_err(|e| {
+                std::io::Error::other(format!("Failed to read fallback image {}: {}", path, e))
+            })?;
+            info!("Fallback image loaded from {}", path);
+            Some(Bytes::from(data))
+        }
+        _ => None,
+    };
+
+    let tenants = CONFIG
+        .tenant_config_path
+        .as_deref()
+        .map(TenantStyles::load)
+        .transpose()
+        .map_err(std::io::Error::other)?;
+
+    let cache_ttl = (CONFIG.cache_ttl_secs > 0).then(|| Duration::from_secs(CONFIG.cache_ttl_secs));
+    let cache = RenderCache::new(CONFIG.cache_max_entries, CONFIG.cache_max_bytes, cache_ttl);
+    if cache.is_some() {
+        info!(
+            "Result cache enabled: up to {} entries, {} bytes, kept {} s",
+            CONFIG.cache_max_entries, CONFIG.cache_max_bytes, CONFIG.cache_ttl_secs
+        );
+    }
+
+    #[cfg(feature = "plugins")]
+    if let Some(plugin_path) = &CONFIG.plugin_path {
+        info!("Loading plugin from {}", plugin_path);
+        let plugin = Plugin::load(plugin_path).map_err(std::io::Error::other)?;
+        let _ = PLUGIN.set(plugin);
+    }
+
+    let metrics = Metrics::new()
+        .map_err(|e| std::io::Error::other(format!("Failed to set up metrics: {}", e)))?;
+
     let app_state = web::Data::new(AppState {
         minio_client,
         font: font_ref_clone,
+        fallback_fonts: Arc::clone(&FALLBACK_FONTS),
+        logo,
+        cache,
+        fallback_image,
+        downloads: (CONFIG.max_concurrent_downloads > 0)
+            .then(|| Semaphore::new(CONFIG.max_concurrent_downloads)),
+        renders: (CONFIG.max_concurrent_renders > 0).then(|| RenderSlots {
+            slots: Arc::new(Semaphore::new(CONFIG.max_concurrent_renders)),
+            waiting: AtomicUsize::new(0),
+        }),
+        metrics,
+        text_providers: text_provider::configured_providers(),
+        tenants,
     });
 
+    #[cfg(unix)]
+    if app_state.tenants.is_some() {
+        spawn_tenant_reloader(app_state.clone())?;
+    }
+
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(lambda_json_config())
+            .wrap(Condition::new(cors_enabled, build_cors()))
+            .wrap(Condition::new(
+                CONFIG.access_log_format != AccessLogFormat::Off,
+                build_access_logger(),
+            ))
             .route("/", web::post().to(generate))
+            .route(
+                "/wm/{bucket}/{object:.*}",
+                web::get().to(generate_from_path),
+            )
+            .route(
+                "/wm-svg/{bucket}/{object:.*}",
+                web::get().to(svg_overlay_from_path),
+            )
             .route(
                 "/",
                 web::get().to(|| async { HttpResponse::Ok().body("OK") }),
@@ -480,9 +4385,514 @@ async fn main() -> std::io::Result<()> {
                 "/health/",
                 web::get().to(|| async { HttpResponse::Ok().body("OK") }),
             )
+            .route("/ready/", web::get().to(readiness_endpoint))
+            .route("/preview", web::get().to(preview_endpoint))
+            .route("/font/coverage", web::get().to(font_coverage_endpoint))
+            .route("/metrics", web::get().to(metrics_endpoint))
+            .service(
+                web::resource("/watermark")
+                    .app_data(web::PayloadConfig::new(MAX_UPLOAD_BYTES))
+                    .route(web::post().to(watermark_upload)),
+            )
     })
     .workers(workers)
     .bind((host.as_str(), port))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_border, decoded_path_segments, downscale, extract_url_params, image_response,
+        is_allowed_bucket, lambda_json_config, minio_transport, parse_s3_url, process_image,
+        query_angle, read_font, retry_backoff, shrink_to_pixels, svg_overlay_link, without_query,
+        DynamicImage, FontPoisonPolicy, GenerateRequest, HashMap, RenderStats, RenderedImage,
+        UserRequest, WatermarkStyle, CONFIG, RETRY_MAX_DELAY,
+    };
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::web::{self, Bytes};
+    use actix_web::{App, HttpResponse};
+    use image::imageops::FilterType;
+    use image::{ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+    use rusttype::Font;
+    use std::io::Cursor;
+    use std::sync::{Arc, RwLock};
+    use url::Url;
+
+    #[test]
+    fn url_params_are_percent_decoded() {
+        let params = extract_url_params("https://host/bucket/key.jpg?usercode=a%3Db&x=1");
+        assert_eq!(params["usercode"], "a=b");
+        assert_eq!(params["x"], "1");
+    }
+
+    #[test]
+    fn url_params_keep_equals_signs_in_values() {
+        let params = extract_url_params("https://host/key.jpg?usercode=a=b=c");
+        assert_eq!(params["usercode"], "a=b=c");
+    }
+
+    #[test]
+    fn url_params_without_value_are_empty() {
+        let params = extract_url_params("https://host/key.jpg?usercode=&debug");
+        assert_eq!(params["usercode"], "");
+        assert_eq!(params["debug"], "");
+    }
+
+    #[test]
+    fn url_params_last_repeated_key_wins() {
+        let params = extract_url_params("https://host/key.jpg?usercode=first&usercode=second");
+        assert_eq!(params["usercode"], "second");
+    }
+
+    #[test]
+    fn url_params_stop_at_the_fragment() {
+        let params = extract_url_params("https://host/key.jpg?usercode=abc#section");
+        assert_eq!(params["usercode"], "abc");
+        let params = extract_url_params("/bucket/key.jpg?usercode=a%20b#x=1");
+        assert_eq!(params["usercode"], "a b");
+        assert!(!params.contains_key("x"));
+    }
+
+    #[test]
+    fn url_params_of_url_without_query_are_empty() {
+        assert!(extract_url_params("https://host/key.jpg").is_empty());
+        assert!(extract_url_params("not a url").is_empty());
+    }
+
+    #[test]
+    fn every_bucket_is_allowed_without_allowlist() {
+        assert!(is_allowed_bucket(&[], "private"));
+    }
+
+    #[test]
+    fn only_listed_buckets_are_allowed() {
+        let allowed = vec!["images".to_string(), "photos".to_string()];
+        assert!(is_allowed_bucket(&allowed, "photos"));
+        assert!(!is_allowed_bucket(&allowed, "private"));
+        assert!(!is_allowed_bucket(&allowed, "Images"));
+    }
+
+    #[test]
+    fn opacity_below_the_minimum_is_raised_without_admin_token() {
+        let request: UserRequest = serde_json::from_str(r#"{"opacity": 0}"#).unwrap();
+        let overrides = request.style_overrides(false, None).unwrap();
+        assert_eq!(overrides.alpha, Some(CONFIG.min_watermark_alpha));
+        let overrides = request.style_overrides(true, None).unwrap();
+        assert_eq!(overrides.alpha, Some(0));
+    }
+
+    #[test]
+    fn opacity_above_the_minimum_is_kept() {
+        let request: UserRequest = serde_json::from_str(r#"{"opacity": 0.5}"#).unwrap();
+        assert_eq!(
+            request.style_overrides(false, None).unwrap().alpha,
+            Some(128)
+        );
+    }
+
+    #[test]
+    fn angle_comes_from_the_query_unless_set_in_the_request() {
+        let params = extract_url_params("https://host/key.jpg?angle=45");
+        let angle = query_angle(&params).unwrap();
+        let request: UserRequest = serde_json::from_str("{}").unwrap();
+        let style = request
+            .style_overrides(false, angle)
+            .unwrap()
+            .apply(CONFIG.watermark_style());
+        assert_eq!(style.angle, 45.0);
+        assert!(style.measured_spacing);
+
+        let request: UserRequest = serde_json::from_str(r#"{"angle": 30}"#).unwrap();
+        let overrides = request.style_overrides(false, angle).unwrap();
+        assert_eq!(overrides.apply(CONFIG.watermark_style()).angle, 30.0);
+    }
+
+    #[test]
+    fn invalid_query_angles_are_rejected() {
+        let params = extract_url_params("https://host/key.jpg?angle=steep");
+        assert!(query_angle(&params).is_err());
+        let params = extract_url_params("https://host/key.jpg?angle=NaN");
+        let request: UserRequest = serde_json::from_str("{}").unwrap();
+        assert!(request
+            .style_overrides(false, query_angle(&params).unwrap())
+            .is_err());
+    }
+
+    fn test_font() -> Arc<RwLock<Option<Font<'static>>>> {
+        let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+        Arc::new(RwLock::new(Some(font)))
+    }
+
+    fn jpeg_input(width: u32, height: u32) -> Bytes {
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 120])
+        });
+        let mut jpeg = Cursor::new(Vec::new());
+        image
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        Bytes::from(jpeg.into_inner())
+    }
+
+    #[test]
+    fn identical_renders_are_byte_equal() {
+        let font = test_font();
+        let input = jpeg_input(320, 240);
+        let style = WatermarkStyle {
+            angle: 30.0,
+            ..CONFIG.watermark_style()
+        };
+        let render = || {
+            process_image(
+                input.clone(),
+                "photo.jpg",
+                "user@example.com",
+                &font,
+                &[],
+                None,
+                &style,
+                false,
+            )
+            .unwrap()
+        };
+        let (first, second) = (render(), render());
+        assert_eq!(first.content_type, "image/jpeg");
+        assert!(first.body != input);
+        assert_eq!(first.body, second.body);
+    }
+
+    /// Compares a render with `tests/golden/<name>.png`, within the tolerances of the
+    /// library golden tests in `tests/golden.rs`. `UPDATE_GOLDEN=1` rewrites the image.
+    fn assert_matches_golden(name: &str, rendered: &RgbaImage) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.png", name));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            rendered.save(&path).unwrap();
+            return;
+        }
+        let golden = image::open(&path)
+            .unwrap_or_else(|e| panic!("{}: cannot read {}: {}", name, path.display(), e))
+            .into_rgba8();
+        assert_eq!(rendered.dimensions(), golden.dimensions(), "{}", name);
+        let differing = rendered
+            .iter()
+            .zip(golden.iter())
+            .filter(|(a, b)| a.abs_diff(**b) > 8)
+            .count();
+        assert!(
+            differing as f64 / rendered.len() as f64 <= 0.002,
+            "{}: {} channels differ from the golden image",
+            name,
+            differing
+        );
+    }
+
+    #[test]
+    fn service_renders_match_golden_images() {
+        let font = test_font();
+        let png_input = {
+            let image = RgbaImage::from_fn(300, 300, |x, y| {
+                let b = if (x + y) % 64 < 32 { 60 } else { 180 };
+                Rgba([(x * 255 / 300) as u8, (y * 255 / 300) as u8, b, 255])
+            });
+            let mut png = Cursor::new(Vec::new());
+            image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+            Bytes::from(png.into_inner())
+        };
+        let cases = [
+            ("service_jpeg", jpeg_input(320, 240), "photo.jpg", 0.0),
+            ("service_rotated_png", png_input, "photo.png", 30.0),
+        ];
+
+        for (name, input, object_name, angle) in cases {
+            let style = WatermarkStyle {
+                angle,
+                ..CONFIG.watermark_style()
+            };
+            let rendered = process_image(
+                input,
+                object_name,
+                "user@example.com",
+                &font,
+                &[],
+                None,
+                &style,
+                false,
+            )
+            .unwrap_or_else(|e| panic!("{}: {}", name, e));
+            let rendered = image::load_from_memory(&rendered.body)
+                .unwrap()
+                .into_rgba8();
+            assert_matches_golden(name, &rendered);
+        }
+    }
+
+    #[test]
+    fn border_surrounds_the_image_in_its_color() {
+        let image = RgbaImage::from_pixel(30, 20, Rgba([10, 20, 30, 255]));
+        let color = Rgba([200, 0, 0, 255]);
+        let framed = add_border(&image, 5, color).unwrap();
+        assert_eq!(framed.dimensions(), (40, 30));
+        for (x, y, pixel) in framed.enumerate_pixels() {
+            let inside = (5..35).contains(&x) && (5..25).contains(&y);
+            let expected = if inside {
+                image.get_pixel(x - 5, y - 5)
+            } else {
+                &color
+            };
+            assert_eq!(pixel, expected, "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn border_overflowing_the_image_size_is_an_error() {
+        let image = RgbaImage::new(2, 2);
+        assert!(add_border(&image, u32::MAX / 2, Rgba([0, 0, 0, 255])).is_err());
+        assert!(add_border(&image, u32::MAX / 2 + 1, Rgba([0, 0, 0, 255])).is_err());
+    }
+
+    /// Poisons the font lock by panicking while holding the write guard, after emptying
+    /// it so a reload can be told apart from keeping the contents.
+    fn poison(font: &Arc<RwLock<Option<Font<'static>>>>) {
+        let result = std::panic::catch_unwind(|| {
+            let mut guard = font.write().unwrap();
+            *guard = None;
+            panic!("font lock poisoned on purpose");
+        });
+        assert!(result.is_err());
+        assert!(font.is_poisoned());
+    }
+
+    #[test]
+    fn poisoned_font_lock_is_cleared_by_recover() {
+        let font = test_font();
+        poison(&font);
+        assert!(read_font(&font, FontPoisonPolicy::Recover)
+            .unwrap()
+            .is_none());
+        assert!(!font.is_poisoned());
+    }
+
+    #[test]
+    fn poisoned_font_lock_is_refilled_by_reload() {
+        let font = test_font();
+        poison(&font);
+        assert!(read_font(&font, FontPoisonPolicy::Reload)
+            .unwrap()
+            .is_some());
+        assert!(!font.is_poisoned());
+        assert!(font.read().unwrap().is_some());
+    }
+
+    #[test]
+    fn poisoned_font_lock_fails_requests_with_fail() {
+        let font = test_font();
+        poison(&font);
+        assert!(read_font(&font, FontPoisonPolicy::Fail).is_err());
+        assert!(font.is_poisoned());
+    }
+
+    /// Sum of the differences between horizontal neighbours, higher for sharper images.
+    fn edge_contrast(image: &RgbaImage) -> u64 {
+        image
+            .rows()
+            .flat_map(|row| {
+                let row: Vec<_> = row.collect();
+                row.windows(2)
+                    .map(|pair| pair[0][0].abs_diff(pair[1][0]) as u64)
+                    .collect::<Vec<_>>()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn sharpening_raises_the_contrast_of_downscaled_images() {
+        let stripes = RgbaImage::from_fn(200, 100, |x, _| {
+            let level = if (x / 10) % 2 == 0 { 60 } else { 190 };
+            Rgba([level, level, level, 255])
+        });
+        let plain = downscale(&stripes, 100, FilterType::Triangle, 0.0).unwrap();
+        let sharpened = downscale(&stripes, 100, FilterType::Triangle, 1.0).unwrap();
+        assert_eq!(plain.dimensions(), (100, 50));
+        assert_eq!(sharpened.dimensions(), (100, 50));
+        assert!(edge_contrast(&sharpened) > edge_contrast(&plain));
+        assert!(downscale(&stripes, 200, FilterType::Triangle, 1.0).is_none());
+
+        let stripes = DynamicImage::ImageRgba8(stripes);
+        let plain = shrink_to_pixels(stripes.clone(), 5000, FilterType::Triangle, 0.0);
+        let sharpened = shrink_to_pixels(stripes.clone(), 5000, FilterType::Triangle, 1.0);
+        assert_eq!((sharpened.width(), sharpened.height()), (100, 50));
+        assert!(edge_contrast(&sharpened.to_rgba8()) > edge_contrast(&plain.to_rgba8()));
+        assert_eq!(
+            shrink_to_pixels(stripes.clone(), 20000, FilterType::Triangle, 1.0),
+            stripes
+        );
+    }
+
+    /// Answers 200 once the object-lambda payload was extracted like in `generate`.
+    async fn accept_payload(_payload: web::Json<GenerateRequest>) -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn lambda_payload_is_accepted_with_amz_json_content_type() {
+        let app = init_service(
+            App::new()
+                .app_data(lambda_json_config())
+                .route("/", web::post().to(accept_payload)),
+        )
+        .await;
+        let payload = r#"{
+            "getObjectContext": {"inputS3Url": "http://127.0.0.1:9000/bucket/key.jpg"},
+            "userRequest": {"url": "https://host/bucket/key.jpg?usercode=ABCD1234"}
+        }"#;
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("Content-Type", "application/x-amz-json-1.1"))
+            .set_payload(payload)
+            .to_request();
+        let status = call_service(&app, req).await.status();
+        assert_ne!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_ne!(status, StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("Content-Type", "application/x-amz-json-1.1"))
+            .set_payload("not json")
+            .to_request();
+        let status = call_service(&app, req).await.status();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    fn segments(url: &str) -> Vec<String> {
+        decoded_path_segments(&Url::parse(url).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn path_segments_are_percent_decoded() {
+        assert_eq!(
+            segments("http://host/bucket/folder/my%20file.jpg"),
+            ["bucket", "folder", "my file.jpg"]
+        );
+        assert_eq!(
+            parse_s3_url("s3://bucket/folder/my%20file.jpg").unwrap(),
+            ("bucket".to_string(), "folder/my file.jpg".to_string())
+        );
+    }
+
+    #[test]
+    fn plus_signs_in_path_segments_are_kept() {
+        assert_eq!(
+            segments("http://host/bucket/a+b/c.png"),
+            ["bucket", "a+b", "c.png"]
+        );
+    }
+
+    #[test]
+    fn query_string_is_not_part_of_the_path_segments() {
+        assert_eq!(
+            segments("http://host/bucket/key.jpg?versionId=abc123"),
+            ["bucket", "key.jpg"]
+        );
+        assert_eq!(
+            parse_s3_url("s3://bucket/key.jpg?versionId=abc123").unwrap(),
+            ("bucket".to_string(), "key.jpg".to_string())
+        );
+    }
+
+    #[test]
+    fn path_segments_that_are_not_utf8_once_decoded_are_rejected() {
+        assert!(decoded_path_segments(&Url::parse("http://host/bucket/%FF.jpg").unwrap()).is_err());
+    }
+
+    #[test]
+    fn svg_overlay_links_keep_the_object_path_and_query() {
+        let params = HashMap::from([
+            ("text".to_string(), "Jane Doe".to_string()),
+            ("angle".to_string(), "30".to_string()),
+        ]);
+        assert_eq!(
+            svg_overlay_link("photos", "2024/summer trip#1.jpg", &params),
+            "/wm-svg/photos/2024/summer%20trip%231.jpg?angle=30&text=Jane+Doe"
+        );
+        assert_eq!(
+            svg_overlay_link("photos", "a.jpg", &HashMap::new()),
+            "/wm-svg/photos/a.jpg"
+        );
+    }
+
+    #[test]
+    fn svg_overlay_header_carries_a_link_instead_of_the_svg() {
+        let rendered = RenderedImage {
+            body: Bytes::from_static(b"image"),
+            content_type: "image/png",
+            svg_overlay: Some("<svg></svg>".to_string()),
+            blurhash: None,
+            stats: RenderStats::default(),
+        };
+        let link = "/wm-svg/photos/a.jpg".to_string();
+        let response = image_response(&rendered, false, None, None, Some(link.clone()));
+        assert_eq!(response.headers().get("X-Watermark-SVG").unwrap(), &link);
+
+        let response = image_response(&rendered, false, None, None, None);
+        assert!(response.headers().get("X-Watermark-SVG").is_none());
+    }
+
+    #[test]
+    fn minio_scheme_follows_the_endpoint_over_minio_secure() {
+        assert_eq!(minio_transport(None, true, false), (true, false));
+        assert_eq!(minio_transport(None, false, false), (false, false));
+        assert_eq!(minio_transport(Some(true), true, false), (true, false));
+        assert_eq!(minio_transport(Some(true), false, false), (true, false));
+        assert_eq!(minio_transport(Some(true), false, true), (true, true));
+        assert_eq!(minio_transport(Some(false), true, false), (false, false));
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_the_cap() {
+        let delays: Vec<u128> = (1..=7).map(|a| retry_backoff(a).as_millis()).collect();
+        assert_eq!(delays, [200, 400, 800, 1600, 3200, 5000, 5000]);
+        assert_eq!(retry_backoff(33), RETRY_MAX_DELAY);
+        assert_eq!(retry_backoff(u32::MAX), RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn lambda_requester_is_the_user_identity_or_the_client() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.7:4000".parse().unwrap())
+            .to_http_request();
+        let payload = |identity: &str| -> GenerateRequest {
+            serde_json::from_str(&format!(
+                r#"{{"getObjectContext": {{"inputS3Url": "http://minio/b/k.jpg?X-Amz-Signature=abc"}},
+                    "userRequest": {{"url": "http://minio/b/k.jpg?X-Amz-Credential=key"}}{}}}"#,
+                identity
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            payload(r#", "userIdentity": {"principalId": "alice"}"#).requester(&req),
+            "alice"
+        );
+        assert_eq!(
+            payload(r#", "userIdentity": {"arn": "arn:aws:iam::1:user/bob"}"#).requester(&req),
+            "arn:aws:iam::1:user/bob"
+        );
+        assert_eq!(payload("").requester(&req), "10.0.0.7");
+    }
+
+    #[test]
+    fn logged_urls_lose_their_query() {
+        assert_eq!(
+            without_query("http://minio/b/k.jpg?X-Amz-Signature=abc#frag"),
+            "http://minio/b/k.jpg"
+        );
+        assert_eq!(
+            without_query("http://minio/b/k.jpg"),
+            "http://minio/b/k.jpg"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::time::Duration;
+
+/// Prometheus counters and histograms for watermarking requests, exposed on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounter,
+    errors: IntCounterVec,
+    download_seconds: Histogram,
+    process_seconds: Histogram,
+    cache_entries: IntGauge,
+    cache_bytes: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, String> {
+        let requests =
+            IntCounter::new("watermark_requests_total", "Watermarking requests received")
+                .map_err(|e| e.to_string())?;
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "watermark_errors_total",
+                "Failed watermarking requests by category",
+            ),
+            &["category"],
+        )
+        .map_err(|e| e.to_string())?;
+        let download_seconds = Histogram::with_opts(HistogramOpts::new(
+            "watermark_download_duration_seconds",
+            "Time spent downloading source objects",
+        ))
+        .map_err(|e| e.to_string())?;
+        let process_seconds = Histogram::with_opts(HistogramOpts::new(
+            "watermark_process_duration_seconds",
+            "Time spent decoding, watermarking and encoding images",
+        ))
+        .map_err(|e| e.to_string())?;
+
+        let cache_entries = IntGauge::new("watermark_cache_entries", "Results in the cache")
+            .map_err(|e| e.to_string())?;
+        let cache_bytes = IntGauge::new(
+            "watermark_cache_bytes",
+            "Approximate bytes held by cached results",
+        )
+        .map_err(|e| e.to_string())?;
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(requests.clone()))
+            .and_then(|_| registry.register(Box::new(errors.clone())))
+            .and_then(|_| registry.register(Box::new(download_seconds.clone())))
+            .and_then(|_| registry.register(Box::new(process_seconds.clone())))
+            .and_then(|_| registry.register(Box::new(cache_entries.clone())))
+            .and_then(|_| registry.register(Box::new(cache_bytes.clone())))
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            registry,
+            requests,
+            errors,
+            download_seconds,
+            process_seconds,
+            cache_entries,
+            cache_bytes,
+        })
+    }
+
+    pub fn record_request(&self) {
+        self.requests.inc();
+    }
+
+    /// Counts a failure under a category such as `download`, `decode` or `font`.
+    pub fn record_error(&self, category: &str) {
+        self.errors.with_label_values(&[category]).inc();
+    }
+
+    pub fn record_durations(&self, download_duration: Duration, process_duration: Duration) {
+        self.download_seconds
+            .observe(download_duration.as_secs_f64());
+        self.process_seconds.observe(process_duration.as_secs_f64());
+    }
+
+    pub fn set_cache_usage(&self, entries: usize, bytes: usize) {
+        self.cache_entries.set(entries as i64);
+        self.cache_bytes.set(bytes as i64);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(buffer).map_err(|e| e.to_string())
+    }
+}
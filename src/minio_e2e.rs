@@ -0,0 +1,121 @@
+//! End-to-end test of the object-lambda endpoint against a real MinIO server, started
+//! in a throwaway container with `testcontainers`. It needs a Docker daemon and pulls
+//! the MinIO image, so it is ignored by default; run it with
+//! `cargo test minio_e2e -- --ignored`.
+
+use super::{generate, lambda_json_config, AppState, MinioClient, WATERMARK_FONT};
+use crate::metrics::Metrics;
+use crate::text_provider;
+use actix_web::{test, web, App};
+use image::{ImageFormat, ImageOutputFormat, Rgb, RgbImage};
+use minio::s3::args::{MakeBucketArgs, PutObjectApiArgs};
+use minio::s3::creds::StaticProvider;
+use std::io::Cursor;
+use std::sync::Arc;
+use testcontainers_modules::minio::MinIO;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+
+/// Root credentials of the `MinIO` module's container
+const ACCESS_KEY: &str = "minioadmin";
+const SECRET_KEY: &str = "minioadmin";
+const BUCKET: &str = "photos";
+const OBJECT: &str = "e2e/gradient.jpg";
+
+fn test_jpeg() -> Vec<u8> {
+    let image = RgbImage::from_fn(320, 240, |x, y| Rgb([x as u8, y as u8, 96]));
+    let mut jpeg = Cursor::new(Vec::new());
+    image
+        .write_to(&mut jpeg, ImageOutputFormat::Jpeg(90))
+        .unwrap();
+    jpeg.into_inner()
+}
+
+#[actix_web::test]
+#[ignore = "starts a MinIO container, needs a Docker daemon"]
+async fn lambda_request_watermarks_an_object_from_minio() {
+    // Removed when dropped at the end of the test
+    let container = MinIO::default().start().await.unwrap();
+    let endpoint = format!(
+        "http://{}:{}",
+        container.get_host().await.unwrap(),
+        container.get_host_port_ipv4(9000).await.unwrap()
+    );
+
+    // The service's MinIO client is built here against the container, instead of from
+    // CONFIG, which holds the test settings
+    let provider: Option<Box<dyn minio::s3::creds::Provider + Send + Sync + 'static>> =
+        Some(Box::new(StaticProvider::new(ACCESS_KEY, SECRET_KEY, None)));
+    let minio_client = MinioClient::new(endpoint.parse().unwrap(), provider, None, None).unwrap();
+    minio_client
+        .make_bucket(&MakeBucketArgs::new(BUCKET).unwrap())
+        .await
+        .unwrap();
+    let original = test_jpeg();
+    minio_client
+        .put_object_api(&PutObjectApiArgs::new(BUCKET, OBJECT, &original).unwrap())
+        .await
+        .unwrap();
+
+    let app_state = web::Data::new(AppState {
+        minio_client,
+        font: Arc::clone(&WATERMARK_FONT),
+        fallback_fonts: Arc::new(Vec::new()),
+        logo: None,
+        cache: None,
+        fallback_image: None,
+        downloads: None,
+        renders: None,
+        metrics: Metrics::new().unwrap(),
+        text_providers: text_provider::configured_providers(),
+        tenants: None,
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(app_state)
+            .app_data(lambda_json_config())
+            .route("/", web::post().to(generate)),
+    )
+    .await;
+
+    let payload = serde_json::json!({
+        "getObjectContext": {
+            "inputS3Url": format!("{}/{}/{}", endpoint, BUCKET, OBJECT),
+            "outputRoute": "e2e-route",
+            "outputToken": "e2e-token",
+        },
+        "userRequest": {
+            "url": format!("https://images.example.com/{}/{}?usercode=E2E", BUCKET, OBJECT),
+        },
+    });
+    let request = test::TestRequest::post()
+        .uri("/")
+        .set_json(payload)
+        .to_request();
+    let response = test::call_service(&app, request).await;
+
+    assert!(
+        response.status().is_success(),
+        "status {}",
+        response.status()
+    );
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    assert_eq!(header("x-amz-request-route").as_deref(), Some("e2e-route"));
+    assert_eq!(header("x-amz-request-token").as_deref(), Some("e2e-token"));
+    assert_eq!(header("content-type").as_deref(), Some("image/jpeg"));
+
+    let body = test::read_body(response).await;
+    assert_eq!(image::guess_format(&body).unwrap(), ImageFormat::Jpeg);
+    let watermarked = image::load_from_memory(&body).unwrap().into_rgb8();
+    let source = image::load_from_memory(&original).unwrap().into_rgb8();
+    assert_eq!(watermarked.dimensions(), source.dimensions());
+    assert_ne!(
+        watermarked, source,
+        "the image came back without a watermark"
+    );
+}
@@ -0,0 +1,62 @@
+use image::RgbaImage;
+use wasmtime::{Engine, Instance, Module, Store};
+
+/// A WASM post-processing plugin loaded from `PLUGIN_PATH`.
+///
+/// The module must not import anything and must export:
+/// - `memory`: its linear memory
+/// - `alloc(len: i32) -> i32`: returns a pointer to `len` writable bytes
+/// - `process(ptr: i32, width: i32, height: i32) -> i32`: edits the RGBA8 pixels at
+///   `ptr` in place and returns 0 on success
+pub struct Plugin {
+    engine: Engine,
+    module: Module,
+}
+
+impl Plugin {
+    /// Compiles the module once, so requests only pay for instantiation.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path)
+            .map_err(|e| format!("Failed to load plugin {}: {}", path, e))?;
+        Ok(Self { engine, module })
+    }
+
+    /// Runs the plugin on `image` in a fresh instance, so no state leaks between requests.
+    pub fn apply(&self, image: &mut RgbaImage) -> Result<(), String> {
+        let mut store = Store::new(&self.engine, ());
+        let instance = Instance::new(&mut store, &self.module, &[])
+            .map_err(|e| format!("Failed to instantiate plugin: {}", e))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| "Plugin does not export 'memory'".to_string())?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(|e| format!("Plugin 'alloc' export is invalid: {}", e))?;
+        let process = instance
+            .get_typed_func::<(i32, i32, i32), i32>(&mut store, "process")
+            .map_err(|e| format!("Plugin 'process' export is invalid: {}", e))?;
+
+        let (width, height) = image.dimensions();
+        let len = i32::try_from(image.len())
+            .map_err(|_| "Image is too large to pass to the plugin".to_string())?;
+        let ptr = alloc
+            .call(&mut store, len)
+            .map_err(|e| format!("Plugin 'alloc' failed: {}", e))?;
+        let offset = usize::try_from(ptr)
+            .map_err(|_| format!("Plugin 'alloc' returned an invalid pointer: {}", ptr))?;
+
+        memory
+            .write(&mut store, offset, image.as_raw())
+            .map_err(|e| format!("Failed to copy image into plugin memory: {}", e))?;
+        let status = process
+            .call(&mut store, (ptr, width as i32, height as i32))
+            .map_err(|e| format!("Plugin 'process' failed: {}", e))?;
+        if status != 0 {
+            return Err(format!("Plugin 'process' returned status {}", status));
+        }
+        memory
+            .read(&store, offset, image)
+            .map_err(|e| format!("Failed to copy image from plugin memory: {}", e))
+    }
+}
@@ -0,0 +1,108 @@
+use crate::config::{self, CONFIG};
+use dynamic_minio_watermark::WatermarkStyle;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// One bucket's entry in the `TENANT_CONFIG_PATH` file. Unset fields keep the global value.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantOverrides {
+    /// Hex color such as `#FF0000` or `#FF000040`
+    color: Option<String>,
+    /// Replaces the alpha channel of `color`, or of the global color
+    alpha: Option<u8>,
+    angle: Option<f32>,
+    font_height_ratio: Option<f32>,
+}
+
+impl TenantOverrides {
+    fn apply(&self, bucket: &str) -> Result<WatermarkStyle, String> {
+        let mut style = CONFIG.watermark_style();
+        if let Some(color) = &self.color {
+            style.color = config::parse_hex_color(color, style.color[3])
+                .ok_or_else(|| format!("Invalid color '{}' for bucket '{}'", color, bucket))?;
+        }
+        if let Some(alpha) = self.alpha {
+            style.color[3] = alpha;
+        }
+        if let Some(angle) = self.angle {
+            style.angle = angle % 360.0;
+        }
+        if let Some(ratio) = self.font_height_ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(format!(
+                    "Invalid font_height_ratio {} for bucket '{}', expected a value in (0, 1]",
+                    ratio, bucket
+                ));
+            }
+            style.font_height_ratio = ratio;
+        }
+        Ok(style)
+    }
+}
+
+/// Per-bucket watermark styles from the JSON file at `TENANT_CONFIG_PATH`, which maps
+/// bucket names to overrides of the global settings.
+pub struct TenantStyles {
+    path: String,
+    styles: RwLock<HashMap<String, WatermarkStyle>>,
+}
+
+impl TenantStyles {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let styles = read_styles(path)?;
+        info!(
+            "Loaded watermark overrides for {} bucket(s) from {}",
+            styles.len(),
+            path
+        );
+        Ok(Self {
+            path: path.to_string(),
+            styles: RwLock::new(styles),
+        })
+    }
+
+    /// Reads the file again, keeping the current styles if it is invalid.
+    pub fn reload(&self) -> Result<usize, String> {
+        let styles = read_styles(&self.path)?;
+        let count = styles.len();
+        *self.styles.write().unwrap_or_else(|e| e.into_inner()) = styles;
+        Ok(count)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The style for `bucket`, or the global one when the bucket is not listed.
+    pub fn style_for(&self, bucket: &str) -> WatermarkStyle {
+        self.styles
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(bucket)
+            .copied()
+            .unwrap_or_else(|| CONFIG.watermark_style())
+    }
+}
+
+fn read_styles(path: &str) -> Result<HashMap<String, WatermarkStyle>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read tenant config {}: {}", path, e))?;
+    let tenants: HashMap<String, TenantOverrides> = serde_json::from_str(&contents)
+        .map_err(|e| format!("Failed to parse tenant config {}: {}", path, e))?;
+
+    let mut styles = HashMap::with_capacity(tenants.len());
+    for (bucket, overrides) in tenants {
+        let style = overrides.apply(&bucket)?;
+        if style.color[3] == 255 {
+            warn!(
+                "Watermark for bucket '{}' is fully opaque; use a lower alpha for a subtle mark",
+                bucket
+            );
+        }
+        styles.insert(bucket, style);
+    }
+    Ok(styles)
+}
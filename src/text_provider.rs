@@ -0,0 +1,446 @@
+use crate::config::{TextSource, CONFIG};
+use crate::{forwarded_header, is_admin_request};
+use actix_web::HttpRequest;
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::{DecodePaddingMode, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Header carrying a watermark text signed with `WATERMARK_SIGNING_KEY`, which takes
+/// precedence over `WATERMARK_TEXT_SOURCES` when present.
+pub const WATERMARK_TOKEN_HEADER: &str = "X-Watermark-Token";
+
+/// URL-safe base64 used by watermark tokens, with or without padding.
+const TOKEN_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// What providers can read the watermark text from.
+pub struct TextRequest<'a> {
+    pub params: &'a HashMap<String, String>,
+    pub req: &'a HttpRequest,
+    /// End user's headers forwarded in an object-lambda payload
+    pub user_headers: Option<&'a HashMap<String, serde_json::Value>>,
+    pub bucket: &'a str,
+    pub object: &'a str,
+    /// Who the result is served to, for logging `{counter}` assignments
+    pub requester: &'a str,
+}
+
+/// A source of watermark text, tried in `WATERMARK_TEXT_SOURCES` order.
+pub trait WatermarkTextProvider: Send + Sync {
+    /// Name used in `WATERMARK_TEXT_SOURCES` and in logs.
+    fn name(&self) -> &'static str;
+
+    /// Returns the text for this request, or `None` to defer to the next provider.
+    /// An empty string is a valid answer and disables the watermark text.
+    fn provide(&self, request: &TextRequest<'_>) -> Option<String>;
+
+    /// Whether texts from this provider differ on every request, making the result
+    /// not worth caching.
+    fn per_request(&self) -> bool {
+        false
+    }
+}
+
+/// Watermark text picked for a request.
+pub struct ResolvedText {
+    pub text: String,
+    pub cacheable: bool,
+}
+
+/// Reads the `WATERMARK_PARAM` query parameter.
+pub struct ParamProvider {
+    /// Name of the parameter to read
+    pub param: String,
+}
+
+impl WatermarkTextProvider for ParamProvider {
+    fn name(&self) -> &'static str {
+        "param"
+    }
+
+    fn provide(&self, request: &TextRequest<'_>) -> Option<String> {
+        request.params.get(&self.param).cloned()
+    }
+}
+
+/// Reads the `WATERMARK_TEXT_HEADER` header, sent directly or forwarded by MinIO.
+pub struct HeaderProvider;
+
+impl WatermarkTextProvider for HeaderProvider {
+    fn name(&self) -> &'static str {
+        "header"
+    }
+
+    fn provide(&self, request: &TextRequest<'_>) -> Option<String> {
+        let header = CONFIG.watermark_text_header.as_str();
+        request
+            .req
+            .headers()
+            .get(header)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| forwarded_header(request.user_headers, header))
+            .map(str::to_string)
+    }
+}
+
+/// Expands `DEFAULT_WATERMARK_TEXT`. Each `{counter}` expansion takes the next
+/// sequence number and logs which requester it was served to.
+pub struct DefaultTemplateProvider {
+    /// Last value handed out for the `{counter}` token. Starts at 0 on every restart.
+    counter: AtomicU64,
+}
+
+impl WatermarkTextProvider for DefaultTemplateProvider {
+    fn name(&self) -> &'static str {
+        "default"
+    }
+
+    fn provide(&self, request: &TextRequest<'_>) -> Option<String> {
+        let template = CONFIG.default_watermark_text.as_deref()?;
+
+        let mut text = template
+            .replace("{date}", &Utc::now().format("%Y-%m-%d").to_string())
+            .replace("{bucket}", request.bucket)
+            .replace("{object}", request.object);
+        if text.contains("{counter}") {
+            let counter = self.counter.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(
+                "Watermark counter {} assigned to {}/{} for requester {}",
+                counter, request.bucket, request.object, request.requester
+            );
+            text = text.replace("{counter}", &counter.to_string());
+        }
+        Some(text).filter(|text| !text.trim().is_empty())
+    }
+
+    fn per_request(&self) -> bool {
+        CONFIG
+            .default_watermark_text
+            .as_deref()
+            .is_some_and(|template| template.contains("{counter}"))
+    }
+}
+
+/// Builds the providers for `WATERMARK_TEXT_SOURCES`, in order.
+pub fn configured_providers() -> Vec<Box<dyn WatermarkTextProvider>> {
+    CONFIG
+        .watermark_text_sources
+        .iter()
+        .map(|source| -> Box<dyn WatermarkTextProvider> {
+            match source {
+                TextSource::Param => Box::new(ParamProvider {
+                    param: CONFIG.watermark_param.clone(),
+                }),
+                TextSource::Header => Box::new(HeaderProvider),
+                TextSource::Default => Box::new(DefaultTemplateProvider {
+                    counter: AtomicU64::new(0),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Verifies the `WATERMARK_TOKEN_HEADER` token of the request, made of the base64 text
+/// and the base64 HMAC-SHA256 of the text keyed with `WATERMARK_SIGNING_KEY`,
+/// separated by a dot. Returns `None` without a token or without a signing key.
+fn signed_text(request: &TextRequest<'_>) -> Option<Result<String, String>> {
+    let key = CONFIG.watermark_signing_key.as_deref()?;
+    let token = request
+        .req
+        .headers()
+        .get(WATERMARK_TOKEN_HEADER)
+        .map(|value| value.to_str().unwrap_or_default())
+        .or_else(|| forwarded_header(request.user_headers, WATERMARK_TOKEN_HEADER))?;
+
+    Some(
+        verify_token(token, key.as_bytes())
+            .map_err(|reason| format!("Invalid {}: {}", WATERMARK_TOKEN_HEADER, reason)),
+    )
+}
+
+/// The text of a `<text>.<signature>` token whose signature verifies with `key`, or
+/// why it does not.
+fn verify_token(token: &str, key: &[u8]) -> Result<String, &'static str> {
+    let (text, signature) = token
+        .trim()
+        .split_once('.')
+        .ok_or("expected <text>.<signature>")?;
+    let text = TOKEN_BASE64
+        .decode(text)
+        .map_err(|_| "text is not valid base64")?;
+    let signature = TOKEN_BASE64
+        .decode(signature)
+        .map_err(|_| "signature is not valid base64")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&text);
+    mac.verify_slice(&signature)
+        .map_err(|_| "signature does not match")?;
+    String::from_utf8(text).map_err(|_| "text is not valid UTF-8")
+}
+
+/// Takes the text from a signed token when the request has one, and otherwise asks each
+/// provider in turn, falling back to "WATERMARK" when none yields text. A blank text,
+/// which returns the original image, is only honored for admin requests; others get the
+/// `DEFAULT_WATERMARK_TEXT` or "WATERMARK" instead. Texts are cut to
+/// `MAX_WATERMARK_TEXT_LENGTH` graphemes when it is set. Fails when the token does not verify.
+pub fn resolve(
+    providers: &[Box<dyn WatermarkTextProvider>],
+    request: &TextRequest<'_>,
+) -> Result<ResolvedText, String> {
+    let mut resolved = resolve_full_text(providers, request)?;
+    resolved.text = truncate_graphemes(resolved.text, CONFIG.max_watermark_text_length);
+    Ok(resolved)
+}
+
+/// Cuts `text` to its first `max` graphemes, or keeps it whole with a `max` of 0. Long
+/// texts would otherwise shrink the glyphs and multiply the tiles under
+/// `COVERAGE_GUARANTEE`.
+fn truncate_graphemes(text: String, max: usize) -> String {
+    match text.grapheme_indices(true).nth(max) {
+        Some((end, _)) if max > 0 => {
+            warn!(
+                "Watermark text is longer than {} graphemes, cutting it at MAX_WATERMARK_TEXT_LENGTH",
+                max
+            );
+            text[..end].to_string()
+        }
+        _ => text,
+    }
+}
+
+fn resolve_full_text(
+    providers: &[Box<dyn WatermarkTextProvider>],
+    request: &TextRequest<'_>,
+) -> Result<ResolvedText, String> {
+    if let Some(text) = signed_text(request) {
+        let text = text?;
+        debug!(
+            "Watermark text taken from a verified {}",
+            WATERMARK_TOKEN_HEADER
+        );
+        return Ok(ResolvedText {
+            text,
+            cacheable: true,
+        });
+    }
+
+    let resolved = providers.iter().find_map(|provider| {
+        let text = provider.provide(request)?;
+        debug!("Watermark text taken from the {} source", provider.name());
+        Some(ResolvedText {
+            text,
+            cacheable: !provider.per_request(),
+        })
+    });
+
+    match resolved {
+        Some(resolved) if resolved.text.trim().is_empty() => {
+            if is_admin_request(request.req, request.user_headers) {
+                warn!("Received admin request with empty watermark text parameter.");
+                return Ok(resolved);
+            }
+            warn!("Ignoring empty watermark text on request without a valid admin token");
+            Ok(default_text(providers, request))
+        }
+        Some(resolved) => Ok(resolved),
+        None => Ok(default_text(providers, request)),
+    }
+}
+
+/// The `default` provider's text when it is configured and yields one, or "WATERMARK".
+fn default_text(
+    providers: &[Box<dyn WatermarkTextProvider>],
+    request: &TextRequest<'_>,
+) -> ResolvedText {
+    providers
+        .iter()
+        .filter(|provider| provider.name() == "default")
+        .find_map(|provider| {
+            Some(ResolvedText {
+                text: provider.provide(request)?,
+                cacheable: !provider.per_request(),
+            })
+        })
+        .unwrap_or_else(|| ResolvedText {
+            text: "WATERMARK".to_string(),
+            cacheable: true,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Settings};
+    use actix_web::test::TestRequest;
+
+    fn resolve_params(params: &[(&str, &str)], req: &HttpRequest) -> String {
+        resolve_with(&configured_providers(), params, req)
+    }
+
+    fn resolve_with(
+        providers: &[Box<dyn WatermarkTextProvider>],
+        params: &[(&str, &str)],
+        req: &HttpRequest,
+    ) -> String {
+        let params = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let request = TextRequest {
+            params: &params,
+            req,
+            user_headers: None,
+            bucket: "bucket",
+            object: "key.jpg",
+            requester: "test",
+        };
+        resolve(providers, &request).unwrap().text
+    }
+
+    #[test]
+    fn param_text_is_used() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            resolve_params(&[("usercode", "ABCD1234")], &req),
+            "ABCD1234"
+        );
+    }
+
+    #[test]
+    fn custom_watermark_param_is_read() {
+        let config = Config::from_settings(&Settings::test().with("WATERMARK_PARAM", "email"));
+        let providers: Vec<Box<dyn WatermarkTextProvider>> = vec![Box::new(ParamProvider {
+            param: config.watermark_param,
+        })];
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            resolve_with(
+                &providers,
+                &[("email", "user@example.com"), ("usercode", "ABCD1234")],
+                &req
+            ),
+            "user@example.com"
+        );
+        assert_eq!(
+            resolve_with(&providers, &[("usercode", "ABCD1234")], &req),
+            "WATERMARK"
+        );
+    }
+
+    #[test]
+    fn empty_param_without_admin_token_is_still_watermarked() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(resolve_params(&[("usercode", "")], &req), "WATERMARK");
+        assert_eq!(resolve_params(&[("usercode", "  ")], &req), "WATERMARK");
+    }
+
+    #[test]
+    fn empty_param_with_admin_token_disables_the_watermark() {
+        let req = TestRequest::default()
+            .insert_header(("X-Admin-Token", crate::config::TEST_ADMIN_TOKEN))
+            .to_http_request();
+        assert_eq!(resolve_params(&[("usercode", "")], &req), "");
+    }
+
+    #[test]
+    fn long_texts_are_cut_to_whole_graphemes() {
+        assert_eq!(truncate_graphemes("ABCDEF".to_string(), 4), "ABCD");
+        assert_eq!(truncate_graphemes("ABC".to_string(), 4), "ABC");
+        assert_eq!(truncate_graphemes("ABCDEF".to_string(), 0), "ABCDEF");
+        assert_eq!(
+            truncate_graphemes("e\u{301}e\u{301}".to_string(), 1),
+            "e\u{301}"
+        );
+    }
+
+    const KEY: &[u8] = b"signing-key";
+
+    fn sign(text: &[u8], engine: &GeneralPurpose) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(KEY).unwrap();
+        mac.update(text);
+        let signature = mac.finalize().into_bytes();
+        format!("{}.{}", engine.encode(text), engine.encode(signature))
+    }
+
+    #[test]
+    fn signed_token_yields_its_text() {
+        let token = sign(
+            b"user@example.com",
+            &base64::engine::general_purpose::URL_SAFE,
+        );
+        assert_eq!(
+            verify_token(&token, KEY),
+            Ok("user@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn signed_token_is_accepted_with_and_without_padding() {
+        let padded = sign(
+            b"user@example.com",
+            &base64::engine::general_purpose::URL_SAFE,
+        );
+        let unpadded = sign(
+            b"user@example.com",
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+        );
+        assert!(padded.contains('='));
+        assert!(!unpadded.contains('='));
+        assert_eq!(verify_token(&padded, KEY), verify_token(&unpadded, KEY));
+        assert!(verify_token(&unpadded, KEY).is_ok());
+    }
+
+    #[test]
+    fn tampered_text_is_rejected() {
+        let token = sign(
+            b"user@example.com",
+            &base64::engine::general_purpose::URL_SAFE,
+        );
+        let (_, signature) = token.split_once('.').unwrap();
+        let tampered = format!(
+            "{}.{}",
+            base64::engine::general_purpose::URL_SAFE.encode("admin@example.com"),
+            signature
+        );
+        assert_eq!(
+            verify_token(&tampered, KEY),
+            Err("signature does not match")
+        );
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        let token = sign(
+            b"user@example.com",
+            &base64::engine::general_purpose::URL_SAFE,
+        );
+        assert_eq!(
+            verify_token(&token, b"other-key"),
+            Err("signature does not match")
+        );
+        let (text, _) = token.split_once('.').unwrap();
+        assert_eq!(
+            verify_token(&format!("{}.not*base64", text), KEY),
+            Err("signature is not valid base64")
+        );
+        assert_eq!(verify_token(text, KEY), Err("expected <text>.<signature>"));
+    }
+
+    #[test]
+    fn signed_text_that_is_not_utf8_is_rejected() {
+        let token = sign(
+            &[0xff, 0xfe, 0x41],
+            &base64::engine::general_purpose::URL_SAFE,
+        );
+        assert_eq!(verify_token(&token, KEY), Err("text is not valid UTF-8"));
+    }
+}
@@ -0,0 +1,177 @@
+//! Golden-image regression tests for the renderer. Each case watermarks a generated
+//! input with fixed options and compares the result with `tests/golden/<name>.png`.
+//!
+//! After an intended rendering change, regenerate the images with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and review them before committing. The
+//! `service_*` images are rendered through the service pipeline by the binary's tests.
+
+use dynamic_minio_watermark::{watermark, WatermarkOptions, WatermarkStyle};
+use image::{ImageFormat, ImageOutputFormat, Rgba, RgbaImage};
+use rusttype::Font;
+use std::io::Cursor;
+use std::path::PathBuf;
+
+/// Channel differences up to this many levels are ignored, absorbing rounding that
+/// may differ between platforms.
+const CHANNEL_TOLERANCE: u8 = 8;
+
+/// Largest fraction of channels allowed to differ by more than `CHANNEL_TOLERANCE`.
+const MAX_DIFFERING_FRACTION: f64 = 0.002;
+
+struct Case {
+    name: &'static str,
+    width: u32,
+    height: u32,
+    text: &'static str,
+    angle: f32,
+}
+
+const CASES: [Case; 4] = [
+    Case {
+        name: "landscape_ascii",
+        width: 320,
+        height: 240,
+        text: "ABCD1234",
+        angle: 0.0,
+    },
+    Case {
+        name: "wide_spaced",
+        width: 480,
+        height: 160,
+        text: "WATERMARK 42",
+        angle: 0.0,
+    },
+    Case {
+        name: "portrait_unicode",
+        width: 180,
+        height: 320,
+        text: "Ünïcødé ✓",
+        angle: 0.0,
+    },
+    Case {
+        name: "rotated",
+        width: 300,
+        height: 300,
+        text: "user@example.com",
+        angle: 30.0,
+    },
+];
+
+#[test]
+fn renders_match_golden_images() {
+    let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+    let mut failures = Vec::new();
+
+    for case in &CASES {
+        let mut options = WatermarkOptions::new(font.clone());
+        options.style = WatermarkStyle {
+            angle: case.angle,
+            ..WatermarkStyle::default()
+        };
+        options.format = Some(ImageFormat::Png);
+        let rendered = watermark(&input_image(case.width, case.height), case.text, &options)
+            .unwrap_or_else(|e| panic!("{}: {}", case.name, e));
+        let rendered = image::load_from_memory(&rendered).unwrap().into_rgba8();
+
+        let path = golden_path(case.name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            rendered.save(&path).unwrap();
+            continue;
+        }
+        let golden = image::open(&path)
+            .unwrap_or_else(|e| panic!("{}: cannot read {}: {}", case.name, path.display(), e))
+            .into_rgba8();
+        if let Err(reason) = compare(&rendered, &golden) {
+            failures.push(format!("{}: {}", case.name, reason));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "Rendering differs from the golden images:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn safe_area_margin_is_left_untouched() {
+    let font = Font::try_from_vec(std::fs::read("assets/DejaVuSans.ttf").unwrap()).unwrap();
+    let (width, height) = (320, 240);
+    let input = input_image(width, height);
+    let original = image::load_from_memory(&input).unwrap().into_rgba8();
+
+    for angle in [0.0, 30.0] {
+        let mut options = WatermarkOptions::new(font.clone());
+        options.style = WatermarkStyle {
+            angle,
+            ..WatermarkStyle::default()
+        };
+        options.layout.safe_area_margin = 0.1;
+        options.format = Some(ImageFormat::Png);
+        let rendered = watermark(&input, "ABCD1234", &options).unwrap();
+        let rendered = image::load_from_memory(&rendered).unwrap().into_rgba8();
+
+        let (left, top, right, bottom) = options.layout.safe_area(width, height);
+        let mut inside_changed = false;
+        for (x, y, pixel) in rendered.enumerate_pixels() {
+            let inside = (left..right).contains(&x) && (top..bottom).contains(&y);
+            if inside {
+                inside_changed |= pixel != original.get_pixel(x, y);
+            } else {
+                assert_eq!(
+                    pixel,
+                    original.get_pixel(x, y),
+                    "angle {}: margin pixel ({}, {}) changed",
+                    angle,
+                    x,
+                    y
+                );
+            }
+        }
+        assert!(inside_changed, "angle {}: nothing was drawn", angle);
+    }
+}
+
+/// A diagonal gradient with a darker band, so both light and dark areas are covered.
+fn input_image(width: u32, height: u32) -> Vec<u8> {
+    let image = RgbaImage::from_fn(width, height, |x, y| {
+        let r = (x * 255 / width) as u8;
+        let g = (y * 255 / height) as u8;
+        let b = if (x + y) % 64 < 32 { 60 } else { 180 };
+        Rgba([r, g, b, 255])
+    });
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    png.into_inner()
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.png", name))
+}
+
+fn compare(rendered: &RgbaImage, golden: &RgbaImage) -> Result<(), String> {
+    if rendered.dimensions() != golden.dimensions() {
+        return Err(format!(
+            "size {:?} instead of {:?}",
+            rendered.dimensions(),
+            golden.dimensions()
+        ));
+    }
+    let differing = rendered
+        .iter()
+        .zip(golden.iter())
+        .filter(|(a, b)| a.abs_diff(**b) > CHANNEL_TOLERANCE)
+        .count();
+    let fraction = differing as f64 / rendered.len() as f64;
+    if fraction > MAX_DIFFERING_FRACTION {
+        return Err(format!(
+            "{} channels ({:.3}%) differ by more than {} levels",
+            differing,
+            fraction * 100.0,
+            CHANNEL_TOLERANCE
+        ));
+    }
+    Ok(())
+}